@@ -0,0 +1,123 @@
+/// Hands out chunk pool slots, and the pages of the voxel pool. Slot 0 is
+/// never allocated, the directory uses it to mark empty chunks.
+pub struct ChunkAllocator {
+    free: Vec<u32>,
+    allocated: Vec<bool>,
+}
+
+impl ChunkAllocator {
+    pub fn new(capacity: usize) -> Self {
+        let mut allocated = vec![false; capacity];
+        if let Some(reserved) = allocated.first_mut() {
+            *reserved = true;
+        }
+        Self {
+            // Reversed so the lowest slots are handed out first
+            free: (1..capacity as u32).rev().collect(),
+            allocated,
+        }
+    }
+
+    pub fn allocate(&mut self) -> Option<u32> {
+        let slot = self.free.pop()?;
+        self.allocated[slot as usize] = true;
+        Some(slot)
+    }
+
+    /// Returns a slot to the free list. Freeing slot 0 or a slot that is not
+    /// allocated does nothing.
+    pub fn free(&mut self, slot: u32) {
+        if slot == 0 || !self.is_allocated(slot) {
+            return;
+        }
+        self.allocated[slot as usize] = false;
+        self.free.push(slot);
+    }
+
+    pub fn is_allocated(&self, slot: u32) -> bool {
+        slot != 0 && self.allocated.get(slot as usize).copied().unwrap_or(false)
+    }
+
+    /// Usable slots, excluding the reserved slot 0.
+    pub fn capacity(&self) -> usize {
+        self.allocated.len().saturating_sub(1)
+    }
+
+    pub fn used(&self) -> usize {
+        self.capacity() - self.free.len()
+    }
+
+    pub fn available(&self) -> usize {
+        self.free.len()
+    }
+
+    /// Fraction of usable slots in use, 0.0 to 1.0.
+    pub fn occupancy(&self) -> f32 {
+        if self.capacity() == 0 {
+            return 0.0;
+        }
+        self.used() as f32 / self.capacity() as f32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn slot_zero_is_reserved() {
+        let mut allocator = ChunkAllocator::new(4);
+        assert!(!allocator.is_allocated(0));
+        assert_eq!(allocator.capacity(), 3);
+        assert_eq!(allocator.allocate(), Some(1));
+        allocator.free(0);
+        assert_eq!(allocator.available(), 2);
+        assert!((1..4).all(|_| allocator.allocate() != Some(0)));
+    }
+
+    #[test]
+    fn freed_slots_are_reused() {
+        let mut allocator = ChunkAllocator::new(8);
+        let slots: Vec<u32> = (0..3).filter_map(|_| allocator.allocate()).collect();
+        assert_eq!(slots, [1, 2, 3]);
+        allocator.free(2);
+        assert!(!allocator.is_allocated(2));
+        assert_eq!(allocator.allocate(), Some(2));
+        assert!(allocator.is_allocated(2));
+    }
+
+    #[test]
+    fn double_free_is_ignored() {
+        let mut allocator = ChunkAllocator::new(8);
+        let slot = allocator.allocate().unwrap();
+        allocator.free(slot);
+        allocator.free(slot);
+        allocator.free(7);
+        assert_eq!(allocator.used(), 0);
+        assert_eq!(allocator.available(), 7);
+    }
+
+    #[test]
+    fn exhausts_then_recovers() {
+        let mut allocator = ChunkAllocator::new(3);
+        assert_eq!(allocator.allocate(), Some(1));
+        assert_eq!(allocator.allocate(), Some(2));
+        assert_eq!(allocator.allocate(), None);
+        allocator.free(1);
+        assert_eq!(allocator.allocate(), Some(1));
+        assert_eq!(ChunkAllocator::new(0).allocate(), None);
+        assert_eq!(ChunkAllocator::new(1).allocate(), None);
+    }
+
+    #[test]
+    fn counts_occupancy() {
+        let mut allocator = ChunkAllocator::new(5);
+        assert_eq!(allocator.occupancy(), 0.0);
+        allocator.allocate();
+        assert_eq!((allocator.used(), allocator.available()), (1, 3));
+        assert_eq!(allocator.occupancy(), 0.25);
+        while allocator.allocate().is_some() {}
+        assert_eq!(allocator.occupancy(), 1.0);
+        assert_eq!(ChunkAllocator::new(0).occupancy(), 0.0);
+    }
+}
@@ -0,0 +1,238 @@
+use nalgebra::{Vector2, Vector3};
+
+use crate::core::{
+    blocks,
+    generator::GeneratorSettings,
+    terrain::{lerp, noise},
+};
+
+pub const BIOME_COUNT: usize = 5;
+/// Deepest fill layer a biome can ask for. generate.comp looks this many
+/// voxels above a chunk to find the surface of its top layer.
+pub const MAX_FILL_DEPTH: u32 = 8;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Biome {
+    Plains,
+    Desert,
+    Mountains,
+    Snow,
+    Ocean,
+}
+
+impl Biome {
+    pub const ALL: [Biome; BIOME_COUNT] = [
+        Biome::Plains,
+        Biome::Desert,
+        Biome::Mountains,
+        Biome::Snow,
+        Biome::Ocean,
+    ];
+}
+
+/// Per biome configuration. Each biome sits at a point of the
+/// temperature/humidity plane and columns take the biome nearest to their
+/// climate, blended with the second nearest near borders.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BiomeSettings {
+    pub temperature: f32,
+    pub humidity: f32,
+    /// Topmost solid voxel of a column
+    pub surface_block: u32,
+    /// Voxels under the surface, `fill_depth` deep (at most MAX_FILL_DEPTH - 1)
+    pub fill_block: u32,
+    pub fill_depth: u32,
+    /// Added to `GeneratorSettings::base_height`
+    pub height_offset: f32,
+    /// Multiplies `GeneratorSettings::height_falloff`, larger gives taller relief
+    pub height_scale: f32,
+    /// Tree and boulder placement attempts per chunk column
+    pub tree_attempts: u32,
+    pub boulder_attempts: u32,
+}
+
+impl BiomeSettings {
+    /// Base height and reciprocal height falloff of this biome's terrain.
+    pub fn profile(&self, settings: &GeneratorSettings) -> (f32, f32) {
+        (
+            settings.base_height + self.height_offset,
+            1.0 / (settings.height_falloff * self.height_scale),
+        )
+    }
+}
+
+pub fn default_biomes() -> [BiomeSettings; BIOME_COUNT] {
+    Biome::ALL.map(|biome| match biome {
+        Biome::Plains => BiomeSettings {
+            temperature: 0.55,
+            humidity: 0.5,
+            surface_block: blocks::GRASS,
+            fill_block: blocks::DIRT,
+            fill_depth: 3,
+            height_offset: -6.0,
+            height_scale: 0.6,
+            tree_attempts: 3,
+            boulder_attempts: 1,
+        },
+        Biome::Desert => BiomeSettings {
+            temperature: 0.7,
+            humidity: 0.3,
+            surface_block: blocks::SAND,
+            fill_block: blocks::SAND,
+            fill_depth: 4,
+            height_offset: -4.0,
+            height_scale: 0.5,
+            tree_attempts: 0,
+            boulder_attempts: 0,
+        },
+        Biome::Mountains => BiomeSettings {
+            temperature: 0.35,
+            humidity: 0.35,
+            surface_block: blocks::STONE,
+            fill_block: blocks::STONE,
+            fill_depth: 0,
+            height_offset: 20.0,
+            height_scale: 2.0,
+            tree_attempts: 0,
+            boulder_attempts: 3,
+        },
+        Biome::Snow => BiomeSettings {
+            temperature: 0.25,
+            humidity: 0.6,
+            surface_block: blocks::SNOW,
+            fill_block: blocks::DIRT,
+            fill_depth: 2,
+            height_offset: 8.0,
+            height_scale: 1.2,
+            tree_attempts: 2,
+            boulder_attempts: 1,
+        },
+        Biome::Ocean => BiomeSettings {
+            temperature: 0.55,
+            humidity: 0.75,
+            surface_block: blocks::SAND,
+            fill_block: blocks::SAND,
+            fill_depth: 3,
+            height_offset: -40.0,
+            height_scale: 0.5,
+            tree_attempts: 0,
+            boulder_attempts: 0,
+        },
+    })
+}
+
+/// Biome data of one world column, as generate.comp sees it.
+#[derive(Clone, Copy, Debug)]
+pub struct ColumnBiome {
+    /// Nearest biome, it picks the surface and fill blocks
+    pub biome: Biome,
+    /// Second nearest biome and the primary's blend weight (0.5 to 1.0)
+    pub secondary: Biome,
+    pub weight: f32,
+    pub temperature: f32,
+    pub humidity: f32,
+    /// Blended height profile
+    pub base_height: f32,
+    pub inv_height_falloff: f32,
+}
+
+fn climate_noise(pos: Vector2<f32>, seed: u32) -> f32 {
+    let q = Vector3::new(pos.x, 0.0, pos.y);
+    noise(q, seed) * 0.6667 + noise(q * 2.0 + Vector3::repeat(100.0), seed) * 0.3333
+}
+
+/// Biome of the column at voxel `(x, z)`. Mirrors columnBiome in generate.comp.
+pub fn biome_at(settings: &GeneratorSettings, x: i32, z: i32) -> ColumnBiome {
+    let climate_pos = Vector2::new(x as f32, z as f32) * settings.climate_scale;
+    let temperature = climate_noise(climate_pos, settings.seed.wrapping_add(1));
+    let humidity = climate_noise(climate_pos, settings.seed.wrapping_add(2));
+
+    let (mut first, mut second) = (0, 0);
+    let (mut d1, mut d2) = (1.0e30_f32, 1.0e30_f32);
+    for (i, biome) in settings.biomes.iter().enumerate() {
+        let dt = temperature - biome.temperature;
+        let dh = humidity - biome.humidity;
+        let d = dt * dt + dh * dh;
+        if d < d1 {
+            d2 = d1;
+            second = first;
+            d1 = d;
+            first = i;
+        } else if d < d2 {
+            d2 = d;
+            second = i;
+        }
+    }
+
+    let t = ((d2 - d1) * settings.blend_sharpness).clamp(0.0, 1.0);
+    let weight = 0.5 + 0.5 * (t * t * (3.0 - 2.0 * t));
+    let (base1, inv1) = settings.biomes[first].profile(settings);
+    let (base2, inv2) = settings.biomes[second].profile(settings);
+
+    ColumnBiome {
+        biome: Biome::ALL[first],
+        secondary: Biome::ALL[second],
+        weight,
+        temperature,
+        humidity,
+        base_height: lerp(base2, base1, weight),
+        inv_height_falloff: lerp(inv2, inv1, weight),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::terrain::density;
+
+    fn surface(settings: &GeneratorSettings, column: &ColumnBiome, x: i32) -> i32 {
+        (0..300)
+            .rev()
+            .find(|&y| density(settings, column, Vector3::new(x, y, 0)) > 0.0)
+            .unwrap_or(0)
+    }
+
+    #[test]
+    fn query_is_deterministic() {
+        let settings = GeneratorSettings::default();
+        let reseeded = GeneratorSettings {
+            seed: 7,
+            ..GeneratorSettings::default()
+        };
+        let columns = (0..64).map(|i| (i * 997 - 30000, i * 613 - 20000));
+        let mut differs = false;
+        for (x, z) in columns {
+            let a = biome_at(&settings, x, z);
+            let b = biome_at(&settings, x, z);
+            assert_eq!(a.biome, b.biome);
+            assert_eq!(a.secondary, b.secondary);
+            assert_eq!(a.weight, b.weight);
+            assert_eq!(a.base_height, b.base_height);
+            assert_eq!(a.inv_height_falloff, b.inv_height_falloff);
+            differs |= biome_at(&reseeded, x, z).temperature != a.temperature;
+        }
+        assert!(differs);
+    }
+
+    #[test]
+    fn borders_blend_smoothly() {
+        let settings = GeneratorSettings::default();
+        let mut borders = 0;
+        let mut previous = biome_at(&settings, 0, 0);
+        for x in 1..20000 {
+            let column = biome_at(&settings, x, 0);
+            if column.biome != previous.biome {
+                borders += 1;
+                // Both sides sit halfway between the two biomes
+                assert!((column.weight - 0.5).abs() < 0.01);
+                assert!((previous.weight - 0.5).abs() < 0.01);
+                assert!((column.base_height - previous.base_height).abs() < 0.5);
+                assert!((column.inv_height_falloff - previous.inv_height_falloff).abs() < 1e-3);
+                let step = surface(&settings, &column, x) - surface(&settings, &previous, x - 1);
+                assert!(step.abs() <= 3, "surface jumps {step} voxels at x = {x}");
+            }
+            previous = column;
+        }
+        assert!(borders >= 8, "only {borders} borders crossed");
+    }
+}
@@ -0,0 +1,17 @@
+//! Block ids stored in the chunk pool. 0 is always air.
+
+pub const AIR: u32 = 0;
+pub const STONE: u32 = 1;
+pub const GRASS: u32 = 2;
+pub const BEDROCK: u32 = 3;
+pub const DIRT: u32 = 4;
+pub const SAND: u32 = 5;
+pub const SNOW: u32 = 6;
+pub const WATER: u32 = 7;
+pub const WOOD: u32 = 8;
+pub const LEAVES: u32 = 9;
+pub const COAL_ORE: u32 = 10;
+pub const IRON_ORE: u32 = 11;
+pub const TORCH: u32 = 12;
+pub const LAVA: u32 = 13;
+pub const GLASS: u32 = 14;
@@ -0,0 +1,68 @@
+use std::{fs::File, io::BufWriter, io::Write, path::Path};
+
+use ash::vk;
+
+use crate::core::error::VoxelError;
+
+/// Converts pixels read back from an image of `format` into RGBA8.
+pub fn to_rgba(format: vk::Format, mut pixels: Vec<u8>) -> Result<Vec<u8>, VoxelError> {
+    match format {
+        vk::Format::R8G8B8A8_UNORM | vk::Format::R8G8B8A8_SRGB => Ok(pixels),
+        vk::Format::B8G8R8A8_UNORM | vk::Format::B8G8R8A8_SRGB => {
+            for pixel in pixels.chunks_exact_mut(4) {
+                pixel.swap(0, 2);
+            }
+            Ok(pixels)
+        }
+        other => Err(VoxelError::Format(format!(
+            "Unsupported capture format {:?}",
+            other
+        ))),
+    }
+}
+
+/// Writes RGBA8 pixels to `path`. The encoding is picked from the extension:
+/// `.ppm` writes a binary PPM (alpha dropped), anything else a PNG.
+pub fn save_rgba(path: &Path, width: u32, height: u32, rgba: &[u8]) -> Result<(), VoxelError> {
+    let writer = BufWriter::new(File::create(path)?);
+    let is_ppm = path
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("ppm"));
+
+    if is_ppm {
+        write_ppm(writer, width, height, rgba)
+    } else {
+        write_png(writer, width, height, rgba)
+    }
+}
+
+fn write_ppm<W: Write>(
+    mut writer: W,
+    width: u32,
+    height: u32,
+    rgba: &[u8],
+) -> Result<(), VoxelError> {
+    write!(writer, "P6\n{} {}\n255\n", width, height)?;
+    let rgb: Vec<u8> = rgba
+        .chunks_exact(4)
+        .flat_map(|pixel| [pixel[0], pixel[1], pixel[2]])
+        .collect();
+    writer.write_all(&rgb)?;
+    writer.flush()?;
+    Ok(())
+}
+
+fn write_png<W: Write>(writer: W, width: u32, height: u32, rgba: &[u8]) -> Result<(), VoxelError> {
+    let mut encoder = png::Encoder::new(writer, width, height);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut png_writer = encoder
+        .write_header()
+        .map_err(|e| VoxelError::Format(e.to_string()))?;
+    png_writer
+        .write_image_data(rgba)
+        .map_err(|e| VoxelError::Format(e.to_string()))?;
+    png_writer
+        .finish()
+        .map_err(|e| VoxelError::Format(e.to_string()))
+}
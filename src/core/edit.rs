@@ -0,0 +1,591 @@
+use ash::vk;
+use log::*;
+use nalgebra::Vector3;
+
+use crate::{
+    core::{
+        palette::{DIRECT_BITS, MAX_BLOCK_ID, PALETTE_CAPACITY, Palette, index_bits},
+        world::{CHUNK_SIZE, CHUNK_VOLUME, ChunkedWorld, entry_block, entry_slot, local_index},
+    },
+    vulkan::context::VulkanContext,
+};
+
+/// Part of an edit region that falls inside one chunk.
+struct ChunkSpan {
+    dir_index: usize,
+    origin: Vector3<i32>,
+    min: Vector3<usize>,
+    max: Vector3<usize>,
+}
+
+impl ChunkSpan {
+    fn rows(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+        (self.min.z..=self.max.z).flat_map(move |z| (self.min.y..=self.max.y).map(move |y| (y, z)))
+    }
+
+    fn covers_chunk(&self) -> bool {
+        self.min == Vector3::zeros() && self.max == Vector3::repeat(CHUNK_SIZE - 1)
+    }
+
+    /// Packed words `first..end` holding a row at `bits` per voxel. Rows
+    /// start on a multiple of `bits` words, so they never cross a page.
+    fn row_words(&self, y: usize, z: usize, bits: u32) -> (usize, usize) {
+        let bits = bits as usize;
+        let first = local_index(self.min.x, y, z) * bits / 32;
+        let end = ((local_index(self.max.x, y, z) + 1) * bits).div_ceil(32);
+        (first, end)
+    }
+}
+
+/// Voxels a brush changes in a chunk, as `(local index, block)` in ascending
+/// order.
+type Changes = Vec<(usize, u32)>;
+
+/// Rows of a span edited in place: the palette with the blocks the edit
+/// added, and the changed rows as their first packed word and contents.
+struct PackedEdit {
+    palette: Palette,
+    rows: Vec<(usize, Vec<u32>)>,
+}
+
+impl ChunkedWorld {
+    pub fn get_voxel(&self, context: &VulkanContext, pos: Vector3<i32>) -> Result<u32, vk::Result> {
+        let Some((dir_index, local)) = self.locate(pos) else {
+            return Ok(0);
+        };
+        let entry = self.directory[dir_index];
+        if let Some(block) = entry_block(entry) {
+            return Ok(block);
+        }
+        let Some(slot) = entry_slot(entry) else {
+            return Ok(0);
+        };
+        let header = self.headers[slot as usize];
+        if header.bits == 0 {
+            return Ok(0);
+        }
+        let (offset, shift) = header.locate(local);
+        let word = self.read_pool(context, &[(offset, 1)])?[0];
+        Ok(self.palettes[slot as usize].block((word >> shift) & ((1 << header.bits) - 1)))
+    }
+
+    pub fn set_voxel(
+        &mut self,
+        context: &VulkanContext,
+        pos: Vector3<i32>,
+        block: u32,
+    ) -> Result<(), vk::Result> {
+        self.fill_box(context, pos, pos, block)
+    }
+
+    /// Sets every voxel in the inclusive box `min..=max` to `block`.
+    pub fn fill_box(
+        &mut self,
+        context: &VulkanContext,
+        min: Vector3<i32>,
+        max: Vector3<i32>,
+        block: u32,
+    ) -> Result<(), vk::Result> {
+        self.edit_region(context, min, max, |_, _| Some(block))
+    }
+
+    /// Sets every voxel whose centre lies within `radius` of `center`.
+    pub fn fill_sphere(
+        &mut self,
+        context: &VulkanContext,
+        center: Vector3<f32>,
+        radius: f32,
+        block: u32,
+    ) -> Result<(), vk::Result> {
+        let min = center.map(|c| (c - radius).floor() as i32);
+        let max = center.map(|c| (c + radius).ceil() as i32);
+        let radius_sq = radius * radius;
+        self.edit_region(context, min, max, |pos, _| {
+            let offset = pos.cast::<f32>().add_scalar(0.5) - center;
+            (offset.norm_squared() <= radius_sq).then_some(block)
+        })
+    }
+
+    /// Replaces `from` with `to` inside the inclusive box `min..=max`.
+    pub fn replace_box(
+        &mut self,
+        context: &VulkanContext,
+        min: Vector3<i32>,
+        max: Vector3<i32>,
+        from: u32,
+        to: u32,
+    ) -> Result<(), vk::Result> {
+        self.edit_region(context, min, max, |_, old| (old == from).then_some(to))
+    }
+
+    /// Runs `brush` over every voxel of the inclusive box `min..=max` (clipped
+    /// to the resident window) with its current block, and sets the voxels it
+    /// returns `Some` for.
+    ///
+    /// Packed chunks are edited in place: only the rows the box covers are
+    /// read back, and only the changed ones uploaded, while the new blocks
+    /// fit the palette's index width. Chunks that outgrow it, air and
+    /// uniform chunks, and chunks the box covers whole are read back and
+    /// repacked; these get a pool slot on their first differing voxel.
+    /// Chunks left all air or all one block give theirs back either way, and
+    /// chunks edited in place are repacked once their blocks fit a narrower
+    /// width. Everything is uploaded in a single submit per path.
+    pub fn edit_region<F>(
+        &mut self,
+        context: &VulkanContext,
+        min: Vector3<i32>,
+        max: Vector3<i32>,
+        mut brush: F,
+    ) -> Result<(), vk::Result>
+    where
+        F: FnMut(Vector3<i32>, u32) -> Option<u32>,
+    {
+        let (window_min, window_max) = self.window_voxel_bounds();
+        let min = min.sup(&window_min);
+        let max = max.inf(&window_max.add_scalar(-1));
+        if min.iter().zip(max.iter()).any(|(lo, hi)| lo > hi) {
+            return Ok(());
+        }
+
+        let spans = self.chunk_spans(min, max);
+        let (in_place, repack): (Vec<&ChunkSpan>, Vec<&ChunkSpan>) = spans
+            .iter()
+            .partition(|span| self.packed_slot(span).is_some() && !span.covers_chunk());
+
+        let mut read_ranges = Vec::new();
+        for span in &in_place {
+            let header = self.headers[self.packed_slot(span).unwrap_or(0) as usize];
+            for (y, z) in span.rows() {
+                let (first, end) = span.row_words(y, z, header.bits);
+                read_ranges.push((header.word_offset(first), (end - first) as u64));
+            }
+        }
+        let existing = self.read_pool(context, &read_ranges)?;
+        let mut existing = existing.as_slice();
+
+        // The brush runs once per voxel: spans that turn out to need a
+        // repack carry the blocks it already set
+        let mut rejected = None;
+        let mut repack: Vec<(&ChunkSpan, Option<Changes>)> =
+            repack.into_iter().map(|span| (span, None)).collect();
+        let mut edited = Vec::new();
+        let mut write_ranges = Vec::new();
+        let mut write_data = Vec::new();
+        for span in in_place {
+            let slot = self.packed_slot(span).unwrap_or(0);
+            let header = self.headers[slot as usize];
+            let len: usize = span
+                .rows()
+                .map(|(y, z)| {
+                    let (first, end) = span.row_words(y, z, header.bits);
+                    end - first
+                })
+                .sum();
+            let (words, rest) = existing.split_at(len);
+            existing = rest;
+
+            let palette = &self.palettes[slot as usize];
+            let old = unpack_span(span, palette, words);
+            let changes = brush_changes(span, &old, &mut brush, &mut rejected);
+            if changes.is_empty() {
+                continue;
+            }
+            let Some(edit) = pack_changes(span, palette, words, &changes) else {
+                repack.push((span, Some(changes)));
+                continue;
+            };
+            if edit.palette != self.palettes[slot as usize] {
+                // Same width, the indices already packed stay valid
+                self.set_chunk_format(slot, edit.palette)?;
+            }
+            for (first, row) in edit.rows {
+                write_ranges.push((header.word_offset(first), row.len() as u64));
+                write_data.extend(row);
+            }
+            edited.push((span.dir_index, slot));
+            self.mark_changed(span.dir_index);
+            self.modified
+                .insert(span.origin.map(|c| c.div_euclid(CHUNK_SIZE as i32)));
+        }
+        let slots: Vec<u32> = edited.iter().map(|&(_, slot)| slot).collect();
+        self.write_pool(context, &slots, &write_ranges, &write_data)?;
+
+        // Overwritten voxels may have been the last of their block. Chunks
+        // left with a single block, or with few enough to pack narrower, are
+        // repacked below, which also drops the dead palette entries
+        let mut dir_indices = Vec::new();
+        let mut voxels = Vec::new();
+        let current = self.read_chunks(context, &slots)?;
+        for (&(dir_index, slot), chunk) in edited.iter().zip(current.chunks_exact(CHUNK_VOLUME)) {
+            if needs_repack(self.headers[slot as usize].bits, chunk) {
+                dir_indices.push(dir_index);
+                voxels.extend_from_slice(chunk);
+            }
+        }
+
+        let entries: Vec<u32> = repack
+            .iter()
+            .map(|(span, _)| self.directory[span.dir_index])
+            .collect();
+        let existing = self.read_chunks(context, &entries)?;
+        for ((span, changes), existing) in
+            repack.into_iter().zip(existing.chunks_exact(CHUNK_VOLUME))
+        {
+            let changes =
+                changes.unwrap_or_else(|| brush_changes(span, existing, &mut brush, &mut rejected));
+            if changes.is_empty() {
+                continue;
+            }
+            let mut chunk = existing.to_vec();
+            for (local, block) in changes {
+                chunk[local] = block;
+            }
+            self.modified
+                .insert(span.origin.map(|c| c.div_euclid(CHUNK_SIZE as i32)));
+            dir_indices.push(span.dir_index);
+            voxels.extend(chunk);
+        }
+
+        if let Some(block) = rejected {
+            warn!(
+                "Block id {} is over {}, left out of the edit",
+                block, MAX_BLOCK_ID
+            );
+        }
+        self.write_chunks(context, &dir_indices, &voxels)
+    }
+
+    /// Pool slot of a span's chunk when it has packed voxels to edit in place.
+    fn packed_slot(&self, span: &ChunkSpan) -> Option<u32> {
+        entry_slot(self.directory[span.dir_index])
+            .filter(|&slot| self.headers[slot as usize].bits != 0)
+    }
+
+    fn chunk_spans(&self, min: Vector3<i32>, max: Vector3<i32>) -> Vec<ChunkSpan> {
+        let size = CHUNK_SIZE as i32;
+        let chunk_min = min.map(|c| c.div_euclid(size));
+        let chunk_max = max.map(|c| c.div_euclid(size));
+        let mut spans = Vec::new();
+        for cz in chunk_min.z..=chunk_max.z {
+            for cy in chunk_min.y..=chunk_max.y {
+                for cx in chunk_min.x..=chunk_max.x {
+                    let chunk = Vector3::new(cx, cy, cz);
+                    let Some(dir_index) = self.chunk_dir_index(chunk) else {
+                        continue;
+                    };
+                    let origin = chunk * size;
+                    let local_min = (min - origin).map(|c| c.clamp(0, size - 1) as usize);
+                    let local_max = (max - origin).map(|c| c.clamp(0, size - 1) as usize);
+                    spans.push(ChunkSpan {
+                        dir_index,
+                        origin,
+                        min: local_min,
+                        max: local_max,
+                    });
+                }
+            }
+        }
+        spans
+    }
+}
+
+/// Blocks of a chunk's voxels inside `span`, unpacked from its rows at
+/// `palette`'s width as read back by `ChunkSpan::row_words`. Voxels outside
+/// the span read as air.
+fn unpack_span(span: &ChunkSpan, palette: &Palette, mut words: &[u32]) -> Vec<u32> {
+    let bits = palette.bits;
+    let mask = (1u32 << bits) - 1;
+    let mut blocks = vec![0; CHUNK_VOLUME];
+    for (y, z) in span.rows() {
+        let (first, end) = span.row_words(y, z, bits);
+        let (row, rest) = words.split_at(end - first);
+        words = rest;
+        for x in span.min.x..=span.max.x {
+            let local = local_index(x, y, z);
+            let bit = local * bits as usize - first * 32;
+            blocks[local] = palette.block((row[bit / 32] >> (bit % 32)) & mask);
+        }
+    }
+    blocks
+}
+
+/// Runs `brush` over a span's voxels, `old` holding their blocks by local
+/// index, and returns the ones it changes. Ids over MAX_BLOCK_ID are left
+/// out and reported through `rejected`.
+fn brush_changes<F>(
+    span: &ChunkSpan,
+    old: &[u32],
+    brush: &mut F,
+    rejected: &mut Option<u32>,
+) -> Changes
+where
+    F: FnMut(Vector3<i32>, u32) -> Option<u32>,
+{
+    let mut changes = Vec::new();
+    for (y, z) in span.rows() {
+        for x in span.min.x..=span.max.x {
+            let local = local_index(x, y, z);
+            let pos = span.origin + Vector3::new(x, y, z).cast::<i32>();
+            let Some(block) = brush(pos, old[local]).filter(|&block| block != old[local]) else {
+                continue;
+            };
+            if block > MAX_BLOCK_ID {
+                *rejected = Some(block);
+                continue;
+            }
+            changes.push((local, block));
+        }
+    }
+    changes
+}
+
+/// Writes `changes` into a span's packed rows, `words` as read back by
+/// `ChunkSpan::row_words`. New blocks are appended to a copy of `palette`;
+/// None when they don't fit its index width.
+fn pack_changes(
+    span: &ChunkSpan,
+    palette: &Palette,
+    mut words: &[u32],
+    changes: &[(usize, u32)],
+) -> Option<PackedEdit> {
+    let bits = palette.bits;
+    let mask = (1u32 << bits) - 1;
+    let capacity = (1 << bits).min(PALETTE_CAPACITY);
+    let mut palette = palette.clone();
+    let mut changes = changes.iter().peekable();
+
+    let mut rows = Vec::new();
+    for (y, z) in span.rows() {
+        let (first, end) = span.row_words(y, z, bits);
+        let (row, rest) = words.split_at(end - first);
+        words = rest;
+        let last = local_index(span.max.x, y, z);
+        let mut row = row.to_vec();
+
+        let mut changed = false;
+        while let Some(&(local, block)) = changes.next_if(|&&(local, _)| local <= last) {
+            let bit = local * bits as usize - first * 32;
+            let (word, shift) = (bit / 32, bit % 32);
+            let index = if bits == DIRECT_BITS {
+                block
+            } else if let Some(index) = palette.blocks.iter().position(|&b| b == block) {
+                index as u32
+            } else if palette.blocks.len() < capacity {
+                palette.blocks.push(block);
+                palette.blocks.len() as u32 - 1
+            } else {
+                return None;
+            };
+            row[word] = (row[word] & !(mask << shift)) | (index << shift);
+            changed = true;
+        }
+        if changed {
+            rows.push((first, row));
+        }
+    }
+    Some(PackedEdit { palette, rows })
+}
+
+/// Whether a chunk edited in place should be repacked from its `voxels`:
+/// when it is left all one block, which frees or collapses it, or when its
+/// blocks now fit a narrower index width than `bits`.
+fn needs_repack(bits: u32, voxels: &[u32]) -> bool {
+    let mut blocks = voxels.to_vec();
+    blocks.sort_unstable();
+    blocks.dedup();
+    blocks.len() == 1 || index_bits(blocks.len()) < bits
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{allocator::ChunkAllocator, palette::PackedChunk};
+
+    fn span(min: Vector3<usize>, max: Vector3<usize>) -> ChunkSpan {
+        ChunkSpan {
+            dir_index: 0,
+            origin: Vector3::zeros(),
+            min,
+            max,
+        }
+    }
+
+    /// Edits `voxels` packed in place, returning the result unpacked, or None
+    /// when the palette outgrew its width.
+    fn edit_in_place<F>(voxels: &[u32], span: &ChunkSpan, mut brush: F) -> Option<Vec<u32>>
+    where
+        F: FnMut(Vector3<i32>, u32) -> Option<u32>,
+    {
+        let packed = PackedChunk::encode(voxels);
+        let edit = edit_packed(&packed, span, &mut brush)?;
+        let mut words = packed.words.clone();
+        for (first, row) in edit.rows {
+            words[first..first + row.len()].copy_from_slice(&row);
+        }
+        Some(PackedChunk::decode(&edit.palette, &words))
+    }
+
+    /// Reads a span's rows out of `packed` and packs the brush's changes
+    /// into them, as `edit_region` does.
+    fn edit_packed<F>(packed: &PackedChunk, span: &ChunkSpan, brush: &mut F) -> Option<PackedEdit>
+    where
+        F: FnMut(Vector3<i32>, u32) -> Option<u32>,
+    {
+        let read = read_rows(packed, span);
+        let old = unpack_span(span, &packed.palette, &read);
+        let changes = brush_changes(span, &old, brush, &mut None);
+        pack_changes(span, &packed.palette, &read, &changes)
+    }
+
+    fn read_rows(packed: &PackedChunk, span: &ChunkSpan) -> Vec<u32> {
+        span.rows()
+            .flat_map(|(y, z)| {
+                let (first, end) = span.row_words(y, z, packed.palette.bits);
+                packed.words[first..end].to_vec()
+            })
+            .collect()
+    }
+
+    fn edit_dense<F>(voxels: &[u32], span: &ChunkSpan, mut brush: F) -> Vec<u32>
+    where
+        F: FnMut(Vector3<i32>, u32) -> Option<u32>,
+    {
+        let mut voxels = voxels.to_vec();
+        for (y, z) in span.rows() {
+            for x in span.min.x..=span.max.x {
+                let index = local_index(x, y, z);
+                if let Some(block) = brush(Vector3::new(x, y, z).cast(), voxels[index]) {
+                    voxels[index] = block;
+                }
+            }
+        }
+        voxels
+    }
+
+    fn chunk_with_blocks(count: u32) -> Vec<u32> {
+        (0..CHUNK_VOLUME as u32)
+            .map(|i| 1 + i * 13 % count)
+            .collect()
+    }
+
+    #[test]
+    fn in_place_edits_match_dense_edits() {
+        let spans = [
+            span(Vector3::new(5, 6, 7), Vector3::new(5, 6, 7)),
+            span(Vector3::new(3, 0, 9), Vector3::new(28, 31, 12)),
+            span(Vector3::new(0, 4, 0), Vector3::new(31, 4, 31)),
+        ];
+        for count in [2, 3, 9, 100, 1000] {
+            let voxels = chunk_with_blocks(count);
+            for span in &spans {
+                // Swaps blocks already in the palette, every width fits that
+                let brush = |pos: Vector3<i32>, old: u32| {
+                    ((pos.x + pos.z) % 2 == 0).then_some(1 + old % count)
+                };
+                let edited =
+                    edit_in_place(&voxels, span, brush).expect("palette blocks always fit");
+                assert_eq!(edited, edit_dense(&voxels, span, brush), "{} blocks", count);
+            }
+        }
+    }
+
+    #[test]
+    fn new_blocks_are_appended_while_they_fit() {
+        // Three blocks pack at 2 bits, leaving room for a fourth
+        let voxels = chunk_with_blocks(3);
+        let span = span(Vector3::new(1, 1, 1), Vector3::new(2, 2, 2));
+        let brush = |_, _| Some(77);
+        let edited = edit_in_place(&voxels, &span, brush).expect("a fourth block fits 2 bits");
+        assert_eq!(edited, edit_dense(&voxels, &span, brush));
+    }
+
+    #[test]
+    fn outgrown_palette_needs_repack() {
+        // Four blocks fill 2 bits
+        let voxels = chunk_with_blocks(4);
+        let span = span(Vector3::new(1, 1, 1), Vector3::new(1, 1, 1));
+        assert!(edit_in_place(&voxels, &span, |_, _| Some(77)).is_none());
+    }
+
+    #[test]
+    fn unchanged_rows_are_not_written() {
+        let packed = PackedChunk::encode(&chunk_with_blocks(5));
+        let span = span(Vector3::zeros(), Vector3::new(31, 1, 0));
+        let mut brush = |pos: Vector3<i32>, _| (pos.y == 1).then_some(2);
+        let edit = edit_packed(&packed, &span, &mut brush).unwrap();
+        assert_eq!(edit.rows.len(), 1);
+        assert_eq!(edit.rows[0].0, span.row_words(1, 0, packed.palette.bits).0);
+    }
+
+    #[test]
+    fn brush_runs_once_per_voxel() {
+        // A stateful brush whose changes outgrow the palette: they are kept
+        // for the repack rather than brushed again
+        let packed = PackedChunk::encode(&chunk_with_blocks(4));
+        let span = span(Vector3::new(0, 0, 0), Vector3::new(3, 0, 0));
+        let mut calls = 0;
+        let mut brush = |_, _| {
+            calls += 1;
+            Some(100 + calls)
+        };
+        let old = unpack_span(&span, &packed.palette, &read_rows(&packed, &span));
+        let changes = brush_changes(&span, &old, &mut brush, &mut None);
+        assert!(
+            pack_changes(&span, &packed.palette, &read_rows(&packed, &span), &changes).is_none()
+        );
+        assert_eq!(calls, 4);
+        assert_eq!(changes, [(0, 101), (1, 102), (2, 103), (3, 104)]);
+    }
+
+    #[test]
+    fn rejected_ids_are_left_out() {
+        let span = span(Vector3::zeros(), Vector3::new(1, 0, 0));
+        let mut rejected = None;
+        let changes = brush_changes(
+            &span,
+            &[1; CHUNK_VOLUME],
+            &mut |pos: Vector3<i32>, _| Some(if pos.x == 0 { MAX_BLOCK_ID + 1 } else { 2 }),
+            &mut rejected,
+        );
+        assert_eq!(changes, [(1, 2)]);
+        assert_eq!(rejected, Some(MAX_BLOCK_ID + 1));
+    }
+
+    #[test]
+    fn digging_a_chunk_out_returns_its_slot() {
+        // Mines a packed chunk voxel by voxel the way set_voxel does: in
+        // place until `needs_repack` hands it to write_chunks, whose all-air
+        // chunk gives its slot back
+        let mut slots = ChunkAllocator::new(4);
+        let slot = slots.allocate().unwrap();
+        let mut voxels = vec![0; CHUNK_VOLUME];
+        for x in 0..4 {
+            voxels[local_index(x, 0, 0)] = 3 + x as u32 % 2;
+        }
+        let mut packed = PackedChunk::encode(&voxels);
+        assert_eq!(packed.palette.bits, 2);
+
+        let mut repacks = Vec::new();
+        for x in 0..4 {
+            let span = span(Vector3::new(x, 0, 0), Vector3::new(x, 0, 0));
+            let edit = edit_packed(&packed, &span, &mut |_, _| Some(0)).unwrap();
+            for (first, row) in edit.rows {
+                packed.words[first..first + row.len()].copy_from_slice(&row);
+            }
+            packed.palette = edit.palette;
+            let current = PackedChunk::decode(&packed.palette, &packed.words);
+            if needs_repack(packed.palette.bits, &current) {
+                repacks.push(x);
+                packed = PackedChunk::encode(&current);
+                if packed.palette.uniform() == Some(0) {
+                    slots.free(slot);
+                }
+            }
+        }
+
+        // Down to two blocks the chunk packs at 1 bit, dropping the dead
+        // entry, then the last voxel leaves it all air
+        assert_eq!(repacks, [2, 3]);
+        assert_eq!(slots.used(), 0);
+    }
+}
@@ -1,30 +1,132 @@
+use std::{
+    path::{Path, PathBuf},
+    time::Instant,
+};
+
 use ash::vk;
 use gpu_allocator::MemoryLocation;
+use log::*;
+use nalgebra::{Point3, Vector3};
 use winit::{event_loop::ActiveEventLoop, window::Window};
 
 use crate::{
-    core::world::ChunkedWorld, vulkan::{
-        buffer::Buffer, camera::{Camera, CameraUniform}, context::VulkanContext, pipelines::raytrace::TestPipeline, swapchain::{SurfaceSwapchain, SurfaceSync}
-    }
+    core::{
+        capture,
+        environment::{Environment, EnvironmentUniform},
+        error::VoxelError,
+        generator::GeneratorSettings,
+        lights::LightSet,
+        materials::{MATERIALS_FILE, MaterialRegistry, TEXTURES_DIR},
+        streaming::{StreamingSettings, WorldStreamer},
+        tree::{TreeBuffers, TreeChunks},
+        world::{CHUNK_SIZE, ChunkedWorld},
+    },
+    vulkan::{
+        accumulation::AccumulationImage,
+        buffer::Buffer,
+        camera::{Camera, CameraUniform},
+        context::VulkanContext,
+        offscreen::OffscreenTarget,
+        pipelines::raytrace::{
+            FrameConstants, RenderSettings, SceneBindings, TestPipeline, WorldLayout,
+        },
+        swapchain::{SurfaceSwapchain, SurfaceSync},
+        texture::TextureArray,
+    },
 };
 
+/// Everything a path traced sample depends on besides the world itself.
+#[derive(PartialEq)]
+struct AccumulationView {
+    position: Point3<f32>,
+    forward: Vector3<f32>,
+    aspect: f32,
+    fov: f32,
+    environment: Environment,
+    settings: RenderSettings,
+    window_origin: Vector3<i32>,
+}
+
+/// Where the raytrace pass writes its output.
+#[allow(clippy::large_enum_variant)]
+pub enum RenderTarget {
+    Surface {
+        window: Window,
+        swapchain: SurfaceSwapchain,
+        sync: SurfaceSync,
+    },
+    Offscreen(OffscreenTarget),
+}
+
+impl RenderTarget {
+    pub fn extent(&self) -> vk::Extent2D {
+        match self {
+            RenderTarget::Surface { swapchain, .. } => swapchain.extent,
+            RenderTarget::Offscreen(target) => target.extent,
+        }
+    }
+
+    pub fn image_views(&self) -> Vec<vk::ImageView> {
+        match self {
+            RenderTarget::Surface { swapchain, .. } => swapchain.image_views.clone(),
+            RenderTarget::Offscreen(target) => vec![target.image_view],
+        }
+    }
+
+    /// Destroys the swapchain and its sync objects or the offscreen image.
+    /// The window itself outlives this, it is dropped with the target.
+    pub fn destroy(&mut self, context: &VulkanContext) {
+        match self {
+            RenderTarget::Surface {
+                swapchain, sync, ..
+            } => {
+                swapchain.destroy(context);
+                sync.destroy(context);
+            }
+            RenderTarget::Offscreen(target) => target.destroy(context),
+        }
+    }
+}
+
 #[allow(unused)]
 pub struct VoxelEngine {
     pub frame: usize,
-    pub window: Window,
+    pub target: RenderTarget,
     pub vkcontext: VulkanContext,
-    pub swapchain: SurfaceSwapchain,
     pub pipeline: TestPipeline,
-    pub sync: SurfaceSync,
     pub command_pool: vk::CommandPool,
     pub command_buffers: Vec<vk::CommandBuffer>,
     pub camera: Camera,
     pub camera_buffer: Buffer,
+    pub render_settings: RenderSettings,
+    pub environment: Environment,
+    pub environment_buffer: Buffer,
+    pub lights: LightSet,
+    pub accumulation: AccumulationImage,
+    /// Tree copy of the world, kept current while the tree layout is selected
+    pub tree: TreeBuffers,
+    /// Per-chunk subtrees the tree is assembled from
+    pub tree_chunks: TreeChunks,
+    /// Path traced samples averaged into `accumulation` so far
+    pub accumulated_samples: u32,
+    /// View the accumulated samples were traced from, None forces a restart
+    accumulated_view: Option<AccumulationView>,
+    /// When the previous windowed frame was drawn, drives the day cycle
+    pub last_frame_time: Option<Instant>,
     pub world: ChunkedWorld,
+    pub materials: MaterialRegistry,
+    pub material_buffer: Buffer,
+    pub textures: TextureArray,
+    pub streamer: Option<WorldStreamer>,
+    pub pending_capture: Option<PathBuf>,
 }
 
 impl VoxelEngine {
-    pub fn new(event_loop: &ActiveEventLoop) -> Result<Self, vk::Result> {
+    pub fn new(
+        event_loop: &ActiveEventLoop,
+        generator_settings: GeneratorSettings,
+    ) -> Result<Self, VoxelError> {
+        generator_settings.validate()?;
         let window = event_loop
             .create_window(Window::default_attributes())
             .expect("Window not created");
@@ -32,9 +134,37 @@ impl VoxelEngine {
         let window_size = window.inner_size();
         let swapchain = SurfaceSwapchain::new(&vkcontext, window_size.width, window_size.height)
             .expect("Swapchain not created");
-        let image_count = swapchain.images.len();
-        let sync = SurfaceSync::new(&vkcontext, image_count)?;
-        let aspect = window_size.width as f32 / window_size.height as f32;
+        let sync = SurfaceSync::new(&vkcontext, swapchain.images.len())?;
+        let target = RenderTarget::Surface {
+            window,
+            swapchain,
+            sync,
+        };
+        Self::with_target(vkcontext, target, generator_settings)
+    }
+
+    /// Builds an engine that renders into an offscreen image, without a window
+    /// or a presentation capable device. Frames are fetched with `render_offscreen`.
+    pub fn new_headless(
+        width: u32,
+        height: u32,
+        generator_settings: GeneratorSettings,
+    ) -> Result<Self, VoxelError> {
+        generator_settings.validate()?;
+        let vkcontext = VulkanContext::new_headless()?;
+        let target = RenderTarget::Offscreen(OffscreenTarget::new(&vkcontext, width, height)?);
+        Self::with_target(vkcontext, target, generator_settings)
+    }
+
+    fn with_target(
+        vkcontext: VulkanContext,
+        target: RenderTarget,
+        generator_settings: GeneratorSettings,
+    ) -> Result<Self, VoxelError> {
+        let extent = target.extent();
+        let target_views = target.image_views();
+        let image_count = target_views.len();
+        let aspect = extent.width as f32 / extent.height as f32;
         let camera = Camera::new(aspect);
         let camera_buffer = Buffer::new(
             &vkcontext,
@@ -44,9 +174,50 @@ impl VoxelEngine {
             "Camera",
         )
         .expect("Camera buffer not created");
-        let world = ChunkedWorld::new(&vkcontext)?;
-        let pipeline = TestPipeline::new(&vkcontext, &swapchain, &camera_buffer, &world)
-            .expect("Pipeline not created");
+        let world = ChunkedWorld::new(&vkcontext, generator_settings)?;
+        let materials = MaterialRegistry::load(Path::new(MATERIALS_FILE)).unwrap_or_else(|err| {
+            warn!(
+                "Unable to load {}, using builtin materials: {}",
+                MATERIALS_FILE, err
+            );
+            MaterialRegistry::builtin()
+        });
+        let material_data = materials.gpu_data();
+        let mut material_buffer = Buffer::new(
+            &vkcontext,
+            std::mem::size_of_val(material_data.as_slice()) as u64,
+            vk::BufferUsageFlags::STORAGE_BUFFER,
+            MemoryLocation::CpuToGpu,
+            "Materials",
+        )?;
+        material_buffer.update_slice(&material_data)?;
+        let textures = TextureArray::load(
+            &vkcontext,
+            Path::new(TEXTURES_DIR),
+            &materials.texture_names(),
+        )?;
+        let environment_buffer = Buffer::new(
+            &vkcontext,
+            std::mem::size_of::<EnvironmentUniform>() as u64,
+            vk::BufferUsageFlags::UNIFORM_BUFFER,
+            MemoryLocation::CpuToGpu,
+            "Environment",
+        )?;
+        let lights = LightSet::new(&vkcontext)?;
+        let accumulation = AccumulationImage::new(&vkcontext, extent)?;
+        let tree = TreeBuffers::new(&vkcontext)?;
+        let scene = SceneBindings {
+            camera_buffer: &camera_buffer,
+            world: &world,
+            material_buffer: &material_buffer,
+            textures: &textures,
+            environment_buffer: &environment_buffer,
+            lights: &lights,
+            accumulation: &accumulation,
+            tree: &tree,
+        };
+        let pipeline =
+            TestPipeline::new(&vkcontext, &target_views, &scene).expect("Pipeline not created");
         let command_pool = unsafe {
             let create_info = vk::CommandPoolCreateInfo::default()
                 .queue_family_index(vkcontext.compute_queue_fi)
@@ -62,44 +233,280 @@ impl VoxelEngine {
         };
         Ok(Self {
             frame: 0,
-            window,
+            target,
             vkcontext,
-            swapchain,
             pipeline,
-            sync,
             command_pool,
             command_buffers,
             camera,
             camera_buffer,
+            render_settings: RenderSettings::default(),
+            environment: Environment::default(),
+            environment_buffer,
+            lights,
+            accumulation,
+            tree,
+            tree_chunks: TreeChunks::default(),
+            accumulated_samples: 0,
+            accumulated_view: None,
+            last_frame_time: None,
             world,
+            materials,
+            material_buffer,
+            textures,
+            streamer: None,
+            pending_capture: None,
         })
     }
 
+    /// Makes the world follow the camera instead of staying a fixed region.
+    /// Camera rays then reach as far as the resident chunks.
+    pub fn enable_streaming(&mut self, settings: StreamingSettings) {
+        let streamer = WorldStreamer::new(settings);
+        self.render_settings.view_distance =
+            ((streamer.settings.radius + 1) as usize * CHUNK_SIZE) as f32;
+        self.streamer = Some(streamer);
+    }
+
+    /// Advances world streaming by one step, called before each frame.
+    pub fn update_streaming(&mut self) -> Result<(), vk::Result> {
+        let Some(streamer) = self.streamer.as_mut() else {
+            return Ok(());
+        };
+        if !streamer.needs_update(self.camera.position) {
+            return Ok(());
+        }
+        // The directory is rewritten from the host, frames in flight must be done with it
+        unsafe {
+            self.vkcontext
+                .device
+                .queue_wait_idle(self.vkcontext.compute_queue)?
+        };
+        self.accumulated_view = None;
+        streamer.update(&self.vkcontext, &mut self.world, self.camera.position)
+    }
+
+    /// Saves the world into region files under `dir`.
+    pub fn save_world(&mut self, dir: &Path) -> Result<(), VoxelError> {
+        unsafe {
+            self.vkcontext
+                .device
+                .queue_wait_idle(self.vkcontext.compute_queue)?
+        };
+        self.world.save(&self.vkcontext, dir)
+    }
+
+    /// Replaces the resident world with the region files under `dir`.
+    pub fn load_world(&mut self, dir: &Path) -> Result<(), VoxelError> {
+        unsafe {
+            self.vkcontext
+                .device
+                .queue_wait_idle(self.vkcontext.compute_queue)?
+        };
+        let loaded = self.world.load(&self.vkcontext, dir)?;
+        // Lights belong to the world they were placed in, the loaded chunks
+        // bring their own block lights
+        self.lights.clear();
+        self.reset_accumulation();
+        if let Some(streamer) = self.streamer.as_mut() {
+            streamer.reset(loaded);
+        }
+        Ok(())
+    }
+
+    /// Replaces the block materials and reloads their textures, visible from
+    /// the next frame.
+    pub fn set_materials(&mut self, materials: MaterialRegistry) -> Result<(), vk::Result> {
+        // The buffer is host visible and read by frames in flight
+        unsafe {
+            self.vkcontext
+                .device
+                .queue_wait_idle(self.vkcontext.compute_queue)?
+        };
+        let textures = TextureArray::load(
+            &self.vkcontext,
+            Path::new(TEXTURES_DIR),
+            &materials.texture_names(),
+        )?;
+        self.material_buffer.update_slice(&materials.gpu_data())?;
+        self.textures.destroy(&self.vkcontext);
+        self.textures = textures;
+        self.materials = materials;
+        // Blocks may have started or stopped glowing
+        self.lights.rescan_blocks();
+        self.reset_accumulation();
+        self.pipeline.update_descriptors(
+            &self.vkcontext,
+            &self.target.image_views(),
+            &self.scene_bindings(),
+        );
+        Ok(())
+    }
+
+    fn scene_bindings(&self) -> SceneBindings<'_> {
+        SceneBindings {
+            camera_buffer: &self.camera_buffer,
+            world: &self.world,
+            material_buffer: &self.material_buffer,
+            textures: &self.textures,
+            environment_buffer: &self.environment_buffer,
+            lights: &self.lights,
+            accumulation: &self.accumulation,
+            tree: &self.tree,
+        }
+    }
+
+    /// Drops the path traced samples gathered so far, for changes the view
+    /// comparison can't see such as edits.
+    pub fn reset_accumulation(&mut self) {
+        self.accumulated_view = None;
+    }
+
+    /// Restarts accumulation when anything affecting the image changed since
+    /// the previous frame.
+    fn update_accumulation(&mut self) {
+        let view = AccumulationView {
+            position: self.camera.position,
+            forward: self.camera.forward,
+            aspect: self.camera.aspect,
+            fov: self.camera.fov,
+            environment: self.environment,
+            settings: self.render_settings,
+            window_origin: self.world.window_origin,
+        };
+        if self.accumulated_view.as_ref() != Some(&view) {
+            self.accumulated_samples = 0;
+            self.accumulated_view = Some(view);
+        }
+    }
+
+    /// Sets one voxel. Emissive blocks get their light on the next frame.
+    pub fn set_block(&mut self, pos: Vector3<i32>, block: u32) -> Result<(), vk::Result> {
+        // The directory, headers and palettes are rewritten from the host,
+        // frames in flight must be done with them
+        unsafe {
+            self.vkcontext
+                .device
+                .queue_wait_idle(self.vkcontext.compute_queue)?
+        };
+        self.world.set_voxel(&self.vkcontext, pos, block)?;
+        self.reset_accumulation();
+        Ok(())
+    }
+
+    /// Rescans the chunks the world changed for emissive blocks and uploads
+    /// point lights changed since the last frame.
+    fn update_lights(&mut self) -> Result<(), vk::Result> {
+        self.lights
+            .sync_world(&self.vkcontext, &self.world, &self.materials)?;
+        let origin = self.world.window_origin;
+        if !self.lights.needs_upload(origin) {
+            return Ok(());
+        }
+        unsafe {
+            self.vkcontext
+                .device
+                .queue_wait_idle(self.vkcontext.compute_queue)?
+        };
+        self.lights.upload(origin)
+    }
+
+    /// Rebuilds the voxel tree when it is the selected layout and the world
+    /// changed since it was built. Only the chunks that changed are read
+    /// back and rebuilt.
+    fn update_tree(&mut self) -> Result<(), vk::Result> {
+        if self.render_settings.layout != WorldLayout::Tree
+            || self.tree.revision == Some(self.world.revision)
+        {
+            return Ok(());
+        }
+        self.tree_chunks.update(&self.vkcontext, &self.world)?;
+        let tree = self.tree_chunks.tree(self.world.window_origin);
+        unsafe {
+            self.vkcontext
+                .device
+                .queue_wait_idle(self.vkcontext.compute_queue)?
+        };
+        self.tree
+            .upload(&self.vkcontext, &tree, self.world.revision)?;
+        self.pipeline.update_descriptors(
+            &self.vkcontext,
+            &self.target.image_views(),
+            &self.scene_bindings(),
+        );
+        Ok(())
+    }
+
+    /// Reloads the block materials from a material file.
+    pub fn reload_materials(&mut self, path: &Path) -> Result<(), VoxelError> {
+        let materials = MaterialRegistry::load(path)?;
+        self.set_materials(materials)?;
+        Ok(())
+    }
+
     pub fn draw_frame(&mut self) -> Result<(), vk::Result> {
+        let now = Instant::now();
+        if let Some(last) = self.last_frame_time {
+            self.environment.advance((now - last).as_secs_f32());
+        }
+        self.last_frame_time = Some(now);
+
+        self.update_streaming()?;
+        self.update_lights()?;
+        self.update_tree()?;
+        self.update_accumulation();
+        let capture_path = self.pending_capture.take();
+        let RenderTarget::Surface {
+            swapchain, sync, ..
+        } = &self.target
+        else {
+            return self.render_offscreen().map(|_| ());
+        };
         let device = &self.vkcontext.device;
-        let current_frame = self.sync.current_frame;
+        let current_frame = sync.current_frame;
+
+        let mut capture = match capture_path {
+            Some(_) if !swapchain.usage.contains(vk::ImageUsageFlags::TRANSFER_SRC) => {
+                error!("Surface images can't be copied, screenshot skipped");
+                None
+            }
+            Some(path) => {
+                let extent = swapchain.extent;
+                let buffer = Buffer::new(
+                    &self.vkcontext,
+                    extent.width as u64 * extent.height as u64 * 4,
+                    vk::BufferUsageFlags::TRANSFER_DST,
+                    MemoryLocation::GpuToCpu,
+                    "Capture Readback",
+                )?;
+                Some((path, buffer))
+            }
+            None => None,
+        };
 
         let ubo_data = self.camera.get_uniform();
         self.camera_buffer.update_item(ubo_data)?;
+        self.environment_buffer
+            .update_item(self.environment.uniform())?;
 
         unsafe {
-            device.wait_for_fences(&[self.sync.in_flight_fences[current_frame]], true, u64::MAX)?;
+            device.wait_for_fences(&[sync.in_flight_fences[current_frame]], true, u64::MAX)?;
 
-            let (image_index, _) = self.swapchain.swapchain_loader.acquire_next_image(
-                self.swapchain.swapchain,
+            let (image_index, _) = swapchain.swapchain_loader.acquire_next_image(
+                swapchain.swapchain,
                 u64::MAX,
-                self.sync.image_available_semaphores[current_frame],
+                sync.image_available_semaphores[current_frame],
                 vk::Fence::null(),
             )?;
 
-            device.reset_fences(&[self.sync.in_flight_fences[current_frame]])?;
+            device.reset_fences(&[sync.in_flight_fences[current_frame]])?;
 
             let cmd = self.command_buffers[current_frame];
-            let wait_semaphores = [self.sync.image_available_semaphores[current_frame]];
-            let signal_semaphores = [self.sync.render_finished_semaphores[image_index as usize]];
+            let wait_semaphores = [sync.image_available_semaphores[current_frame]];
+            let signal_semaphores = [sync.render_finished_semaphores[image_index as usize]];
             let command_buffers = [cmd];
             let wait_stages = [vk::PipelineStageFlags::COMPUTE_SHADER];
-            let swapchains = [self.swapchain.swapchain];
+            let swapchains = [swapchain.swapchain];
             let image_indices = [image_index];
 
             let submit_info = vk::SubmitInfo::default()
@@ -114,49 +521,147 @@ impl VoxelEngine {
 
             device.reset_command_buffer(cmd, vk::CommandBufferResetFlags::empty())?;
 
-            self.record_compute_commands(cmd, image_index as usize)?;
+            self.record_compute_commands(
+                cmd,
+                swapchain.images[image_index as usize],
+                image_index as usize,
+                swapchain.extent,
+                capture.as_ref().map(|(_, buffer)| buffer.buffer),
+                true,
+            )?;
 
             device.queue_submit(
                 self.vkcontext.compute_queue,
                 &[submit_info],
-                self.sync.in_flight_fences[current_frame],
+                sync.in_flight_fences[current_frame],
             )?;
 
-            self.swapchain
+            swapchain
                 .swapchain_loader
                 .queue_present(self.vkcontext.compute_queue, &present_info)?;
         }
+        self.accumulated_samples = self.accumulated_samples.saturating_add(1);
+
+        if let Some((path, buffer)) = capture.as_mut() {
+            unsafe {
+                device.wait_for_fences(&[sync.in_flight_fences[current_frame]], true, u64::MAX)?;
+            }
+            let extent = swapchain.extent;
+            let result = buffer
+                .read_slice::<u8>(extent.width as usize * extent.height as usize * 4)
+                .map_err(VoxelError::from)
+                .and_then(|pixels| capture::to_rgba(swapchain.surface_format.format, pixels))
+                .and_then(|rgba| capture::save_rgba(path, extent.width, extent.height, &rgba));
+            match result {
+                Ok(()) => info!("Screenshot saved to {}", path.display()),
+                Err(err) => error!("Unable to save screenshot {}: {}", path.display(), err),
+            }
+            buffer.destroy(&self.vkcontext);
+        }
 
         self.frame = (self.frame + 1) % usize::MAX;
-        self.sync.current_frame = (self.sync.current_frame + 1) % self.sync.in_flight_fences.len();
+        if let RenderTarget::Surface { sync, .. } = &mut self.target {
+            sync.current_frame = (sync.current_frame + 1) % sync.in_flight_fences.len();
+        }
 
         Ok(())
     }
 
+    /// Saves the next frame to `path` (PNG, or PPM for a `.ppm` extension).
+    /// Windowed engines copy the swapchain image during the next `draw_frame`;
+    /// headless engines render and save immediately.
+    pub fn capture_frame(&mut self, path: impl Into<PathBuf>) -> Result<(), VoxelError> {
+        let path = path.into();
+        match &self.target {
+            RenderTarget::Surface { .. } => {
+                self.pending_capture = Some(path);
+                Ok(())
+            }
+            RenderTarget::Offscreen(target) => {
+                let (format, extent) = (target.format, target.extent);
+                let pixels = self.render_offscreen()?;
+                let rgba = capture::to_rgba(format, pixels)?;
+                capture::save_rgba(&path, extent.width, extent.height, &rgba)?;
+                info!("Frame saved to {}", path.display());
+                Ok(())
+            }
+        }
+    }
+
+    /// Renders one frame into the offscreen target and returns it as tightly
+    /// packed RGBA8 rows. Blocks until the GPU is done.
+    pub fn render_offscreen(&mut self) -> Result<Vec<u8>, vk::Result> {
+        if !matches!(self.target, RenderTarget::Offscreen(_)) {
+            return Err(vk::Result::ERROR_FEATURE_NOT_PRESENT);
+        }
+        self.update_streaming()?;
+        self.update_lights()?;
+        self.update_tree()?;
+        self.update_accumulation();
+        let RenderTarget::Offscreen(target) = &self.target else {
+            unreachable!();
+        };
+        let device = &self.vkcontext.device;
+
+        let ubo_data = self.camera.get_uniform();
+        self.camera_buffer.update_item(ubo_data)?;
+        self.environment_buffer
+            .update_item(self.environment.uniform())?;
+
+        unsafe {
+            let cmd = self.command_buffers[0];
+            let command_buffers = [cmd];
+            let submit_info = vk::SubmitInfo::default().command_buffers(&command_buffers);
+
+            device.reset_command_buffer(cmd, vk::CommandBufferResetFlags::empty())?;
+
+            self.record_compute_commands(
+                cmd,
+                target.image,
+                0,
+                target.extent,
+                Some(target.readback.buffer),
+                false,
+            )?;
+
+            device.queue_submit(
+                self.vkcontext.compute_queue,
+                &[submit_info],
+                vk::Fence::null(),
+            )?;
+            device.queue_wait_idle(self.vkcontext.compute_queue)?;
+        }
+        self.accumulated_samples = self.accumulated_samples.saturating_add(1);
+
+        let pixel_count = (target.extent.width * target.extent.height) as usize;
+        let pixels = target.readback.read_slice::<u8>(pixel_count * 4)?;
+
+        self.frame = (self.frame + 1) % usize::MAX;
+
+        Ok(pixels)
+    }
+
     pub fn rebuild_swapchain(&mut self, width: u32, height: u32) -> Result<(), vk::Result> {
+        let RenderTarget::Surface {
+            swapchain, sync, ..
+        } = &mut self.target
+        else {
+            return Ok(());
+        };
         let device = &self.vkcontext.device;
         unsafe {
             device.device_wait_idle()?;
-            for view in &self.swapchain.image_views {
-                device.destroy_image_view(*view, None);
-            }
-            self.swapchain
-                .swapchain_loader
-                .destroy_swapchain(self.swapchain.swapchain, None);
+            swapchain.destroy(&self.vkcontext);
             let new_swapchain = SurfaceSwapchain::new(&self.vkcontext, width, height)?;
-            self.swapchain = new_swapchain;
+            *swapchain = new_swapchain;
 
-            let sync_len = self.sync.in_flight_fences.len();
-            let swapchain_len = self.swapchain.images.len();
+            let sync_len = sync.in_flight_fences.len();
+            let swapchain_len = swapchain.images.len();
 
             if sync_len != swapchain_len {
-                for i in 0..sync_len {
-                    device.destroy_fence(self.sync.in_flight_fences[i], None);
-                    device.destroy_semaphore(self.sync.image_available_semaphores[i], None);
-                    device.destroy_semaphore(self.sync.render_finished_semaphores[i], None);
-                }
+                sync.destroy(&self.vkcontext);
                 device.free_command_buffers(self.command_pool, self.command_buffers.as_slice());
-                self.sync = SurfaceSync::new(&self.vkcontext, swapchain_len)?;
+                *sync = SurfaceSync::new(&self.vkcontext, swapchain_len)?;
                 self.command_buffers = {
                     let allocate_info = vk::CommandBufferAllocateInfo::default()
                         .command_pool(self.command_pool)
@@ -167,26 +672,34 @@ impl VoxelEngine {
                         .allocate_command_buffers(&allocate_info)?
                 };
             }
-            self.sync.current_frame = 0;
-            self.pipeline.update_descriptors(
-                &self.vkcontext,
-                &self.swapchain,
-                &self.camera_buffer,
-                &self.world,
-            );
+            sync.current_frame = 0;
         }
+        self.accumulation.destroy(&self.vkcontext);
+        self.accumulation = AccumulationImage::new(&self.vkcontext, self.target.extent())?;
+        self.reset_accumulation();
+        self.pipeline.update_descriptors(
+            &self.vkcontext,
+            &self.target.image_views(),
+            &self.scene_bindings(),
+        );
         Ok(())
     }
 
+    /// Records the raytrace dispatch into `target_image`. When a `readback`
+    /// buffer is given the image is copied into it, and `present` transitions
+    /// the image for the presentation engine afterwards.
     fn record_compute_commands(
         &self,
         cmd: vk::CommandBuffer,
-        image_index: usize,
+        target_image: vk::Image,
+        descriptor_index: usize,
+        extent: vk::Extent2D,
+        readback: Option<vk::Buffer>,
+        present: bool,
     ) -> Result<(), vk::Result> {
         let device = &self.vkcontext.device;
-        let target_image = self.swapchain.images[image_index];
-        let x_groups = (self.swapchain.extent.width + 15).div_ceil(16);
-        let y_groups = (self.swapchain.extent.height + 15).div_ceil(16);
+        let x_groups = (extent.width + 15).div_ceil(16);
+        let y_groups = (extent.height + 15).div_ceil(16);
         let begin_info = vk::CommandBufferBeginInfo::default();
         let subresource_range = vk::ImageSubresourceRange {
             aspect_mask: vk::ImageAspectFlags::COLOR,
@@ -213,6 +726,15 @@ impl VoxelEngine {
             .subresource_range(subresource_range)
             .src_access_mask(vk::AccessFlags::SHADER_WRITE)
             .dst_access_mask(vk::AccessFlags::empty());
+        let barrier_to_transfer = vk::ImageMemoryBarrier::default()
+            .old_layout(vk::ImageLayout::GENERAL)
+            .new_layout(vk::ImageLayout::TRANSFER_SRC_OPTIMAL)
+            .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .image(target_image)
+            .subresource_range(subresource_range)
+            .src_access_mask(vk::AccessFlags::SHADER_WRITE)
+            .dst_access_mask(vk::AccessFlags::TRANSFER_READ);
 
         unsafe {
             device.begin_command_buffer(cmd, &begin_info)?;
@@ -225,27 +747,141 @@ impl VoxelEngine {
                 &[],
                 &[barrier_to_compute],
             );
+            // The accumulation image is shared by all frames, the previous
+            // frame's dispatch must be done with it
+            let accumulation_barrier = vk::MemoryBarrier::default()
+                .src_access_mask(vk::AccessFlags::SHADER_WRITE)
+                .dst_access_mask(vk::AccessFlags::SHADER_READ | vk::AccessFlags::SHADER_WRITE);
+            device.cmd_pipeline_barrier(
+                cmd,
+                vk::PipelineStageFlags::COMPUTE_SHADER,
+                vk::PipelineStageFlags::COMPUTE_SHADER,
+                vk::DependencyFlags::empty(),
+                &[accumulation_barrier],
+                &[],
+                &[],
+            );
             device.cmd_bind_pipeline(cmd, vk::PipelineBindPoint::COMPUTE, self.pipeline.pipeline);
             device.cmd_bind_descriptor_sets(
                 cmd,
                 vk::PipelineBindPoint::COMPUTE,
                 self.pipeline.layout,
                 0,
-                &[self.pipeline.descriptor_sets[image_index]],
+                &[self.pipeline.descriptor_sets[descriptor_index]],
                 &[],
             );
-            device.cmd_dispatch(cmd, x_groups, y_groups, 1);
-            device.cmd_pipeline_barrier(
+            let origin = self.world.window_origin;
+            let constants = FrameConstants::new(
+                [origin.x, origin.y, origin.z, 0],
+                &self.render_settings,
+                self.accumulated_samples,
+            );
+            device.cmd_push_constants(
                 cmd,
-                vk::PipelineStageFlags::COMPUTE_SHADER,
-                vk::PipelineStageFlags::BOTTOM_OF_PIPE,
-                vk::DependencyFlags::empty(),
-                &[],
-                &[],
-                &[barrier_to_present],
+                self.pipeline.layout,
+                vk::ShaderStageFlags::COMPUTE,
+                0,
+                constants.as_bytes(),
             );
+            device.cmd_dispatch(cmd, x_groups, y_groups, 1);
+            if let Some(buffer) = readback {
+                device.cmd_pipeline_barrier(
+                    cmd,
+                    vk::PipelineStageFlags::COMPUTE_SHADER,
+                    vk::PipelineStageFlags::TRANSFER,
+                    vk::DependencyFlags::empty(),
+                    &[],
+                    &[],
+                    &[barrier_to_transfer],
+                );
+                let region = vk::BufferImageCopy::default()
+                    .image_subresource(vk::ImageSubresourceLayers {
+                        aspect_mask: vk::ImageAspectFlags::COLOR,
+                        mip_level: 0,
+                        base_array_layer: 0,
+                        layer_count: 1,
+                    })
+                    .image_extent(vk::Extent3D {
+                        width: extent.width,
+                        height: extent.height,
+                        depth: 1,
+                    });
+                device.cmd_copy_image_to_buffer(
+                    cmd,
+                    target_image,
+                    vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                    buffer,
+                    &[region],
+                );
+                let host_barrier = vk::MemoryBarrier::default()
+                    .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+                    .dst_access_mask(vk::AccessFlags::HOST_READ);
+                device.cmd_pipeline_barrier(
+                    cmd,
+                    vk::PipelineStageFlags::TRANSFER,
+                    vk::PipelineStageFlags::HOST,
+                    vk::DependencyFlags::empty(),
+                    &[host_barrier],
+                    &[],
+                    &[],
+                );
+            }
+            if present {
+                let (src_stage, barrier) = if readback.is_some() {
+                    (
+                        vk::PipelineStageFlags::TRANSFER,
+                        barrier_to_present
+                            .old_layout(vk::ImageLayout::TRANSFER_SRC_OPTIMAL)
+                            .src_access_mask(vk::AccessFlags::TRANSFER_READ),
+                    )
+                } else {
+                    (vk::PipelineStageFlags::COMPUTE_SHADER, barrier_to_present)
+                };
+                device.cmd_pipeline_barrier(
+                    cmd,
+                    src_stage,
+                    vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+                    vk::DependencyFlags::empty(),
+                    &[],
+                    &[],
+                    &[barrier],
+                );
+            }
             device.end_command_buffer(cmd)?;
         }
         Ok(())
     }
+
+    pub fn window(&self) -> Option<&Window> {
+        match &self.target {
+            RenderTarget::Surface { window, .. } => Some(window),
+            RenderTarget::Offscreen(_) => None,
+        }
+    }
+}
+
+impl Drop for VoxelEngine {
+    fn drop(&mut self) {
+        // Frames in flight may still use any of the resources
+        unsafe {
+            let _ = self.vkcontext.device.device_wait_idle();
+        }
+        let context = &self.vkcontext;
+        self.pipeline.destroy(context);
+        self.target.destroy(context);
+        unsafe {
+            // Frees the command buffers along with the pool
+            context.device.destroy_command_pool(self.command_pool, None);
+        }
+        self.command_buffers.clear();
+        self.camera_buffer.destroy(context);
+        self.environment_buffer.destroy(context);
+        self.material_buffer.destroy(context);
+        self.textures.destroy(context);
+        self.lights.destroy(context);
+        self.accumulation.destroy(context);
+        self.tree.destroy(context);
+        self.world.destroy(context);
+        self.vkcontext.destroy();
+    }
 }
@@ -0,0 +1,135 @@
+use std::f32::consts::{PI, TAU};
+
+use nalgebra::Vector3;
+
+/// Sun, moon, sky and fog. The raytracer reads it through
+/// `EnvironmentUniform`, rebuilt every frame.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Environment {
+    /// Hour in 0..24, the sun rises at 6 and sets at 18
+    pub time_of_day: f32,
+    /// In-game hours per real second, 0 stops the clock
+    pub day_speed: f32,
+    /// Angle of the sun's path from the zenith toward -z, in radians
+    pub sun_tilt: f32,
+    pub sun_color: [f32; 3],
+    pub moon_color: [f32; 3],
+    pub day_zenith: [f32; 3],
+    pub day_horizon: [f32; 3],
+    pub night_zenith: [f32; 3],
+    pub night_horizon: [f32; 3],
+    /// Ambient light at noon and at midnight
+    pub day_ambient: f32,
+    pub night_ambient: f32,
+    /// Fog colour at noon, it follows the horizon's brightness over the day
+    pub fog_color: [f32; 3],
+    /// Extinction per voxel
+    pub fog_density: f32,
+}
+
+impl Default for Environment {
+    fn default() -> Self {
+        Self {
+            time_of_day: 10.0,
+            day_speed: 0.0,
+            sun_tilt: 0.3,
+            sun_color: [0.9, 0.855, 0.72],
+            moon_color: [0.12, 0.14, 0.2],
+            day_zenith: [0.2, 0.4, 0.7],
+            day_horizon: [0.6, 0.7, 0.9],
+            night_zenith: [0.01, 0.015, 0.04],
+            night_horizon: [0.04, 0.05, 0.1],
+            day_ambient: 0.5,
+            night_ambient: 0.08,
+            fog_color: [0.6, 0.7, 0.9],
+            fog_density: 0.002,
+        }
+    }
+}
+
+/// Environment as laid out in raytrace.comp's uniform buffer.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct EnvironmentUniform {
+    /// Toward the sun, w its disc's cosine radius
+    sun_dir: [f32; 4],
+    moon_dir: [f32; 4],
+    /// Toward the light that shades and casts shadows, w its intensity
+    light_dir: [f32; 4],
+    light_color: [f32; 4],
+    /// rgb, a unused
+    ambient: [f32; 4],
+    sky_zenith: [f32; 4],
+    sky_horizon: [f32; 4],
+    /// rgb colour, a density
+    fog: [f32; 4],
+}
+
+impl Environment {
+    /// Moves the clock forward by `seconds` of real time.
+    pub fn advance(&mut self, seconds: f32) {
+        self.time_of_day = (self.time_of_day + seconds * self.day_speed).rem_euclid(24.0);
+    }
+
+    /// Unit vector toward the sun.
+    pub fn sun_direction(&self) -> Vector3<f32> {
+        let angle = (self.time_of_day - 6.0) / 24.0 * TAU;
+        let height = angle.sin();
+        Vector3::new(
+            angle.cos(),
+            height * self.sun_tilt.cos(),
+            -height * self.sun_tilt.sin(),
+        )
+        .normalize()
+    }
+
+    pub fn moon_direction(&self) -> Vector3<f32> {
+        -self.sun_direction()
+    }
+
+    /// 1 while the sun is up, 0 at night, blended over dawn and dusk.
+    pub fn daylight(&self) -> f32 {
+        smoothstep(-0.1, 0.15, self.sun_direction().y)
+    }
+
+    pub(crate) fn uniform(&self) -> EnvironmentUniform {
+        let sun = self.sun_direction();
+        let moon = self.moon_direction();
+        let day = self.daylight();
+        let mix = |night: [f32; 3], day_value: [f32; 3]| {
+            let c = Vector3::from(night).lerp(&Vector3::from(day_value), day);
+            [c.x, c.y, c.z, 0.0]
+        };
+
+        // Whichever body is up lights the scene, fading out as it sets
+        let (light, color, intensity) = if sun.y > 0.0 {
+            (sun, self.sun_color, smoothstep(0.0, 0.1, sun.y))
+        } else {
+            (moon, self.moon_color, smoothstep(0.0, 0.1, moon.y))
+        };
+        let ambient = self.night_ambient + (self.day_ambient - self.night_ambient) * day;
+        let horizon = mix(self.night_horizon, self.day_horizon);
+        let brightness = luminance(&horizon) / luminance(&self.day_horizon).max(1e-4);
+        let fog = Vector3::from(self.fog_color) * brightness;
+
+        EnvironmentUniform {
+            sun_dir: [sun.x, sun.y, sun.z, (0.6 * PI / 180.0).cos()],
+            moon_dir: [moon.x, moon.y, moon.z, (0.5 * PI / 180.0).cos()],
+            light_dir: [light.x, light.y, light.z, intensity],
+            light_color: [color[0], color[1], color[2], 0.0],
+            ambient: [ambient; 4],
+            sky_zenith: mix(self.night_zenith, self.day_zenith),
+            sky_horizon: horizon,
+            fog: [fog.x, fog.y, fog.z, self.fog_density],
+        }
+    }
+}
+
+fn smoothstep(edge0: f32, edge1: f32, x: f32) -> f32 {
+    let t = ((x - edge0) / (edge1 - edge0)).clamp(0.0, 1.0);
+    t * t * (3.0 - 2.0 * t)
+}
+
+fn luminance(c: &[f32]) -> f32 {
+    0.2126 * c[0] + 0.7152 * c[1] + 0.0722 * c[2]
+}
@@ -1 +1,35 @@
+use std::fmt;
 
+use ash::vk;
+
+/// Errors from engine operations that reach outside of Vulkan (files, encoders).
+#[derive(Debug)]
+pub enum VoxelError {
+    Vulkan(vk::Result),
+    Io(std::io::Error),
+    Format(String),
+}
+
+impl fmt::Display for VoxelError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VoxelError::Vulkan(result) => write!(f, "Vulkan error: {}", result),
+            VoxelError::Io(err) => write!(f, "I/O error: {}", err),
+            VoxelError::Format(message) => write!(f, "Format error: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for VoxelError {}
+
+impl From<vk::Result> for VoxelError {
+    fn from(result: vk::Result) -> Self {
+        VoxelError::Vulkan(result)
+    }
+}
+
+impl From<std::io::Error> for VoxelError {
+    fn from(err: std::io::Error) -> Self {
+        VoxelError::Io(err)
+    }
+}
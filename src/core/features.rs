@@ -0,0 +1,398 @@
+use std::collections::HashMap;
+
+use nalgebra::Vector3;
+
+use crate::core::{
+    biome::biome_at,
+    blocks,
+    generator::GeneratorSettings,
+    terrain::{density, hash_int, is_cave},
+    world::{CHUNK_SIZE, WORLD_CHUNKS},
+};
+
+/// Ore vein configuration, one entry per ore type.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct OreSettings {
+    pub block: u32,
+    /// Placement attempts per chunk
+    pub attempts: u32,
+    /// Veins only start below this height
+    pub max_height: i32,
+    pub radius: i32,
+}
+
+/// Decoration pass configuration. Trees and boulders are placed per chunk
+/// column on the surface, at `BiomeSettings::tree_attempts` and
+/// `boulder_attempts` spots; ore veins per chunk inside stone.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FeatureSettings {
+    pub trunk_block: u32,
+    pub leaves_block: u32,
+    pub boulder_block: u32,
+    pub ores: [OreSettings; 2],
+    /// Highest voxel looked at when searching a column's surface
+    pub surface_scan_top: i32,
+}
+
+impl Default for FeatureSettings {
+    fn default() -> Self {
+        Self {
+            trunk_block: blocks::WOOD,
+            leaves_block: blocks::LEAVES,
+            boulder_block: blocks::STONE,
+            ores: [
+                OreSettings {
+                    block: blocks::COAL_ORE,
+                    attempts: 6,
+                    max_height: 96,
+                    radius: 2,
+                },
+                OreSettings {
+                    block: blocks::IRON_ORE,
+                    attempts: 3,
+                    max_height: 48,
+                    radius: 1,
+                },
+            ],
+            surface_scan_top: 256,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FeatureKind {
+    /// Trunk standing on `origin`, round canopy at its top. Grows into air only.
+    Tree = 1,
+    /// Ball centred on `origin`. Grows into air only.
+    Boulder = 2,
+    /// Sparse ball centred on `origin`. Replaces stone only.
+    OreVein = 3,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Feature {
+    pub kind: FeatureKind,
+    pub origin: Vector3<i32>,
+    /// Trunk height of trees, unused otherwise
+    pub height: i32,
+    pub radius: i32,
+    pub block: u32,
+    /// Leaves of trees, unused otherwise
+    pub secondary_block: u32,
+    /// Picks the voxels trimmed off canopies and veins
+    pub seed: u32,
+}
+
+/// Feature as laid out in decorate.comp's storage buffer.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct FeatureData {
+    origin: [i32; 4],
+    shape: [i32; 4],
+    seed: [u32; 4],
+}
+
+impl Feature {
+    /// Inclusive voxel bounds of everything the feature may write.
+    pub fn bounds(&self) -> (Vector3<i32>, Vector3<i32>) {
+        let r = Vector3::repeat(self.radius);
+        match self.kind {
+            FeatureKind::Tree => (
+                self.origin - Vector3::new(self.radius, 0, self.radius),
+                self.origin + r + Vector3::new(0, self.height, 0),
+            ),
+            FeatureKind::Boulder | FeatureKind::OreVein => (self.origin - r, self.origin + r),
+        }
+    }
+
+    /// Block the feature puts at `pos` given the block already there, None to
+    /// leave it. Mirrors featureBlock in decorate.comp.
+    pub fn block_at(&self, pos: Vector3<i32>, current: u32, stone_block: u32) -> Option<u32> {
+        let d = pos - self.origin;
+        let r = self.radius;
+        match self.kind {
+            FeatureKind::Tree => {
+                if current != blocks::AIR {
+                    return None;
+                }
+                if d.x == 0 && d.z == 0 && d.y >= 1 && d.y <= self.height {
+                    return Some(self.block);
+                }
+                let c = d - Vector3::new(0, self.height, 0);
+                let dist_sq = c.x * c.x + c.y * c.y + c.z * c.z;
+                if dist_sq > r * r + r {
+                    return None;
+                }
+                // Ragged rim
+                let rim = dist_sq > (r - 1) * (r - 1);
+                (!rim || hash_int(pos, self.seed) & 3 != 0).then_some(self.secondary_block)
+            }
+            FeatureKind::Boulder => {
+                let dist_sq = d.x * d.x + d.y * d.y + d.z * d.z;
+                (current == blocks::AIR && dist_sq <= r * r).then_some(self.block)
+            }
+            FeatureKind::OreVein => {
+                let dist_sq = d.x * d.x + d.y * d.y + d.z * d.z;
+                (current == stone_block
+                    && dist_sq <= r * r
+                    && !hash_int(pos, self.seed).is_multiple_of(3))
+                .then_some(self.block)
+            }
+        }
+    }
+
+    pub(crate) fn data(&self) -> FeatureData {
+        FeatureData {
+            origin: [
+                self.origin.x,
+                self.origin.y,
+                self.origin.z,
+                self.kind as i32,
+            ],
+            shape: [
+                self.height,
+                self.radius,
+                self.block as i32,
+                self.secondary_block as i32,
+            ],
+            seed: [self.seed, 0, 0, 0],
+        }
+    }
+}
+
+/// Applies `features` in order to the voxel at `pos`.
+pub fn decorate_voxel(
+    features: &[Feature],
+    pos: Vector3<i32>,
+    block: u32,
+    stone_block: u32,
+) -> u32 {
+    features.iter().fold(block, |current, feature| {
+        feature
+            .block_at(pos, current, stone_block)
+            .unwrap_or(current)
+    })
+}
+
+/// Topmost solid voxel of a column, None if a cave opens it up.
+fn surface_at(settings: &GeneratorSettings, x: i32, z: i32) -> Option<i32> {
+    let column = biome_at(settings, x, z);
+    let bottom = settings.layers.bedrock_height;
+    for y in (bottom..settings.features.surface_scan_top).rev() {
+        let pos = Vector3::new(x, y, z);
+        if density(settings, &column, pos) > 0.0 {
+            return (!is_cave(settings, pos)).then_some(y);
+        }
+    }
+    None
+}
+
+/// Trees and boulders anchored in the chunk column `(cx, cz)`. Every anchor
+/// comes from a hash of the column and the seed, so any chunk can rebuild its
+/// neighbours' features.
+pub fn column_features(settings: &GeneratorSettings, cx: i32, cz: i32) -> Vec<Feature> {
+    let mut features = Vec::new();
+    let origin = Vector3::new(cx, 0, cz) * CHUNK_SIZE as i32;
+    let max_attempts = settings
+        .biomes
+        .iter()
+        .map(|biome| biome.tree_attempts.max(biome.boulder_attempts))
+        .max()
+        .unwrap_or(0);
+
+    for attempt in 0..max_attempts {
+        for kind in [FeatureKind::Tree, FeatureKind::Boulder] {
+            let seed = settings.seed.wrapping_add(6 + kind as u32);
+            let h = hash_int(Vector3::new(cx, attempt as i32, cz), seed);
+            let x = origin.x + (h & 31) as i32;
+            let z = origin.z + ((h >> 5) & 31) as i32;
+
+            let biome = &settings.biomes[biome_at(settings, x, z).biome as usize];
+            let attempts = match kind {
+                FeatureKind::Tree => biome.tree_attempts,
+                _ => biome.boulder_attempts,
+            };
+            if attempt >= attempts {
+                continue;
+            }
+            let Some(y) = surface_at(settings, x, z) else {
+                continue;
+            };
+            if y < settings.sea_level {
+                continue;
+            }
+
+            let features_settings = &settings.features;
+            features.push(match kind {
+                FeatureKind::Tree => Feature {
+                    kind,
+                    origin: Vector3::new(x, y, z),
+                    height: 4 + ((h >> 10) % 4) as i32,
+                    radius: 2 + ((h >> 12) % 2) as i32,
+                    block: features_settings.trunk_block,
+                    secondary_block: features_settings.leaves_block,
+                    seed: h,
+                },
+                _ => Feature {
+                    kind,
+                    origin: Vector3::new(x, y, z),
+                    height: 0,
+                    radius: 1 + ((h >> 10) % 3) as i32,
+                    block: features_settings.boulder_block,
+                    secondary_block: 0,
+                    seed: h,
+                },
+            });
+        }
+    }
+    features
+}
+
+/// Ore veins anchored in `chunk`.
+pub fn ore_features(settings: &GeneratorSettings, chunk: Vector3<i32>) -> Vec<Feature> {
+    let mut features = Vec::new();
+    let origin = chunk * CHUNK_SIZE as i32;
+    for (ore_index, ore) in settings.features.ores.iter().enumerate() {
+        for attempt in 0..ore.attempts {
+            let seed = settings
+                .seed
+                .wrapping_add(10 + ore_index as u32)
+                .wrapping_add(attempt.wrapping_mul(0x68e31da4));
+            let h = hash_int(chunk, seed);
+            let pos = origin + Vector3::new(h & 31, (h >> 5) & 31, (h >> 10) & 31).cast::<i32>();
+            if pos.y >= ore.max_height {
+                continue;
+            }
+            features.push(Feature {
+                kind: FeatureKind::OreVein,
+                origin: pos,
+                height: 0,
+                radius: ore.radius,
+                block: ore.block,
+                secondary_block: 0,
+                seed: h,
+            });
+        }
+    }
+    features
+}
+
+/// Builds the feature list of chunks, caching the surface search of each
+/// chunk column.
+#[derive(Default)]
+pub struct FeaturePlacer {
+    settings: Option<GeneratorSettings>,
+    columns: HashMap<(i32, i32), Vec<Feature>>,
+}
+
+impl FeaturePlacer {
+    /// Every feature, anchored in `chunk` or one of its neighbours, that
+    /// writes into `chunk`, in the order they are applied.
+    pub fn chunk_features(
+        &mut self,
+        settings: &GeneratorSettings,
+        chunk: Vector3<i32>,
+    ) -> Vec<Feature> {
+        if self.settings.as_ref() != Some(settings) {
+            self.settings = Some(*settings);
+            self.columns.clear();
+        }
+
+        let chunk_min = chunk * CHUNK_SIZE as i32;
+        let chunk_max = chunk_min.add_scalar(CHUNK_SIZE as i32 - 1);
+        let overlaps = |feature: &Feature| {
+            let (min, max) = feature.bounds();
+            (0..3).all(|axis| min[axis] <= chunk_max[axis] && max[axis] >= chunk_min[axis])
+        };
+
+        let mut features = Vec::new();
+        for dz in -1..=1 {
+            for dx in -1..=1 {
+                let key = (chunk.x + dx, chunk.z + dz);
+                let column = self
+                    .columns
+                    .entry(key)
+                    .or_insert_with(|| column_features(settings, key.0, key.1));
+                features.extend(column.iter().copied().filter(|feature| overlaps(feature)));
+            }
+        }
+        for dz in -1..=1 {
+            for dy in -1..=1 {
+                for dx in -1..=1 {
+                    let anchor = chunk + Vector3::new(dx, dy, dz);
+                    features.extend(ore_features(settings, anchor).into_iter().filter(overlaps));
+                }
+            }
+        }
+        features
+    }
+
+    /// Drops cached columns away from the window at `origin`.
+    pub fn retain_window(&mut self, origin: Vector3<i32>) {
+        let size = WORLD_CHUNKS as i32;
+        self.columns.retain(|&(cx, cz), _| {
+            (origin.x - 1..=origin.x + size).contains(&cx)
+                && (origin.z - 1..=origin.z + size).contains(&cz)
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn voxels_written(feature: &Feature, chunk: Vector3<i32>, stone_block: u32) -> usize {
+        let min = chunk * CHUNK_SIZE as i32;
+        let size = CHUNK_SIZE as i32;
+        let mut count = 0;
+        for z in 0..size {
+            for y in 0..size {
+                for x in 0..size {
+                    let pos = min + Vector3::new(x, y, z);
+                    count += feature.block_at(pos, stone_block, stone_block).is_some() as usize;
+                }
+            }
+        }
+        count
+    }
+
+    fn same(a: &Feature, b: &Feature) -> bool {
+        a.kind == b.kind && a.origin == b.origin && a.radius == b.radius && a.seed == b.seed
+    }
+
+    #[test]
+    fn vein_reaches_across_chunk_border() {
+        let settings = GeneratorSettings::default();
+        let stone = settings.layers.stone_block;
+        let edge = CHUNK_SIZE as i32;
+        let (anchor, vein) = (0..64)
+            .map(|x| Vector3::new(x, 0, 0))
+            .flat_map(|chunk| {
+                ore_features(&settings, chunk)
+                    .into_iter()
+                    .map(move |f| (chunk, f))
+            })
+            .find(|(chunk, feature)| feature.bounds().1.x >= (chunk.x + 1) * edge)
+            .expect("no vein crosses a chunk border");
+        let neighbour = anchor + Vector3::x();
+
+        // Per-chunk seeds rebuild the same veins every time
+        let again = ore_features(&settings, anchor);
+        assert!(again.iter().any(|feature| same(feature, &vein)));
+
+        let mut placer = FeaturePlacer::default();
+        for chunk in [anchor, neighbour] {
+            let features = placer.chunk_features(&settings, chunk);
+            let placed = features
+                .iter()
+                .find(|feature| same(feature, &vein))
+                .expect("vein missing from a chunk it overlaps");
+            assert!(voxels_written(placed, chunk, stone) > 0);
+        }
+
+        let fresh = FeaturePlacer::default().chunk_features(&settings, neighbour);
+        let cached = placer.chunk_features(&settings, neighbour);
+        assert_eq!(fresh.len(), cached.len());
+        assert!(fresh.iter().zip(&cached).all(|(a, b)| same(a, b)));
+    }
+}
@@ -1,13 +1,295 @@
+use crate::core::biome::{BIOME_COUNT, BiomeSettings, MAX_FILL_DEPTH, default_biomes};
+use crate::core::blocks;
+use crate::core::error::VoxelError;
+use crate::core::features::{FeatureData, FeatureSettings};
+use crate::core::palette::{MAX_BLOCK_ID, PALETTE_CAPACITY, Palette};
+use crate::core::world::{CHUNK_SIZE, CHUNK_VOLUME, MAX_CHUNKS, MIP_LEVELS};
 use crate::vulkan::buffer::Buffer;
 use crate::vulkan::context::VulkanContext;
 use ash::vk;
+use gpu_allocator::MemoryLocation;
 
+/// Push constants of generate.comp.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct GenerateConstants {
+    pub start_chunk: [i32; 4],
+    pub window_origin: [i32; 4],
+}
+
+/// Push constants of decorate.comp.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DecorateConstants {
+    pub chunk: [i32; 4],
+    pub window_origin: [i32; 4],
+    /// First feature, feature count, stone block
+    pub features: [u32; 4],
+}
+
+/// Push constants of bricks.comp.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct BrickConstants {
+    /// Pool slot whose brick masks are rebuilt
+    pub slot: [u32; 4],
+}
+
+/// Push constants of mips.comp.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MipConstants {
+    /// Pool slot and the mip level built from the one below
+    pub slot: [u32; 4],
+}
+
+/// Push constants of pack.comp.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PackConstants {
+    /// Pool slot to pack and the scratch chunk it was generated into
+    pub slot: [u32; 4],
+}
+
+/// Features decorate.comp can take per run.
+pub const MAX_FEATURES: usize = 1 << 17;
+
+/// Chunks generated per run. They are written unpacked to a scratch buffer,
+/// then packed into the pool once their palettes are known.
+pub const SCRATCH_CHUNKS: usize = 256;
+/// Words per chunk in the palette readback: the distinct block count, then
+/// up to PALETTE_CAPACITY of the blocks.
+const PALETTE_STRIDE: usize = PALETTE_CAPACITY + 1;
+
+/// Block ids that don't depend on the biome.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LayerRules {
+    /// Block for solid voxels below the biome's surface and fill layers
+    pub stone_block: u32,
+    /// Voxels below this height are bedrock, whatever the density
+    pub bedrock_block: u32,
+    pub bedrock_height: i32,
+    /// Fills air below `GeneratorSettings::sea_level`, 0 leaves it empty
+    pub fluid_block: u32,
+}
+
+impl Default for LayerRules {
+    fn default() -> Self {
+        Self {
+            stone_block: blocks::STONE,
+            bedrock_block: blocks::BEDROCK,
+            bedrock_height: 3,
+            fluid_block: blocks::WATER,
+        }
+    }
+}
+
+/// Underground carving, applied after the surface layers so cave walls stay
+/// stone. Bedrock and fluids are never carved.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CaveSettings {
+    /// Frequency of the noise that hollows out large "cheese" caverns
+    pub cheese_scale: f32,
+    /// Noise value above which caverns open, 1.0 or more disables them
+    pub cheese_threshold: f32,
+    /// Frequency of the two noise fields whose shared zero band forms tunnels
+    pub worm_scale: f32,
+    /// Half width of that band, 0.0 disables tunnels
+    pub worm_radius: f32,
+    /// Nothing above this height is carved
+    pub max_height: i32,
+}
+
+impl Default for CaveSettings {
+    fn default() -> Self {
+        Self {
+            cheese_scale: 0.04,
+            cheese_threshold: 0.78,
+            worm_scale: 0.02,
+            worm_radius: 0.025,
+            max_height: 120,
+        }
+    }
+}
+
+/// Terrain parameters. Density is `fbm(pos * scale) - (y - base_height) /
+/// height_falloff`, voxels with positive density are solid. Biomes shift
+/// base_height and scale height_falloff per column.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct GeneratorSettings {
+    pub seed: u32,
+    /// Noise frequency per voxel
+    pub scale: f32,
+    pub octaves: u32,
+    /// Frequency multiplier between octaves
+    pub lacunarity: f32,
+    /// Amplitude multiplier between octaves
+    pub gain: f32,
+    /// Height where the height bias is zero
+    pub base_height: f32,
+    /// Voxels over which the height bias grows by 1
+    pub height_falloff: f32,
+    pub sea_level: i32,
+    pub layers: LayerRules,
+    pub caves: CaveSettings,
+    pub features: FeatureSettings,
+    /// Temperature/humidity noise frequency per voxel
+    pub climate_scale: f32,
+    /// How quickly a column turns fully into its nearest biome away from a
+    /// border, in inverse squared climate distance
+    pub blend_sharpness: f32,
+    /// Indexed by `Biome as usize`
+    pub biomes: [BiomeSettings; BIOME_COUNT],
+}
+
+impl Default for GeneratorSettings {
+    fn default() -> Self {
+        Self {
+            seed: 0,
+            scale: 0.03,
+            octaves: 4,
+            lacunarity: 2.0,
+            gain: 0.5,
+            base_height: 84.0,
+            height_falloff: 40.0,
+            sea_level: 60,
+            layers: LayerRules::default(),
+            caves: CaveSettings::default(),
+            features: FeatureSettings::default(),
+            climate_scale: 0.002,
+            blend_sharpness: 30.0,
+            biomes: default_biomes(),
+        }
+    }
+}
+
+/// BiomeSettings as laid out in generate.comp's std140 uniform block.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct BiomeUniform {
+    temperature: f32,
+    humidity: f32,
+    base_height: f32,
+    /// Reciprocal so GPU and CPU multiply instead of dividing
+    inv_height_falloff: f32,
+    surface_block: u32,
+    fill_block: u32,
+    fill_depth: u32,
+    _pad: u32,
+}
+
+/// GeneratorSettings as laid out in generate.comp's std140 uniform block.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct GeneratorUniform {
+    seed: u32,
+    octaves: u32,
+    scale: f32,
+    lacunarity: f32,
+    gain: f32,
+    climate_scale: f32,
+    blend_sharpness: f32,
+    sea_level: i32,
+    stone_block: u32,
+    bedrock_block: u32,
+    bedrock_height: i32,
+    fluid_block: u32,
+    cheese_scale: f32,
+    cheese_threshold: f32,
+    worm_scale: f32,
+    worm_radius: f32,
+    cave_max_height: i32,
+    /// std140 aligns the biome array to 16 bytes
+    _pad: [u32; 3],
+    biomes: [BiomeUniform; BIOME_COUNT],
+}
+
+impl GeneratorSettings {
+    /// Checks every block id against MAX_BLOCK_ID, the shaders would clamp
+    /// larger ones to other blocks.
+    pub fn validate(&self) -> Result<(), VoxelError> {
+        let layers = &self.layers;
+        let features = &self.features;
+        let mut blocks = vec![
+            ("stone", layers.stone_block),
+            ("bedrock", layers.bedrock_block),
+            ("fluid", layers.fluid_block),
+            ("trunk", features.trunk_block),
+            ("leaves", features.leaves_block),
+            ("boulder", features.boulder_block),
+        ];
+        blocks.extend(features.ores.iter().map(|ore| ("ore", ore.block)));
+        blocks.extend(
+            self.biomes
+                .iter()
+                .flat_map(|biome| [("surface", biome.surface_block), ("fill", biome.fill_block)]),
+        );
+        match blocks.into_iter().find(|&(_, block)| block > MAX_BLOCK_ID) {
+            Some((name, block)) => Err(VoxelError::Format(format!(
+                "{} block id {} is over {}",
+                name, block, MAX_BLOCK_ID
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    pub(crate) fn uniform(&self) -> GeneratorUniform {
+        let biomes = self.biomes.map(|biome| {
+            let (base_height, inv_height_falloff) = biome.profile(self);
+            BiomeUniform {
+                temperature: biome.temperature,
+                humidity: biome.humidity,
+                base_height,
+                inv_height_falloff,
+                surface_block: biome.surface_block,
+                fill_block: biome.fill_block,
+                fill_depth: biome.fill_depth.min(MAX_FILL_DEPTH - 1),
+                _pad: 0,
+            }
+        });
+        GeneratorUniform {
+            seed: self.seed,
+            octaves: self.octaves,
+            scale: self.scale,
+            lacunarity: self.lacunarity,
+            gain: self.gain,
+            climate_scale: self.climate_scale,
+            blend_sharpness: self.blend_sharpness,
+            sea_level: self.sea_level,
+            stone_block: self.layers.stone_block,
+            bedrock_block: self.layers.bedrock_block,
+            bedrock_height: self.layers.bedrock_height,
+            fluid_block: self.layers.fluid_block,
+            cheese_scale: self.caves.cheese_scale,
+            cheese_threshold: self.caves.cheese_threshold,
+            worm_scale: self.caves.worm_scale,
+            worm_radius: self.caves.worm_radius,
+            cave_max_height: self.caves.max_height,
+            _pad: [0; 3],
+            biomes,
+        }
+    }
+}
+
+#[allow(unused)]
 pub struct VoxelGenerator {
     pipeline: vk::Pipeline,
+    decorate_pipeline: vk::Pipeline,
+    bricks_pipeline: vk::Pipeline,
+    mips_pipeline: vk::Pipeline,
+    palette_pipeline: vk::Pipeline,
+    pack_pipeline: vk::Pipeline,
     pipeline_layout: vk::PipelineLayout,
     descriptor_set_layout: vk::DescriptorSetLayout,
     descriptor_pool: vk::DescriptorPool,
     descriptor_set: vk::DescriptorSet,
+    settings_buffer: Buffer,
+    feature_buffer: Buffer,
+    /// Unpacked voxels of the chunks being generated
+    scratch_buffer: Buffer,
+    /// Scratch chunk + 1 per pool slot, 0 for slots outside the run
+    scratch_map_buffer: Buffer,
+    palette_readback: Buffer,
 }
 
 impl VoxelGenerator {
@@ -15,6 +297,10 @@ impl VoxelGenerator {
         context: &VulkanContext,
         dir_buffer: &Buffer,
         pool_buffer: &Buffer,
+        header_buffer: &Buffer,
+        palette_buffer: &Buffer,
+        brick_buffer: &Buffer,
+        mip_buffer: &Buffer,
     ) -> Result<Self, vk::Result> {
         let device = &context.device;
 
@@ -30,16 +316,67 @@ impl VoxelGenerator {
                     .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
                     .descriptor_count(1)
                     .stage_flags(vk::ShaderStageFlags::COMPUTE),
+                vk::DescriptorSetLayoutBinding::default()
+                    .binding(2)
+                    .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                    .descriptor_count(1)
+                    .stage_flags(vk::ShaderStageFlags::COMPUTE),
+                vk::DescriptorSetLayoutBinding::default()
+                    .binding(3)
+                    .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER)
+                    .descriptor_count(1)
+                    .stage_flags(vk::ShaderStageFlags::COMPUTE),
+                vk::DescriptorSetLayoutBinding::default()
+                    .binding(4)
+                    .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                    .descriptor_count(1)
+                    .stage_flags(vk::ShaderStageFlags::COMPUTE),
+                vk::DescriptorSetLayoutBinding::default()
+                    .binding(5)
+                    .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                    .descriptor_count(1)
+                    .stage_flags(vk::ShaderStageFlags::COMPUTE),
+                vk::DescriptorSetLayoutBinding::default()
+                    .binding(6)
+                    .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                    .descriptor_count(1)
+                    .stage_flags(vk::ShaderStageFlags::COMPUTE),
+                vk::DescriptorSetLayoutBinding::default()
+                    .binding(7)
+                    .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                    .descriptor_count(1)
+                    .stage_flags(vk::ShaderStageFlags::COMPUTE),
+                vk::DescriptorSetLayoutBinding::default()
+                    .binding(8)
+                    .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                    .descriptor_count(1)
+                    .stage_flags(vk::ShaderStageFlags::COMPUTE),
+                vk::DescriptorSetLayoutBinding::default()
+                    .binding(9)
+                    .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                    .descriptor_count(1)
+                    .stage_flags(vk::ShaderStageFlags::COMPUTE),
+                vk::DescriptorSetLayoutBinding::default()
+                    .binding(10)
+                    .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                    .descriptor_count(1)
+                    .stage_flags(vk::ShaderStageFlags::COMPUTE),
             ];
 
             let layout_info = vk::DescriptorSetLayoutCreateInfo::default().bindings(&bindings);
             let ds_layout = device.create_descriptor_set_layout(&layout_info, None)?;
 
-            // 2. Pipeline Layout
+            // 2. Pipeline Layout, shared by all passes
             let push_constant = vk::PushConstantRange::default()
                 .stage_flags(vk::ShaderStageFlags::COMPUTE)
                 .offset(0)
-                .size(12); // ivec3 (4*3 bytes)
+                .size(
+                    std::mem::size_of::<GenerateConstants>()
+                        .max(std::mem::size_of::<DecorateConstants>())
+                        .max(std::mem::size_of::<BrickConstants>())
+                        .max(std::mem::size_of::<MipConstants>())
+                        .max(std::mem::size_of::<PackConstants>()) as u32,
+                );
 
             let pipeline_layout_info = vk::PipelineLayoutCreateInfo::default()
                 .set_layouts(std::slice::from_ref(&ds_layout))
@@ -47,37 +384,47 @@ impl VoxelGenerator {
 
             let pipeline_layout = device.create_pipeline_layout(&pipeline_layout_info, None)?;
 
-            // 3. Shader
-
-            let shader_module = unsafe {
-                let code = include_bytes!("../vulkan/shaders/generate.spv");
-                let code_u32 = ash::util::read_spv(&mut std::io::Cursor::new(&code))
-                    .expect("generate.spv counld't be read properly");
-
+            // 3. Shaders
+            let create_pipeline = |code: &[u8]| -> Result<vk::Pipeline, vk::Result> {
+                let code_u32 = ash::util::read_spv(&mut std::io::Cursor::new(code))
+                    .expect("generator shader counld't be read properly");
                 let create_info = vk::ShaderModuleCreateInfo::default().code(&code_u32);
+                let shader_module = device.create_shader_module(&create_info, None)?;
 
-                context.device.create_shader_module(&create_info, None)?
-            };
-            let stage_info = vk::PipelineShaderStageCreateInfo::default()
-                .stage(vk::ShaderStageFlags::COMPUTE)
-                .module(shader_module)
-                .name(std::ffi::CStr::from_bytes_with_nul(b"main\0").unwrap());
-
-            let pipeline_info = vk::ComputePipelineCreateInfo::default()
-                .stage(stage_info)
-                .layout(pipeline_layout);
+                let stage_info = vk::PipelineShaderStageCreateInfo::default()
+                    .stage(vk::ShaderStageFlags::COMPUTE)
+                    .module(shader_module)
+                    .name(c"main");
+                let pipeline_info = vk::ComputePipelineCreateInfo::default()
+                    .stage(stage_info)
+                    .layout(pipeline_layout);
+                let pipeline = device
+                    .create_compute_pipelines(vk::PipelineCache::null(), &[pipeline_info], None)
+                    .map_err(|e| e.1);
 
-            let pipeline = device
-                .create_compute_pipelines(vk::PipelineCache::null(), &[pipeline_info], None)
-                .map_err(|e| e.1)?[0];
-
-            device.destroy_shader_module(shader_module, None);
+                device.destroy_shader_module(shader_module, None);
+                Ok(pipeline?[0])
+            };
+            let pipeline = create_pipeline(include_bytes!("../vulkan/shaders/generate.spv"))?;
+            let decorate_pipeline =
+                create_pipeline(include_bytes!("../vulkan/shaders/decorate.spv"))?;
+            let bricks_pipeline = create_pipeline(include_bytes!("../vulkan/shaders/bricks.spv"))?;
+            let mips_pipeline = create_pipeline(include_bytes!("../vulkan/shaders/mips.spv"))?;
+            let palette_pipeline =
+                create_pipeline(include_bytes!("../vulkan/shaders/palette.spv"))?;
+            let pack_pipeline = create_pipeline(include_bytes!("../vulkan/shaders/pack.spv"))?;
 
             // 4. Allocate Descriptor Set
-            let pool_size = [vk::DescriptorPoolSize {
-                ty: vk::DescriptorType::STORAGE_BUFFER,
-                descriptor_count: 2,
-            }];
+            let pool_size = [
+                vk::DescriptorPoolSize {
+                    ty: vk::DescriptorType::STORAGE_BUFFER,
+                    descriptor_count: 10,
+                },
+                vk::DescriptorPoolSize {
+                    ty: vk::DescriptorType::UNIFORM_BUFFER,
+                    descriptor_count: 1,
+                },
+            ];
             let pool_info = vk::DescriptorPoolCreateInfo::default()
                 .pool_sizes(&pool_size)
                 .max_sets(1);
@@ -88,6 +435,42 @@ impl VoxelGenerator {
                 .set_layouts(std::slice::from_ref(&ds_layout));
             let descriptor_set = device.allocate_descriptor_sets(&alloc_info)?[0];
 
+            let settings_buffer = Buffer::new(
+                context,
+                std::mem::size_of::<GeneratorUniform>() as u64,
+                vk::BufferUsageFlags::UNIFORM_BUFFER,
+                MemoryLocation::CpuToGpu,
+                "Generator Settings",
+            )?;
+            let feature_buffer = Buffer::new(
+                context,
+                (MAX_FEATURES * std::mem::size_of::<FeatureData>()) as u64,
+                vk::BufferUsageFlags::STORAGE_BUFFER,
+                MemoryLocation::CpuToGpu,
+                "Generator Features",
+            )?;
+            let scratch_buffer = Buffer::new(
+                context,
+                (SCRATCH_CHUNKS * CHUNK_VOLUME * 4) as u64,
+                vk::BufferUsageFlags::STORAGE_BUFFER,
+                MemoryLocation::GpuOnly,
+                "Generator Scratch",
+            )?;
+            let scratch_map_buffer = Buffer::new(
+                context,
+                (MAX_CHUNKS * 4) as u64,
+                vk::BufferUsageFlags::STORAGE_BUFFER,
+                MemoryLocation::CpuToGpu,
+                "Generator Scratch Map",
+            )?;
+            let palette_readback = Buffer::new(
+                context,
+                (SCRATCH_CHUNKS * PALETTE_STRIDE * 4) as u64,
+                vk::BufferUsageFlags::STORAGE_BUFFER,
+                MemoryLocation::GpuToCpu,
+                "Generator Palettes",
+            )?;
+
             // 5. Update Descriptors
             let dir_info = vk::DescriptorBufferInfo::default()
                 .buffer(dir_buffer.buffer)
@@ -95,6 +478,33 @@ impl VoxelGenerator {
             let pool_info = vk::DescriptorBufferInfo::default()
                 .buffer(pool_buffer.buffer)
                 .range(vk::WHOLE_SIZE);
+            let scratch_info = vk::DescriptorBufferInfo::default()
+                .buffer(scratch_buffer.buffer)
+                .range(vk::WHOLE_SIZE);
+            let settings_info = vk::DescriptorBufferInfo::default()
+                .buffer(settings_buffer.buffer)
+                .range(vk::WHOLE_SIZE);
+            let feature_info = vk::DescriptorBufferInfo::default()
+                .buffer(feature_buffer.buffer)
+                .range(vk::WHOLE_SIZE);
+            let brick_info = vk::DescriptorBufferInfo::default()
+                .buffer(brick_buffer.buffer)
+                .range(vk::WHOLE_SIZE);
+            let mip_info = vk::DescriptorBufferInfo::default()
+                .buffer(mip_buffer.buffer)
+                .range(vk::WHOLE_SIZE);
+            let header_info = vk::DescriptorBufferInfo::default()
+                .buffer(header_buffer.buffer)
+                .range(vk::WHOLE_SIZE);
+            let palette_info = vk::DescriptorBufferInfo::default()
+                .buffer(palette_buffer.buffer)
+                .range(vk::WHOLE_SIZE);
+            let scratch_map_info = vk::DescriptorBufferInfo::default()
+                .buffer(scratch_map_buffer.buffer)
+                .range(vk::WHOLE_SIZE);
+            let readback_info = vk::DescriptorBufferInfo::default()
+                .buffer(palette_readback.buffer)
+                .range(vk::WHOLE_SIZE);
 
             let writes = [
                 vk::WriteDescriptorSet::default()
@@ -107,31 +517,246 @@ impl VoxelGenerator {
                     .dst_binding(1)
                     .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
                     .buffer_info(std::slice::from_ref(&pool_info)),
+                vk::WriteDescriptorSet::default()
+                    .dst_set(descriptor_set)
+                    .dst_binding(2)
+                    .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                    .buffer_info(std::slice::from_ref(&scratch_info)),
+                vk::WriteDescriptorSet::default()
+                    .dst_set(descriptor_set)
+                    .dst_binding(3)
+                    .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER)
+                    .buffer_info(std::slice::from_ref(&settings_info)),
+                vk::WriteDescriptorSet::default()
+                    .dst_set(descriptor_set)
+                    .dst_binding(4)
+                    .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                    .buffer_info(std::slice::from_ref(&feature_info)),
+                vk::WriteDescriptorSet::default()
+                    .dst_set(descriptor_set)
+                    .dst_binding(5)
+                    .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                    .buffer_info(std::slice::from_ref(&brick_info)),
+                vk::WriteDescriptorSet::default()
+                    .dst_set(descriptor_set)
+                    .dst_binding(6)
+                    .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                    .buffer_info(std::slice::from_ref(&mip_info)),
+                vk::WriteDescriptorSet::default()
+                    .dst_set(descriptor_set)
+                    .dst_binding(7)
+                    .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                    .buffer_info(std::slice::from_ref(&header_info)),
+                vk::WriteDescriptorSet::default()
+                    .dst_set(descriptor_set)
+                    .dst_binding(8)
+                    .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                    .buffer_info(std::slice::from_ref(&palette_info)),
+                vk::WriteDescriptorSet::default()
+                    .dst_set(descriptor_set)
+                    .dst_binding(9)
+                    .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                    .buffer_info(std::slice::from_ref(&scratch_map_info)),
+                vk::WriteDescriptorSet::default()
+                    .dst_set(descriptor_set)
+                    .dst_binding(10)
+                    .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                    .buffer_info(std::slice::from_ref(&readback_info)),
             ];
             device.update_descriptor_sets(&writes, &[]);
 
             Ok(Self {
                 pipeline,
+                decorate_pipeline,
+                bricks_pipeline,
+                mips_pipeline,
+                palette_pipeline,
+                pack_pipeline,
                 pipeline_layout,
                 descriptor_set_layout: ds_layout,
                 descriptor_pool,
                 descriptor_set,
+                settings_buffer,
+                feature_buffer,
+                scratch_buffer,
+                scratch_map_buffer,
+                palette_readback,
             })
         }
     }
 
-    pub fn run(
+    /// Generates every `(start_chunk, num_chunks)` box, one dispatch each, for
+    /// the chunks of `slots` that lie in the window at `window_origin`, then
+    /// runs the decoration pass. `decorations` are `(chunk, first, count)`
+    /// slices of `features` to stamp into each chunk. The voxels are left
+    /// unpacked in the scratch buffer, chunk `i` for `slots[i]`, and the
+    /// palette of each is returned. At most SCRATCH_CHUNKS slots per run.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn run(
+        &mut self,
+        context: &VulkanContext,
+        settings: &GeneratorSettings,
+        window_origin: [i32; 3],
+        regions: &[([i32; 3], [u32; 3])],
+        features: &[FeatureData],
+        decorations: &[([i32; 3], u32, u32)],
+        slots: &[u32],
+    ) -> Result<Vec<Palette>, vk::Result> {
+        let slots = &slots[..slots.len().min(SCRATCH_CHUNKS)];
+        let mut scratch_map = vec![0u32; MAX_CHUNKS];
+        for (scratch, &slot) in slots.iter().enumerate() {
+            scratch_map[slot as usize] = scratch as u32 + 1;
+        }
+        // immediate_submit waits for the previous run, the buffers are free to rewrite
+        self.settings_buffer.update_item(settings.uniform())?;
+        self.feature_buffer.update_slice(features)?;
+        self.scratch_map_buffer.update_slice(&scratch_map)?;
+        context.immediate_submit(|cmd| {
+            unsafe {
+                context.device.cmd_bind_pipeline(
+                    cmd,
+                    vk::PipelineBindPoint::COMPUTE,
+                    self.pipeline,
+                );
+                context.device.cmd_bind_descriptor_sets(
+                    cmd,
+                    vk::PipelineBindPoint::COMPUTE,
+                    self.pipeline_layout,
+                    0,
+                    &[self.descriptor_set],
+                    &[],
+                );
+
+                for (start_chunk, num_chunks) in regions {
+                    // Push Constants
+                    let constants = GenerateConstants {
+                        start_chunk: [start_chunk[0], start_chunk[1], start_chunk[2], 0],
+                        window_origin: [window_origin[0], window_origin[1], window_origin[2], 0],
+                    };
+                    let pc_bytes = std::slice::from_raw_parts(
+                        &constants as *const GenerateConstants as *const u8,
+                        std::mem::size_of::<GenerateConstants>(),
+                    );
+                    context.device.cmd_push_constants(
+                        cmd,
+                        self.pipeline_layout,
+                        vk::ShaderStageFlags::COMPUTE,
+                        0,
+                        pc_bytes,
+                    );
+
+                    context
+                        .device
+                        .cmd_dispatch(cmd, num_chunks[0], num_chunks[1], num_chunks[2]);
+                }
+
+                if !decorations.is_empty() {
+                    // Features are stamped on top of the finished terrain
+                    let barrier = vk::MemoryBarrier::default()
+                        .src_access_mask(vk::AccessFlags::SHADER_WRITE)
+                        .dst_access_mask(
+                            vk::AccessFlags::SHADER_READ | vk::AccessFlags::SHADER_WRITE,
+                        );
+                    context.device.cmd_pipeline_barrier(
+                        cmd,
+                        vk::PipelineStageFlags::COMPUTE_SHADER,
+                        vk::PipelineStageFlags::COMPUTE_SHADER,
+                        vk::DependencyFlags::empty(),
+                        &[barrier],
+                        &[],
+                        &[],
+                    );
+                    context.device.cmd_bind_pipeline(
+                        cmd,
+                        vk::PipelineBindPoint::COMPUTE,
+                        self.decorate_pipeline,
+                    );
+                }
+                for &(chunk, first, count) in decorations {
+                    let constants = DecorateConstants {
+                        chunk: [chunk[0], chunk[1], chunk[2], 0],
+                        window_origin: [window_origin[0], window_origin[1], window_origin[2], 0],
+                        features: [first, count, settings.layers.stone_block, 0],
+                    };
+                    let pc_bytes = std::slice::from_raw_parts(
+                        &constants as *const DecorateConstants as *const u8,
+                        std::mem::size_of::<DecorateConstants>(),
+                    );
+                    context.device.cmd_push_constants(
+                        cmd,
+                        self.pipeline_layout,
+                        vk::ShaderStageFlags::COMPUTE,
+                        0,
+                        pc_bytes,
+                    );
+                    context.device.cmd_dispatch(cmd, 1, 1, 1);
+                }
+
+                let barrier = vk::MemoryBarrier::default()
+                    .src_access_mask(vk::AccessFlags::SHADER_WRITE)
+                    .dst_access_mask(vk::AccessFlags::SHADER_READ);
+                context.device.cmd_pipeline_barrier(
+                    cmd,
+                    vk::PipelineStageFlags::COMPUTE_SHADER,
+                    vk::PipelineStageFlags::COMPUTE_SHADER,
+                    vk::DependencyFlags::empty(),
+                    &[barrier],
+                    &[],
+                    &[],
+                );
+                context.device.cmd_bind_pipeline(
+                    cmd,
+                    vk::PipelineBindPoint::COMPUTE,
+                    self.palette_pipeline,
+                );
+                // One workgroup per scratch chunk
+                context.device.cmd_dispatch(cmd, slots.len() as u32, 1, 1);
+
+                let barrier = vk::MemoryBarrier::default()
+                    .src_access_mask(vk::AccessFlags::SHADER_WRITE)
+                    .dst_access_mask(vk::AccessFlags::HOST_READ);
+                context.device.cmd_pipeline_barrier(
+                    cmd,
+                    vk::PipelineStageFlags::COMPUTE_SHADER,
+                    vk::PipelineStageFlags::HOST,
+                    vk::DependencyFlags::empty(),
+                    &[barrier],
+                    &[],
+                    &[],
+                );
+            }
+        })?;
+
+        let readback = self
+            .palette_readback
+            .read_slice::<u32>(slots.len() * PALETTE_STRIDE)?;
+        Ok(readback
+            .chunks_exact(PALETTE_STRIDE)
+            .map(|chunk| {
+                let count = chunk[0] as usize;
+                Palette::new(chunk[1..1 + count.min(PALETTE_CAPACITY)].to_vec(), count)
+            })
+            .collect())
+    }
+
+    /// Packs scratch chunks of the last run into the pool, as `(slot, scratch
+    /// chunk)` pairs, once the slots have their palette and pages. Their brick
+    /// masks and mips are rebuilt in the same submit.
+    pub(crate) fn pack(
         &self,
         context: &VulkanContext,
-        start_chunk: [i32; 3],
-        num_chunks: [u32; 3],
+        chunks: &[(u32, u32)],
     ) -> Result<(), vk::Result> {
+        if chunks.is_empty() {
+            return Ok(());
+        }
+        let slots: Vec<u32> = chunks.iter().map(|&(slot, _)| slot).collect();
         context.immediate_submit(|cmd| {
             unsafe {
                 context.device.cmd_bind_pipeline(
                     cmd,
                     vk::PipelineBindPoint::COMPUTE,
-                    self.pipeline,
+                    self.pack_pipeline,
                 );
                 context.device.cmd_bind_descriptor_sets(
                     cmd,
@@ -141,9 +766,74 @@ impl VoxelGenerator {
                     &[self.descriptor_set],
                     &[],
                 );
+                for &(slot, scratch) in chunks {
+                    let constants = PackConstants {
+                        slot: [slot, scratch, 0, 0],
+                    };
+                    let pc_bytes = std::slice::from_raw_parts(
+                        &constants as *const PackConstants as *const u8,
+                        std::mem::size_of::<PackConstants>(),
+                    );
+                    context.device.cmd_push_constants(
+                        cmd,
+                        self.pipeline_layout,
+                        vk::ShaderStageFlags::COMPUTE,
+                        0,
+                        pc_bytes,
+                    );
+                    context.device.cmd_dispatch(cmd, 1, 1, 1);
+                }
+            }
+            self.record_bricks(context, cmd, &slots);
+            self.record_mips(context, cmd, &slots);
+        })
+    }
 
-                // Push Constants
-                let pc_bytes = std::slice::from_raw_parts(start_chunk.as_ptr() as *const u8, 12);
+    /// Records the rebuild of the brick occupancy masks of `slots`, after
+    /// whatever transfer or compute work last wrote their voxels.
+    pub(crate) fn record_bricks(
+        &self,
+        context: &VulkanContext,
+        cmd: vk::CommandBuffer,
+        slots: &[u32],
+    ) {
+        if slots.is_empty() {
+            return;
+        }
+        unsafe {
+            let barrier = vk::MemoryBarrier::default()
+                .src_access_mask(vk::AccessFlags::TRANSFER_WRITE | vk::AccessFlags::SHADER_WRITE)
+                .dst_access_mask(vk::AccessFlags::SHADER_READ);
+            context.device.cmd_pipeline_barrier(
+                cmd,
+                vk::PipelineStageFlags::TRANSFER | vk::PipelineStageFlags::COMPUTE_SHADER,
+                vk::PipelineStageFlags::COMPUTE_SHADER,
+                vk::DependencyFlags::empty(),
+                &[barrier],
+                &[],
+                &[],
+            );
+            context.device.cmd_bind_pipeline(
+                cmd,
+                vk::PipelineBindPoint::COMPUTE,
+                self.bricks_pipeline,
+            );
+            context.device.cmd_bind_descriptor_sets(
+                cmd,
+                vk::PipelineBindPoint::COMPUTE,
+                self.pipeline_layout,
+                0,
+                &[self.descriptor_set],
+                &[],
+            );
+            for &slot in slots {
+                let constants = BrickConstants {
+                    slot: [slot, 0, 0, 0],
+                };
+                let pc_bytes = std::slice::from_raw_parts(
+                    &constants as *const BrickConstants as *const u8,
+                    std::mem::size_of::<BrickConstants>(),
+                );
                 context.device.cmd_push_constants(
                     cmd,
                     self.pipeline_layout,
@@ -151,14 +841,122 @@ impl VoxelGenerator {
                     0,
                     pc_bytes,
                 );
+                // One workgroup covers the CHUNK_BRICKS^3 bricks of a slot
+                context.device.cmd_dispatch(cmd, 1, 1, 1);
+            }
+        }
+    }
 
-                context.device.cmd_dispatch(
+    /// Records the rebuild of the mip levels of `slots`, after whatever
+    /// transfer or compute work last wrote their voxels. Each level is built
+    /// from the one below.
+    pub(crate) fn record_mips(
+        &self,
+        context: &VulkanContext,
+        cmd: vk::CommandBuffer,
+        slots: &[u32],
+    ) {
+        if slots.is_empty() {
+            return;
+        }
+        unsafe {
+            context.device.cmd_bind_pipeline(
+                cmd,
+                vk::PipelineBindPoint::COMPUTE,
+                self.mips_pipeline,
+            );
+            context.device.cmd_bind_descriptor_sets(
+                cmd,
+                vk::PipelineBindPoint::COMPUTE,
+                self.pipeline_layout,
+                0,
+                &[self.descriptor_set],
+                &[],
+            );
+            for level in 1..=MIP_LEVELS as u32 {
+                let barrier = vk::MemoryBarrier::default()
+                    .src_access_mask(
+                        vk::AccessFlags::TRANSFER_WRITE | vk::AccessFlags::SHADER_WRITE,
+                    )
+                    .dst_access_mask(vk::AccessFlags::SHADER_READ);
+                context.device.cmd_pipeline_barrier(
                     cmd,
-                    num_chunks[0],
-                    num_chunks[1],
-                    num_chunks[2],
+                    vk::PipelineStageFlags::TRANSFER | vk::PipelineStageFlags::COMPUTE_SHADER,
+                    vk::PipelineStageFlags::COMPUTE_SHADER,
+                    vk::DependencyFlags::empty(),
+                    &[barrier],
+                    &[],
+                    &[],
                 );
+                // 4^3 invocations per workgroup, each building 2 cells along x
+                let edge = (CHUNK_SIZE >> level) as u32;
+                let groups = edge.div_ceil(4);
+                for &slot in slots {
+                    let constants = MipConstants {
+                        slot: [slot, level, 0, 0],
+                    };
+                    let pc_bytes = std::slice::from_raw_parts(
+                        &constants as *const MipConstants as *const u8,
+                        std::mem::size_of::<MipConstants>(),
+                    );
+                    context.device.cmd_push_constants(
+                        cmd,
+                        self.pipeline_layout,
+                        vk::ShaderStageFlags::COMPUTE,
+                        0,
+                        pc_bytes,
+                    );
+                    context
+                        .device
+                        .cmd_dispatch(cmd, (edge / 2).div_ceil(4), groups, groups);
+                }
             }
-        })
+        }
+    }
+
+    pub fn destroy(&mut self, context: &VulkanContext) {
+        let device = &context.device;
+        unsafe {
+            for pipeline in [
+                self.pipeline,
+                self.decorate_pipeline,
+                self.bricks_pipeline,
+                self.mips_pipeline,
+                self.palette_pipeline,
+                self.pack_pipeline,
+            ] {
+                device.destroy_pipeline(pipeline, None);
+            }
+            device.destroy_pipeline_layout(self.pipeline_layout, None);
+            device.destroy_descriptor_pool(self.descriptor_pool, None);
+            device.destroy_descriptor_set_layout(self.descriptor_set_layout, None);
+        }
+        self.settings_buffer.destroy(context);
+        self.feature_buffer.destroy(context);
+        self.scratch_buffer.destroy(context);
+        self.scratch_map_buffer.destroy(context);
+        self.palette_readback.destroy(context);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validates_block_ids() {
+        assert!(GeneratorSettings::default().validate().is_ok());
+
+        let mut settings = GeneratorSettings::default();
+        settings.layers.fluid_block = MAX_BLOCK_ID;
+        settings.biomes[0].surface_block = MAX_BLOCK_ID;
+        assert!(settings.validate().is_ok());
+
+        settings.biomes[3].fill_block = MAX_BLOCK_ID + 1;
+        assert!(matches!(settings.validate(), Err(VoxelError::Format(_))));
+
+        let mut settings = GeneratorSettings::default();
+        settings.features.ores[1].block = u32::MAX;
+        assert!(settings.validate().is_err());
     }
 }
@@ -0,0 +1,399 @@
+use std::collections::HashMap;
+
+use ash::vk;
+use gpu_allocator::MemoryLocation;
+use log::*;
+use nalgebra::Vector3;
+
+use crate::{
+    core::{
+        materials::MaterialRegistry,
+        palette::DIRECT_BITS,
+        world::{CHUNK_SIZE, CHUNK_VOLUME, ChunkedWorld, DIR_SIZE, WORLD_CHUNKS, entry_slot},
+    },
+    vulkan::{buffer::Buffer, context::VulkanContext},
+};
+
+pub const MAX_LIGHTS: usize = 4096;
+/// Lights shaded per chunk, the nearest ones to the chunk win.
+pub const MAX_LIGHTS_PER_CHUNK: usize = 32;
+/// Total entries of the per-chunk light lists.
+pub const MAX_LIGHT_INDICES: usize = 1 << 16;
+/// Chunks read back at a time when looking for emissive blocks.
+const SCAN_BATCH: usize = 64;
+
+/// Light of an emissive block.
+pub const BLOCK_LIGHT_INTENSITY: f32 = 12.0;
+pub const BLOCK_LIGHT_RADIUS: f32 = 16.0;
+
+/// Omnidirectional light with inverse square falloff, cut off smoothly at
+/// `radius`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PointLight {
+    pub position: Vector3<f32>,
+    pub color: [f32; 3],
+    pub intensity: f32,
+    /// Nothing past this distance is lit, in voxels
+    pub radius: f32,
+}
+
+impl PointLight {
+    /// Light sitting in the middle of an emissive voxel.
+    pub fn block(pos: Vector3<i32>, emissive: [f32; 3]) -> Self {
+        Self {
+            position: pos.cast::<f32>().add_scalar(0.5),
+            color: emissive,
+            intensity: BLOCK_LIGHT_INTENSITY,
+            radius: BLOCK_LIGHT_RADIUS,
+        }
+    }
+
+    fn data(&self) -> LightData {
+        let p = self.position;
+        let [r, g, b] = self.color;
+        LightData {
+            position: [p.x, p.y, p.z, self.radius],
+            color: [r, g, b, self.intensity],
+        }
+    }
+}
+
+/// Light as laid out in raytrace.comp's light buffer.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+struct LightData {
+    /// xyz position, w radius
+    position: [f32; 4],
+    /// rgb colour, a intensity
+    color: [f32; 4],
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct LightId(usize);
+
+/// Point lights and their GPU copy. Lights are binned into the chunk
+/// directory's cells so a hit only shades the lights reaching its chunk.
+///
+/// The grid buffer holds a `(first, count)` pair per directory index followed
+/// by the light indices the pairs point into.
+pub struct LightSet {
+    pub light_buffer: Buffer,
+    pub grid_buffer: Buffer,
+    lights: Vec<Option<PointLight>>,
+    free: Vec<usize>,
+    /// Lights owned by emissive blocks, by chunk
+    chunk_lights: HashMap<Vector3<i32>, Vec<LightId>>,
+    /// Window the grid was built for, None when it needs a rebuild
+    uploaded_origin: Option<Vector3<i32>>,
+    /// World revision the block lights were derived at, None to rescan
+    /// every chunk
+    synced_revision: Option<u64>,
+}
+
+impl LightSet {
+    pub fn new(context: &VulkanContext) -> Result<Self, vk::Result> {
+        let light_buffer = Buffer::new(
+            context,
+            (MAX_LIGHTS * std::mem::size_of::<LightData>()) as u64,
+            vk::BufferUsageFlags::STORAGE_BUFFER,
+            MemoryLocation::CpuToGpu,
+            "Point Lights",
+        )?;
+        let mut grid_buffer = Buffer::new(
+            context,
+            ((DIR_SIZE * 2 + MAX_LIGHT_INDICES) * 4) as u64,
+            vk::BufferUsageFlags::STORAGE_BUFFER,
+            MemoryLocation::CpuToGpu,
+            "Light Grid",
+        )?;
+        grid_buffer.update_slice(&vec![0u32; DIR_SIZE * 2])?;
+
+        Ok(Self {
+            light_buffer,
+            grid_buffer,
+            lights: Vec::new(),
+            free: Vec::new(),
+            chunk_lights: HashMap::new(),
+            uploaded_origin: None,
+            synced_revision: None,
+        })
+    }
+
+    /// Adds a light, None when MAX_LIGHTS are already in use.
+    pub fn add(&mut self, light: PointLight) -> Option<LightId> {
+        let index = match self.free.pop() {
+            Some(index) => index,
+            None if self.lights.len() < MAX_LIGHTS => {
+                self.lights.push(None);
+                self.lights.len() - 1
+            }
+            None => {
+                warn!("Light limit of {} reached, light dropped", MAX_LIGHTS);
+                return None;
+            }
+        };
+        self.lights[index] = Some(light);
+        self.uploaded_origin = None;
+        Some(LightId(index))
+    }
+
+    pub fn remove(&mut self, id: LightId) -> Option<PointLight> {
+        let light = self.lights.get_mut(id.0)?.take()?;
+        self.free.push(id.0);
+        self.uploaded_origin = None;
+        Some(light)
+    }
+
+    pub fn get(&self, id: LightId) -> Option<&PointLight> {
+        self.lights.get(id.0).and_then(Option::as_ref)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (LightId, &PointLight)> {
+        self.lights
+            .iter()
+            .enumerate()
+            .filter_map(|(index, light)| light.as_ref().map(|l| (LightId(index), l)))
+    }
+
+    pub fn len(&self) -> usize {
+        self.lights.len() - self.free.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn clear(&mut self) {
+        self.lights.clear();
+        self.free.clear();
+        self.chunk_lights.clear();
+        self.uploaded_origin = None;
+        self.synced_revision = None;
+    }
+
+    /// Has the next `sync_world` rescan every chunk, for when the emissive
+    /// colours of blocks change.
+    pub fn rescan_blocks(&mut self) {
+        self.synced_revision = None;
+    }
+
+    /// Replaces the lights of a chunk's emissive blocks.
+    pub fn set_chunk_lights(
+        &mut self,
+        chunk: Vector3<i32>,
+        lights: impl IntoIterator<Item = PointLight>,
+    ) {
+        for id in self.chunk_lights.remove(&chunk).unwrap_or_default() {
+            self.remove(id);
+        }
+        let ids: Vec<LightId> = lights
+            .into_iter()
+            .map_while(|light| self.add(light))
+            .collect();
+        if !ids.is_empty() {
+            self.chunk_lights.insert(chunk, ids);
+        }
+    }
+
+    /// Derives the block lights from the world: the chunks it changed since
+    /// the last call are rescanned for emissive blocks, and the lights of
+    /// chunks that left the window are dropped. Only chunks whose palette
+    /// holds an emissive block are read back.
+    pub fn sync_world(
+        &mut self,
+        context: &VulkanContext,
+        world: &ChunkedWorld,
+        materials: &MaterialRegistry,
+    ) -> Result<(), vk::Result> {
+        if self.synced_revision == Some(world.revision) {
+            return Ok(());
+        }
+        let changed = world.changed_since(self.synced_revision);
+        self.synced_revision = Some(world.revision);
+        let evicted: Vec<Vector3<i32>> = self
+            .chunk_lights
+            .keys()
+            .copied()
+            .filter(|&chunk| !world.in_window(chunk))
+            .collect();
+        for chunk in evicted {
+            self.set_chunk_lights(chunk, []);
+        }
+
+        let emissive = |block: u32| {
+            materials
+                .get(block)
+                .map(|material| material.emissive)
+                .filter(|emissive| emissive.iter().any(|&c| c > 0.0))
+        };
+        // Uniform chunks of an emissive block stay unlit, they would take
+        // every light there is
+        let mut scan = Vec::new();
+        for chunk in changed {
+            let slot = world
+                .chunk_dir_index(chunk)
+                .and_then(|dir_index| entry_slot(world.directory[dir_index]));
+            match slot {
+                Some(slot) => {
+                    let palette = &world.palettes[slot as usize];
+                    if palette.bits == DIRECT_BITS
+                        || palette
+                            .blocks
+                            .iter()
+                            .any(|&block| emissive(block).is_some())
+                    {
+                        scan.push((chunk, slot));
+                    } else {
+                        self.set_chunk_lights(chunk, []);
+                    }
+                }
+                None => self.set_chunk_lights(chunk, []),
+            }
+        }
+
+        for batch in scan.chunks(SCAN_BATCH) {
+            let slots: Vec<u32> = batch.iter().map(|&(_, slot)| slot).collect();
+            let voxels = world.read_chunks(context, &slots)?;
+            for (&(chunk, _), voxels) in batch.iter().zip(voxels.chunks_exact(CHUNK_VOLUME)) {
+                let lights = block_lights(chunk, voxels, emissive);
+                self.set_chunk_lights(chunk, lights);
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether `upload` has anything to do for the window at `window_origin`.
+    pub fn needs_upload(&self, window_origin: Vector3<i32>) -> bool {
+        self.uploaded_origin != Some(window_origin)
+    }
+
+    /// Rebuilds the per-chunk lists for the window at `window_origin` and
+    /// uploads everything, when the lights or the window changed since the
+    /// last upload.
+    pub fn upload(&mut self, window_origin: Vector3<i32>) -> Result<(), vk::Result> {
+        if !self.needs_upload(window_origin) {
+            return Ok(());
+        }
+
+        let data: Vec<LightData> = self
+            .lights
+            .iter()
+            .map(|light| light.map(|l| l.data()).unwrap_or_default())
+            .collect();
+        if !data.is_empty() {
+            self.light_buffer.update_slice(&data)?;
+        }
+
+        // Lights reaching each chunk, with their distance to its centre
+        let size = CHUNK_SIZE as f32;
+        let mut cells: HashMap<usize, Vec<(f32, u32)>> = HashMap::new();
+        for (LightId(index), light) in self.iter() {
+            let min = light
+                .position
+                .add_scalar(-light.radius)
+                .map(|c| (c / size).floor() as i32);
+            let max = light
+                .position
+                .add_scalar(light.radius)
+                .map(|c| (c / size).floor() as i32);
+            let min = min.sup(&window_origin);
+            let max = max.inf(&window_origin.add_scalar(WORLD_CHUNKS as i32 - 1));
+            for z in min.z..=max.z {
+                for y in min.y..=max.y {
+                    for x in min.x..=max.x {
+                        let chunk = Vector3::new(x, y, z);
+                        let center = chunk.cast::<f32>().add_scalar(0.5) * size;
+                        let distance = (center - light.position).norm();
+                        cells
+                            .entry(dir_index(chunk))
+                            .or_default()
+                            .push((distance, index as u32));
+                    }
+                }
+            }
+        }
+
+        let mut grid = vec![0u32; DIR_SIZE * 2];
+        let mut indices = Vec::new();
+        let mut sorted: Vec<_> = cells.into_iter().collect();
+        sorted.sort_unstable_by_key(|(dir_index, _)| *dir_index);
+        for (dir_index, mut lights) in sorted {
+            if lights.len() > MAX_LIGHTS_PER_CHUNK {
+                lights.sort_by(|a, b| a.0.total_cmp(&b.0));
+                lights.truncate(MAX_LIGHTS_PER_CHUNK);
+            }
+            if indices.len() + lights.len() > MAX_LIGHT_INDICES {
+                warn!("Light grid full, some chunks are left unlit");
+                break;
+            }
+            grid[dir_index * 2] = indices.len() as u32;
+            grid[dir_index * 2 + 1] = lights.len() as u32;
+            indices.extend(lights.iter().map(|&(_, index)| index));
+        }
+        grid.extend(indices);
+        self.grid_buffer.update_slice(&grid)?;
+
+        self.uploaded_origin = Some(window_origin);
+        Ok(())
+    }
+
+    pub fn destroy(&mut self, context: &VulkanContext) {
+        self.light_buffer.destroy(context);
+        self.grid_buffer.destroy(context);
+    }
+}
+
+/// Lights of the emissive blocks among a chunk's voxels. `emissive` gives a
+/// block's colour, None for blocks that don't glow.
+fn block_lights(
+    chunk: Vector3<i32>,
+    voxels: &[u32],
+    emissive: impl Fn(u32) -> Option<[f32; 3]>,
+) -> Vec<PointLight> {
+    let origin = chunk * CHUNK_SIZE as i32;
+    voxels
+        .iter()
+        .enumerate()
+        .filter_map(|(local, &block)| {
+            let offset = Vector3::new(
+                local % CHUNK_SIZE,
+                (local / CHUNK_SIZE) % CHUNK_SIZE,
+                local / (CHUNK_SIZE * CHUNK_SIZE),
+            );
+            emissive(block)
+                .map(|emissive| PointLight::block(origin + offset.cast::<i32>(), emissive))
+        })
+        .collect()
+}
+
+fn dir_index(chunk: Vector3<i32>) -> usize {
+    let wrapped = chunk.map(|c| c.rem_euclid(WORLD_CHUNKS as i32) as usize);
+    wrapped.x + wrapped.y * WORLD_CHUNKS + wrapped.z * WORLD_CHUNKS * WORLD_CHUNKS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::world::local_index;
+
+    #[test]
+    fn block_lights_sit_on_emissive_voxels() {
+        let mut voxels = vec![1; CHUNK_VOLUME];
+        voxels[local_index(0, 0, 0)] = 7;
+        voxels[local_index(3, 31, 5)] = 7;
+        voxels[local_index(4, 4, 4)] = 2;
+        let emissive = |block| (block == 7).then_some([1.0, 0.5, 0.0]);
+
+        let lights = block_lights(Vector3::new(-1, 2, 0), &voxels, emissive);
+        let positions: Vec<_> = lights.iter().map(|light| light.position).collect();
+        assert_eq!(
+            positions,
+            [
+                Vector3::new(-31.5, 64.5, 0.5),
+                Vector3::new(-28.5, 95.5, 5.5),
+            ]
+        );
+        assert!(lights.iter().all(|light| light.color == [1.0, 0.5, 0.0]));
+        assert!(block_lights(Vector3::zeros(), &vec![1; CHUNK_VOLUME], emissive).is_empty());
+    }
+}
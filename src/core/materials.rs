@@ -0,0 +1,449 @@
+use std::{fs, path::Path};
+
+use crate::core::error::VoxelError;
+
+/// Material file the engine loads at startup.
+pub const MATERIALS_FILE: &str = "assets/materials.txt";
+/// Directory holding the block textures, `<name>.png` each.
+pub const TEXTURES_DIR: &str = "assets/textures";
+/// Entries in raytrace.comp's material buffer, block ids past it render magenta.
+pub const MAX_MATERIALS: usize = 256;
+
+/// Material flags.
+pub const FLUID: u32 = 1 << 0;
+/// Faces toward the air above ripple like a water surface
+pub const WATER_SURFACE: u32 = 1 << 1;
+
+const FLAG_NAMES: [(&str, u32); 2] = [("fluid", FLUID), ("water_surface", WATER_SURFACE)];
+
+/// Colour the raytracer shows for block ids without a material.
+const MISSING_ALBEDO: [f32; 3] = [1.0, 0.0, 1.0];
+
+/// Texture names of a block's faces, None leaves the face a flat colour.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FaceTextures {
+    pub top: Option<String>,
+    pub side: Option<String>,
+    pub bottom: Option<String>,
+}
+
+impl FaceTextures {
+    pub fn iter(&self) -> impl Iterator<Item = &str> {
+        [&self.top, &self.side, &self.bottom]
+            .into_iter()
+            .filter_map(|name| name.as_deref())
+    }
+}
+
+/// How a block looks. Colours are linear RGB.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Material {
+    pub name: String,
+    /// Multiplies the texels of the textures whose alpha is set
+    pub albedo: [f32; 3],
+    pub emissive: [f32; 3],
+    pub roughness: f32,
+    /// Share of the light going through the surface, 0 is opaque
+    pub transparency: f32,
+    /// Index of refraction, 1 lets rays through unbent and unreflected
+    pub ior: f32,
+    /// Extinction per voxel travelled inside the material, tinted by `albedo`
+    pub absorption: f32,
+    pub flags: u32,
+    pub textures: FaceTextures,
+}
+
+impl Material {
+    pub fn new(name: &str, albedo: [f32; 3]) -> Self {
+        Self {
+            name: name.to_string(),
+            albedo,
+            emissive: [0.0; 3],
+            roughness: 1.0,
+            transparency: 0.0,
+            ior: 1.0,
+            absorption: 0.0,
+            flags: 0,
+            textures: FaceTextures::default(),
+        }
+    }
+
+    pub fn has_flag(&self, flag: u32) -> bool {
+        self.flags & flag != 0
+    }
+
+    pub fn is_transparent(&self) -> bool {
+        self.transparency > 0.0
+    }
+
+    /// `layer_of` maps a texture name to its layer in the texture array.
+    pub(crate) fn data(&self, layer_of: impl Fn(&str) -> Option<usize>) -> MaterialData {
+        let [r, g, b] = self.albedo;
+        let [er, eg, eb] = self.emissive;
+        let layer = |name: &Option<String>| {
+            name.as_deref()
+                .and_then(&layer_of)
+                .map_or(-1, |layer| layer as i32)
+        };
+        let textures = &self.textures;
+        MaterialData {
+            albedo: [r, g, b, self.transparency],
+            emissive: [er, eg, eb, self.roughness],
+            flags: [self.flags, 0, 0, 0],
+            textures: [
+                layer(&textures.top),
+                layer(&textures.side),
+                layer(&textures.bottom),
+                -1,
+            ],
+            optics: [self.ior, self.absorption, 0.0, 0.0],
+        }
+    }
+}
+
+/// Material as laid out in raytrace.comp's storage buffer.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct MaterialData {
+    /// rgb albedo, a transparency
+    albedo: [f32; 4],
+    /// rgb emissive, a roughness
+    emissive: [f32; 4],
+    flags: [u32; 4],
+    /// Texture layers of the top, side and bottom faces, -1 untextured
+    textures: [i32; 4],
+    /// x index of refraction, y absorption
+    optics: [f32; 4],
+}
+
+/// Materials indexed by block id.
+#[derive(Clone, Debug, Default)]
+pub struct MaterialRegistry {
+    materials: Vec<Option<Material>>,
+}
+
+impl MaterialRegistry {
+    /// The material file shipped with the engine.
+    pub fn builtin() -> Self {
+        Self::parse(include_str!("../../assets/materials.txt"))
+            .expect("builtin materials are valid")
+    }
+
+    pub fn load(path: &Path) -> Result<Self, VoxelError> {
+        let text = fs::read_to_string(path)?;
+        Self::parse(&text).map_err(|err| VoxelError::Format(format!("{}: {}", path.display(), err)))
+    }
+
+    /// Parses a material file, see assets/materials.txt for the format.
+    pub fn parse(text: &str) -> Result<Self, VoxelError> {
+        let mut registry = Self::default();
+        let mut current: Option<(usize, Option<u32>, Material)> = None;
+
+        for (line_index, line) in text.lines().enumerate() {
+            let line_number = line_index + 1;
+            let error =
+                |message: String| VoxelError::Format(format!("line {}: {}", line_number, message));
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+                if let Some(section) = current.take() {
+                    registry.finish_section(section)?;
+                }
+                let name = name.trim();
+                if name.is_empty() {
+                    return Err(error("empty material name".to_string()));
+                }
+                current = Some((line_number, None, Material::new(name, [0.0; 3])));
+                continue;
+            }
+
+            let Some((_, id, material)) = current.as_mut() else {
+                return Err(error(
+                    "property outside of a [material] section".to_string(),
+                ));
+            };
+            let Some((key, value)) = line.split_once('=') else {
+                return Err(error(format!("expected `key = value`, got `{}`", line)));
+            };
+            let (key, value) = (key.trim(), value.trim());
+            match key {
+                "id" => {
+                    *id = Some(
+                        value
+                            .parse()
+                            .map_err(|_| error(format!("invalid id `{}`", value)))?,
+                    )
+                }
+                "albedo" => material.albedo = parse_rgb(value).map_err(error)?,
+                "emissive" => material.emissive = parse_rgb(value).map_err(error)?,
+                "roughness" => material.roughness = parse_unit(value).map_err(error)?,
+                "transparency" => material.transparency = parse_unit(value).map_err(error)?,
+                "ior" => {
+                    material.ior = value
+                        .parse::<f32>()
+                        .ok()
+                        .filter(|ior| (1.0..=4.0).contains(ior))
+                        .ok_or_else(|| {
+                            error(format!(
+                                "expected an index of refraction in 1..4, got `{}`",
+                                value
+                            ))
+                        })?
+                }
+                "absorption" => {
+                    material.absorption = value
+                        .parse::<f32>()
+                        .ok()
+                        .filter(|a| a.is_finite() && *a >= 0.0)
+                        .ok_or_else(|| error(format!("invalid absorption `{}`", value)))?
+                }
+                "texture" => {
+                    let name = parse_texture(value).map_err(error)?;
+                    material.textures = FaceTextures {
+                        top: name.clone(),
+                        side: name.clone(),
+                        bottom: name,
+                    };
+                }
+                "texture_top" => material.textures.top = parse_texture(value).map_err(error)?,
+                "texture_side" => material.textures.side = parse_texture(value).map_err(error)?,
+                "texture_bottom" => {
+                    material.textures.bottom = parse_texture(value).map_err(error)?
+                }
+                "flags" => {
+                    material.flags = 0;
+                    for flag in value.split_whitespace() {
+                        let Some(&(_, bit)) = FLAG_NAMES.iter().find(|(name, _)| *name == flag)
+                        else {
+                            return Err(error(format!("unknown flag `{}`", flag)));
+                        };
+                        material.flags |= bit;
+                    }
+                }
+                _ => return Err(error(format!("unknown property `{}`", key))),
+            }
+        }
+        if let Some(section) = current.take() {
+            registry.finish_section(section)?;
+        }
+        Ok(registry)
+    }
+
+    fn finish_section(
+        &mut self,
+        (line_number, id, material): (usize, Option<u32>, Material),
+    ) -> Result<(), VoxelError> {
+        let Some(id) = id else {
+            return Err(VoxelError::Format(format!(
+                "line {}: material `{}` has no id",
+                line_number, material.name
+            )));
+        };
+        self.insert(id, material)
+            .map_err(|message| VoxelError::Format(format!("line {}: {}", line_number, message)))
+    }
+
+    /// Registers `material` under block `id`.
+    pub fn insert(&mut self, id: u32, material: Material) -> Result<(), String> {
+        let index = id as usize;
+        if id == 0 {
+            return Err(format!(
+                "`{}` uses id 0, which is reserved for air",
+                material.name
+            ));
+        }
+        if index >= MAX_MATERIALS {
+            return Err(format!(
+                "`{}` id {} is over the limit of {}",
+                material.name,
+                id,
+                MAX_MATERIALS - 1
+            ));
+        }
+        if let Some(existing) = self.id_of(&material.name) {
+            return Err(format!(
+                "`{}` is already defined with id {}",
+                material.name, existing
+            ));
+        }
+        if let Some(Some(existing)) = self.materials.get(index) {
+            return Err(format!(
+                "id {} is used by both `{}` and `{}`",
+                id, existing.name, material.name
+            ));
+        }
+        if self.materials.len() <= index {
+            self.materials.resize(index + 1, None);
+        }
+        self.materials[index] = Some(material);
+        Ok(())
+    }
+
+    pub fn get(&self, id: u32) -> Option<&Material> {
+        self.materials.get(id as usize).and_then(Option::as_ref)
+    }
+
+    pub fn id_of(&self, name: &str) -> Option<u32> {
+        self.materials
+            .iter()
+            .position(|material| material.as_ref().is_some_and(|m| m.name == name))
+            .map(|index| index as u32)
+    }
+
+    /// Defined materials with their block id.
+    pub fn iter(&self) -> impl Iterator<Item = (u32, &Material)> {
+        self.materials
+            .iter()
+            .enumerate()
+            .filter_map(|(id, material)| material.as_ref().map(|m| (id as u32, m)))
+    }
+
+    /// Every texture the materials use, in texture array layer order.
+    pub fn texture_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = Vec::new();
+        for (_, material) in self.iter() {
+            for name in material.textures.iter() {
+                if !names.iter().any(|n| n == name) {
+                    names.push(name.to_string());
+                }
+            }
+        }
+        names
+    }
+
+    /// The full material buffer, undefined ids filled with magenta. Texture
+    /// layers follow `texture_names`.
+    pub(crate) fn gpu_data(&self) -> Vec<MaterialData> {
+        let names = self.texture_names();
+        let layer_of = |name: &str| names.iter().position(|n| n == name);
+        let missing = Material::new("missing", MISSING_ALBEDO).data(layer_of);
+        (0..MAX_MATERIALS)
+            .map(|id| {
+                self.get(id as u32)
+                    .map_or(missing, |material| material.data(layer_of))
+            })
+            .collect()
+    }
+}
+
+fn parse_rgb(value: &str) -> Result<[f32; 3], String> {
+    let components = value
+        .split_whitespace()
+        .map(|c| c.parse::<f32>().ok().filter(|c| c.is_finite() && *c >= 0.0))
+        .collect::<Option<Vec<_>>>()
+        .ok_or_else(|| format!("invalid colour `{}`", value))?;
+    components
+        .try_into()
+        .map_err(|_| format!("expected 3 colour components, got `{}`", value))
+}
+
+fn parse_texture(value: &str) -> Result<Option<String>, String> {
+    match value {
+        "" => Err("missing texture name".to_string()),
+        "none" => Ok(None),
+        name if name.contains(['/', '\\', ' ']) => Err(format!(
+            "texture `{}` must be a file name in {}",
+            name, TEXTURES_DIR
+        )),
+        name => Ok(Some(name.to_string())),
+    }
+}
+
+fn parse_unit(value: &str) -> Result<f32, String> {
+    value
+        .parse::<f32>()
+        .ok()
+        .filter(|v| (0.0..=1.0).contains(v))
+        .ok_or_else(|| format!("expected a number in 0..1, got `{}`", value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_error(text: &str) -> String {
+        match MaterialRegistry::parse(text) {
+            Err(VoxelError::Format(message)) => message,
+            other => panic!("expected a format error, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn builtin_materials_parse() {
+        let registry = MaterialRegistry::builtin();
+        assert_eq!(registry.id_of("stone"), Some(1));
+        assert!(registry.get(0).is_none());
+    }
+
+    #[test]
+    fn parses_properties() {
+        let registry = MaterialRegistry::parse(
+            "# comment\n[glass]\nid = 7\nalbedo = 0.5 0.25 1\ntransparency = 0.8 # trailing\nior = 1.5\nflags = fluid water_surface\ntexture = none\n",
+        )
+        .unwrap();
+        let glass = registry.get(7).unwrap();
+        assert_eq!(glass.name, "glass");
+        assert_eq!(glass.albedo, [0.5, 0.25, 1.0]);
+        assert_eq!(glass.transparency, 0.8);
+        assert_eq!(glass.ior, 1.5);
+        assert_eq!(glass.flags, FLUID | WATER_SURFACE);
+    }
+
+    #[test]
+    fn rejects_missing_id() {
+        assert!(parse_error("[stone]\nalbedo = 1 1 1\n").contains("has no id"));
+    }
+
+    #[test]
+    fn rejects_air_id() {
+        assert!(parse_error("[stone]\nid = 0\n").contains("reserved for air"));
+    }
+
+    #[test]
+    fn rejects_id_over_limit() {
+        let text = format!("[stone]\nid = {}\n", MAX_MATERIALS);
+        assert!(parse_error(&text).contains("over the limit"));
+    }
+
+    #[test]
+    fn rejects_duplicate_id() {
+        assert!(parse_error("[stone]\nid = 1\n[dirt]\nid = 1\n").contains("used by both"));
+    }
+
+    #[test]
+    fn rejects_duplicate_name() {
+        assert!(parse_error("[stone]\nid = 1\n[stone]\nid = 2\n").contains("already defined"));
+    }
+
+    #[test]
+    fn rejects_unknown_flag() {
+        assert!(
+            parse_error("[stone]\nid = 1\nflags = fluid sticky\n")
+                .contains("unknown flag `sticky`")
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_property() {
+        assert!(
+            parse_error("[stone]\nid = 1\nshininess = 2\n")
+                .contains("unknown property `shininess`")
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_lines() {
+        assert!(parse_error("id = 1\n").contains("outside of a [material] section"));
+        assert!(parse_error("[stone]\nid 1\n").contains("expected `key = value`"));
+        assert!(parse_error("[stone]\nid = one\n").contains("invalid id"));
+        assert!(parse_error("[]\n").contains("empty material name"));
+    }
+
+    #[test]
+    fn reports_line_numbers() {
+        assert!(parse_error("[stone]\nid = 1\n\nroughness = 2\n").starts_with("line 4:"));
+    }
+}
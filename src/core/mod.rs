@@ -1,4 +1,18 @@
-pub mod error;
+pub mod allocator;
+pub mod biome;
+pub mod blocks;
+pub mod capture;
+pub mod edit;
 pub mod engine;
-pub mod world;
+pub mod environment;
+pub mod error;
+pub mod features;
 pub mod generator;
+pub mod lights;
+pub mod materials;
+pub mod palette;
+pub mod region;
+pub mod streaming;
+pub mod terrain;
+pub mod tree;
+pub mod world;
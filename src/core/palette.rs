@@ -0,0 +1,255 @@
+use crate::core::world::{CHUNK_VOLUME, PAGE_WORDS};
+
+/// Block ids a pool slot's palette can hold. Chunks with more distinct blocks
+/// store their ids directly.
+pub const PALETTE_CAPACITY: usize = 256;
+/// Widest index. Chunks packed at this width hold block ids, not palette
+/// indices.
+pub const DIRECT_BITS: u32 = 16;
+/// Highest block id a chunk can store.
+pub const MAX_BLOCK_ID: u32 = (1 << DIRECT_BITS) - 1;
+/// Pages a chunk takes at the widest index.
+pub const MAX_CHUNK_PAGES: usize = CHUNK_VOLUME * DIRECT_BITS as usize / 32 / PAGE_WORDS;
+
+/// Where and how a pool slot's voxels are packed, as read by the shaders.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ChunkHeader {
+    /// Bits per voxel: 1, 2, 4, 8 or DIRECT_BITS, 0 while the slot has no pages
+    pub bits: u32,
+    pub palette_len: u32,
+    /// Pool pages holding the packed voxels in order, one per index bit
+    pub pages: [u32; MAX_CHUNK_PAGES],
+}
+
+impl ChunkHeader {
+    /// Pages in use, `pages[..page_count()]`.
+    pub fn page_count(&self) -> usize {
+        page_count(self.bits)
+    }
+
+    /// Pool word holding voxel `local` and the bit its index starts at.
+    pub fn locate(&self, local: usize) -> (u64, u32) {
+        let bit = local * self.bits as usize;
+        (self.word_offset(bit / 32), (bit % 32) as u32)
+    }
+
+    /// Pool word holding word `word` of the packed indices.
+    pub fn word_offset(&self, word: usize) -> u64 {
+        let page = self.pages[word / PAGE_WORDS] as u64;
+        page * PAGE_WORDS as u64 + (word % PAGE_WORDS) as u64
+    }
+}
+
+/// Smallest index width that fits `palette_len` blocks, DIRECT_BITS past the
+/// palette's capacity.
+pub fn index_bits(palette_len: usize) -> u32 {
+    match palette_len {
+        0..=2 => 1,
+        3..=4 => 2,
+        5..=16 => 4,
+        17..=PALETTE_CAPACITY => 8,
+        _ => DIRECT_BITS,
+    }
+}
+
+/// Pages of packed voxels at an index width.
+pub fn page_count(bits: u32) -> usize {
+    CHUNK_VOLUME * bits as usize / 32 / PAGE_WORDS
+}
+
+/// The blocks of a chunk and the index width they need.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Palette {
+    /// Distinct blocks, ascending when packed from scratch and with the ones
+    /// edits add appended. Empty when ids are stored directly
+    pub blocks: Vec<u32>,
+    pub bits: u32,
+}
+
+impl Palette {
+    /// Palette of a chunk with `count` distinct blocks, the first of which
+    /// are `blocks` in ascending order. Only up to PALETTE_CAPACITY of them
+    /// are needed.
+    pub fn new(mut blocks: Vec<u32>, count: usize) -> Self {
+        let bits = index_bits(count);
+        if bits == DIRECT_BITS {
+            blocks.clear();
+        }
+        Self { blocks, bits }
+    }
+
+    /// The block filling the whole chunk, if it only has one.
+    pub fn uniform(&self) -> Option<u32> {
+        match self.blocks[..] {
+            [block] => Some(block),
+            _ => None,
+        }
+    }
+
+    /// Block of a packed index.
+    pub fn block(&self, index: u32) -> u32 {
+        if self.bits == DIRECT_BITS {
+            index
+        } else {
+            self.blocks.get(index as usize).copied().unwrap_or(0)
+        }
+    }
+}
+
+/// A chunk's voxels as indices into its palette, packed the way pack.comp
+/// does: voxel `i` sits at bit `i * bits` of the stream.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PackedChunk {
+    pub palette: Palette,
+    pub words: Vec<u32>,
+}
+
+impl PackedChunk {
+    /// Packs CHUNK_VOLUME voxels. Panics on block ids over MAX_BLOCK_ID,
+    /// callers reject those where they enter the world.
+    pub fn encode(voxels: &[u32]) -> Self {
+        assert!(
+            voxels.iter().all(|&block| block <= MAX_BLOCK_ID),
+            "block ids over MAX_BLOCK_ID can't be packed"
+        );
+        let mut blocks = voxels.to_vec();
+        blocks.sort_unstable();
+        blocks.dedup();
+        let count = blocks.len();
+        let palette = Palette::new(blocks, count);
+        let bits = palette.bits;
+
+        let per_word = 32 / bits as usize;
+        let words = voxels
+            .chunks(per_word)
+            .map(|voxels| {
+                voxels.iter().enumerate().fold(0, |word, (i, &block)| {
+                    let index = if bits == DIRECT_BITS {
+                        block
+                    } else {
+                        palette.blocks.binary_search(&block).unwrap_or_default() as u32
+                    };
+                    word | (index << (i * bits as usize))
+                })
+            })
+            .collect();
+        Self { palette, words }
+    }
+
+    /// Unpacks `words` back into one block per voxel.
+    pub fn decode(palette: &Palette, words: &[u32]) -> Vec<u32> {
+        let mask = (1u32 << palette.bits) - 1;
+        (0..CHUNK_VOLUME)
+            .map(|local| {
+                let bit = local * palette.bits as usize;
+                palette.block((words[bit / 32] >> (bit % 32)) & mask)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A chunk cycling through `count` distinct blocks spread over the id range.
+    fn chunk_with_blocks(count: u32) -> Vec<u32> {
+        (0..CHUNK_VOLUME as u32)
+            .map(|i| (i * 7 % count) * (MAX_BLOCK_ID / count))
+            .collect()
+    }
+
+    #[test]
+    fn index_bits_fit_palette_length() {
+        assert_eq!(index_bits(1), 1);
+        assert_eq!(index_bits(2), 1);
+        assert_eq!(index_bits(3), 2);
+        assert_eq!(index_bits(4), 2);
+        assert_eq!(index_bits(5), 4);
+        assert_eq!(index_bits(16), 4);
+        assert_eq!(index_bits(17), 8);
+        assert_eq!(index_bits(PALETTE_CAPACITY), 8);
+        assert_eq!(index_bits(PALETTE_CAPACITY + 1), DIRECT_BITS);
+    }
+
+    #[test]
+    fn round_trips_every_width() {
+        for (count, bits) in [
+            (2, 1),
+            (4, 2),
+            (16, 4),
+            (256, 8),
+            (257, DIRECT_BITS),
+            (5000, DIRECT_BITS),
+        ] {
+            let voxels = chunk_with_blocks(count);
+            let packed = PackedChunk::encode(&voxels);
+            assert_eq!(packed.palette.bits, bits, "{} blocks", count);
+            assert_eq!(packed.words.len(), page_count(bits) * PAGE_WORDS);
+            assert_eq!(PackedChunk::decode(&packed.palette, &packed.words), voxels);
+        }
+    }
+
+    #[test]
+    fn palette_is_sorted_and_empty_when_direct() {
+        let packed = PackedChunk::encode(&chunk_with_blocks(16));
+        assert!(packed.palette.blocks.is_sorted());
+        assert_eq!(packed.palette.blocks.len(), 16);
+        assert!(
+            PackedChunk::encode(&chunk_with_blocks(300))
+                .palette
+                .blocks
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn uniform_chunk_has_one_block() {
+        let packed = PackedChunk::encode(&vec![12; CHUNK_VOLUME]);
+        assert_eq!(packed.palette.uniform(), Some(12));
+        assert_eq!(packed.palette.bits, 1);
+        assert!(packed.words.iter().all(|&word| word == 0));
+        assert_eq!(
+            PackedChunk::encode(&vec![0; CHUNK_VOLUME])
+                .palette
+                .uniform(),
+            Some(0)
+        );
+        assert_eq!(
+            PackedChunk::encode(&chunk_with_blocks(2)).palette.uniform(),
+            None
+        );
+    }
+
+    #[test]
+    fn header_locates_packed_voxels() {
+        let voxels = chunk_with_blocks(16);
+        let packed = PackedChunk::encode(&voxels);
+        let mut header = ChunkHeader {
+            bits: packed.palette.bits,
+            ..Default::default()
+        };
+        // Pages out of order, as the allocator may hand them out
+        let count = header.page_count();
+        for (i, page) in header.pages[..count].iter_mut().enumerate() {
+            *page = 10 - i as u32;
+        }
+        let mut pool = vec![0; 11 * PAGE_WORDS];
+        for (i, page) in packed.words.chunks(PAGE_WORDS).enumerate() {
+            let start = header.pages[i] as usize * PAGE_WORDS;
+            pool[start..start + PAGE_WORDS].copy_from_slice(page);
+        }
+        for local in [0, 1, 777, CHUNK_VOLUME - 1] {
+            let (offset, shift) = header.locate(local);
+            let index = (pool[offset as usize] >> shift) & ((1 << header.bits) - 1);
+            assert_eq!(packed.palette.block(index), voxels[local]);
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn rejects_block_ids_over_limit() {
+        PackedChunk::encode(&vec![MAX_BLOCK_ID + 1; CHUNK_VOLUME]);
+    }
+}
@@ -0,0 +1,423 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fs,
+    io::{BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use log::*;
+use nalgebra::Vector3;
+
+use crate::{
+    core::{
+        error::VoxelError,
+        palette::MAX_BLOCK_ID,
+        world::{CHUNK_VOLUME, ChunkedWorld, WORLD_CHUNKS},
+    },
+    vulkan::context::VulkanContext,
+};
+
+/// Chunks per region file along each axis.
+pub const REGION_CHUNKS: i32 = 16;
+pub const REGION_VERSION: u32 = 1;
+const REGION_MAGIC: &[u8; 4] = b"VXRG";
+const ENCODING_RLE: u8 = 1;
+
+/// One region file: up to 16^3 chunks, each stored RLE encoded.
+///
+/// Layout (little endian): magic `VXRG`, version u32, region coordinate
+/// 3 x i32, chunk count u32, then per chunk a header (local index u16,
+/// encoding u8, reserved u8, payload length u32) followed by its payload.
+/// RLE payloads are `(run length u16, block u32)` pairs covering the chunk
+/// in pool order.
+pub struct Region {
+    pub coord: Vector3<i32>,
+    chunks: BTreeMap<u16, Vec<u8>>,
+}
+
+impl Region {
+    pub fn new(coord: Vector3<i32>) -> Self {
+        Self {
+            coord,
+            chunks: BTreeMap::new(),
+        }
+    }
+
+    /// Region coordinate and index inside it for a chunk coordinate.
+    pub fn locate(chunk: Vector3<i32>) -> (Vector3<i32>, u16) {
+        let region = chunk.map(|c| c.div_euclid(REGION_CHUNKS));
+        let local = chunk.map(|c| c.rem_euclid(REGION_CHUNKS));
+        let index = local.x + local.y * REGION_CHUNKS + local.z * REGION_CHUNKS * REGION_CHUNKS;
+        (region, index as u16)
+    }
+
+    pub fn chunk_coord(&self, index: u16) -> Vector3<i32> {
+        let index = index as i32;
+        let local = Vector3::new(
+            index % REGION_CHUNKS,
+            (index / REGION_CHUNKS) % REGION_CHUNKS,
+            index / (REGION_CHUNKS * REGION_CHUNKS),
+        );
+        self.coord * REGION_CHUNKS + local
+    }
+
+    pub fn path(dir: &Path, coord: Vector3<i32>) -> PathBuf {
+        dir.join(format!("r.{}.{}.{}.vxr", coord.x, coord.y, coord.z))
+    }
+
+    /// Reads the region at `coord`, or an empty one if it was never saved.
+    pub fn load_or_new(dir: &Path, coord: Vector3<i32>) -> Result<Self, VoxelError> {
+        let path = Self::path(dir, coord);
+        if path.exists() {
+            Self::read(&path)
+        } else {
+            Ok(Self::new(coord))
+        }
+    }
+
+    pub fn read(path: &Path) -> Result<Self, VoxelError> {
+        let mut reader = BufReader::new(fs::File::open(path)?);
+
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != REGION_MAGIC {
+            return Err(VoxelError::Format(format!(
+                "{} is not a region file",
+                path.display()
+            )));
+        }
+        let version = read_u32(&mut reader)?;
+        if version != REGION_VERSION {
+            return Err(VoxelError::Format(format!(
+                "{} has unsupported region version {}",
+                path.display(),
+                version
+            )));
+        }
+        let coord = Vector3::new(
+            read_u32(&mut reader)? as i32,
+            read_u32(&mut reader)? as i32,
+            read_u32(&mut reader)? as i32,
+        );
+        let count = read_u32(&mut reader)?;
+
+        let mut chunks = BTreeMap::new();
+        for _ in 0..count {
+            let mut header = [0u8; 8];
+            reader.read_exact(&mut header)?;
+            let index = u16::from_le_bytes([header[0], header[1]]);
+            let encoding = header[2];
+            let len = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+            if encoding != ENCODING_RLE {
+                return Err(VoxelError::Format(format!(
+                    "Unknown chunk encoding {} in {}",
+                    encoding,
+                    path.display()
+                )));
+            }
+            let mut payload = vec![0u8; len as usize];
+            reader.read_exact(&mut payload)?;
+            chunks.insert(index, payload);
+        }
+
+        Ok(Self { coord, chunks })
+    }
+
+    pub fn write(&self, path: &Path) -> Result<(), VoxelError> {
+        let mut writer = BufWriter::new(fs::File::create(path)?);
+        writer.write_all(REGION_MAGIC)?;
+        writer.write_all(&REGION_VERSION.to_le_bytes())?;
+        for axis in 0..3 {
+            writer.write_all(&self.coord[axis].to_le_bytes())?;
+        }
+        writer.write_all(&(self.chunks.len() as u32).to_le_bytes())?;
+        for (index, payload) in &self.chunks {
+            writer.write_all(&index.to_le_bytes())?;
+            writer.write_all(&[ENCODING_RLE, 0])?;
+            writer.write_all(&(payload.len() as u32).to_le_bytes())?;
+            writer.write_all(payload)?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    pub fn get(&self, index: u16) -> Option<Result<Vec<u32>, VoxelError>> {
+        self.chunks.get(&index).map(|payload| decode_rle(payload))
+    }
+
+    pub fn insert(&mut self, index: u16, voxels: &[u32]) {
+        self.chunks.insert(index, encode_rle(voxels));
+    }
+
+    pub fn indices(&self) -> impl Iterator<Item = u16> + '_ {
+        self.chunks.keys().copied()
+    }
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> Result<u32, VoxelError> {
+    let mut bytes = [0u8; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+pub fn encode_rle(voxels: &[u32]) -> Vec<u8> {
+    let mut encoded = Vec::new();
+    let mut iter = voxels.iter().peekable();
+    while let Some(&block) = iter.next() {
+        let mut run: u16 = 1;
+        while run < u16::MAX && iter.peek() == Some(&&block) {
+            iter.next();
+            run += 1;
+        }
+        encoded.extend_from_slice(&run.to_le_bytes());
+        encoded.extend_from_slice(&block.to_le_bytes());
+    }
+    encoded
+}
+
+pub fn decode_rle(encoded: &[u8]) -> Result<Vec<u32>, VoxelError> {
+    if !encoded.len().is_multiple_of(6) {
+        return Err(VoxelError::Format("Truncated RLE chunk".to_string()));
+    }
+    let mut voxels = Vec::with_capacity(CHUNK_VOLUME);
+    for pair in encoded.chunks_exact(6) {
+        let run = u16::from_le_bytes([pair[0], pair[1]]) as usize;
+        let block = u32::from_le_bytes([pair[2], pair[3], pair[4], pair[5]]);
+        if block > MAX_BLOCK_ID {
+            return Err(VoxelError::Format(format!(
+                "Block id {} out of range",
+                block
+            )));
+        }
+        if voxels.len() + run > CHUNK_VOLUME {
+            return Err(VoxelError::Format("RLE chunk overflows".to_string()));
+        }
+        voxels.resize(voxels.len() + run, block);
+    }
+    if voxels.len() != CHUNK_VOLUME {
+        return Err(VoxelError::Format("RLE chunk is incomplete".to_string()));
+    }
+    Ok(voxels)
+}
+
+impl ChunkedWorld {
+    /// Writes every resident chunk, plus chunks edited since the last save,
+    /// into the region files under `dir`. Chunks outside the window that were
+    /// saved before are kept.
+    pub fn save(&mut self, context: &VulkanContext, dir: &Path) -> Result<(), VoxelError> {
+        let mut chunks: HashSet<Vector3<i32>> = self
+            .resident_chunks()
+            .into_iter()
+            .map(|(chunk, _)| chunk)
+            .collect();
+        chunks.extend(self.modified.iter().copied());
+        let chunks: Vec<Vector3<i32>> = chunks.into_iter().collect();
+        self.save_chunks(context, dir, &chunks)?;
+        info!("Saved {} chunks to {}", chunks.len(), dir.display());
+        Ok(())
+    }
+
+    /// Writes the given chunks to their region files. Chunks outside the window
+    /// are skipped.
+    pub fn save_chunks(
+        &mut self,
+        context: &VulkanContext,
+        dir: &Path,
+        chunks: &[Vector3<i32>],
+    ) -> Result<(), VoxelError> {
+        fs::create_dir_all(dir)?;
+
+        let mut by_region: HashMap<Vector3<i32>, Vec<(u16, Vector3<i32>)>> = HashMap::new();
+        for &chunk in chunks {
+            if !self.in_window(chunk) {
+                continue;
+            }
+            let (region, index) = Region::locate(chunk);
+            by_region.entry(region).or_default().push((index, chunk));
+        }
+
+        for (coord, entries) in by_region {
+            let mut region = Region::load_or_new(dir, coord)?;
+
+            let mut solid = Vec::new();
+            for (index, chunk) in entries {
+                let entry = self
+                    .chunk_dir_index(chunk)
+                    .map_or(0, |dir_index| self.directory[dir_index]);
+                if entry == 0 {
+                    // Kept as a record so loading doesn't regenerate carved out chunks
+                    region.insert(index, &[0; CHUNK_VOLUME]);
+                } else {
+                    solid.push((index, entry));
+                }
+                self.modified.remove(&chunk);
+            }
+
+            let entries: Vec<u32> = solid.iter().map(|&(_, entry)| entry).collect();
+            let voxels = self.read_chunks(context, &entries)?;
+            for ((index, _), chunk_voxels) in solid.iter().zip(voxels.chunks_exact(CHUNK_VOLUME)) {
+                region.insert(*index, chunk_voxels);
+            }
+
+            region.write(&Region::path(dir, coord))?;
+        }
+        Ok(())
+    }
+
+    /// Replaces the resident window with what was saved under `dir` and
+    /// returns the chunks that had a saved copy. The others are left empty.
+    pub fn load(
+        &mut self,
+        context: &VulkanContext,
+        dir: &Path,
+    ) -> Result<HashSet<Vector3<i32>>, VoxelError> {
+        for (chunk, _) in self.resident_chunks() {
+            if let Some(dir_index) = self.chunk_dir_index(chunk) {
+                self.free_chunk(dir_index)?;
+            }
+        }
+        self.modified.clear();
+
+        let origin = self.window_origin;
+        let first = Region::locate(origin).0;
+        let last = Region::locate(origin.add_scalar(WORLD_CHUNKS as i32 - 1)).0;
+        let mut chunks = Vec::new();
+        for z in first.z..=last.z {
+            for y in first.y..=last.y {
+                for x in first.x..=last.x {
+                    let path = Region::path(dir, Vector3::new(x, y, z));
+                    if !path.exists() {
+                        continue;
+                    }
+                    let region = Region::read(&path)?;
+                    chunks.extend(
+                        region
+                            .indices()
+                            .map(|index| region.chunk_coord(index))
+                            .filter(|&chunk| self.in_window(chunk)),
+                    );
+                }
+            }
+        }
+
+        let loaded = self.load_chunks(context, dir, &chunks)?;
+        info!("Loaded {} chunks from {}", loaded.len(), dir.display());
+        Ok(loaded)
+    }
+
+    /// Uploads the saved copies of `chunks` found under `dir` and returns the
+    /// chunks that had one. Saved all air chunks just release their slot.
+    pub fn load_chunks(
+        &mut self,
+        context: &VulkanContext,
+        dir: &Path,
+        chunks: &[Vector3<i32>],
+    ) -> Result<HashSet<Vector3<i32>>, VoxelError> {
+        let mut by_region: HashMap<Vector3<i32>, Vec<(u16, Vector3<i32>)>> = HashMap::new();
+        for &chunk in chunks {
+            if !self.in_window(chunk) {
+                continue;
+            }
+            let (region, index) = Region::locate(chunk);
+            by_region.entry(region).or_default().push((index, chunk));
+        }
+
+        let mut loaded = HashSet::new();
+        let mut dir_indices = Vec::new();
+        let mut data = Vec::new();
+        for (coord, entries) in by_region {
+            let path = Region::path(dir, coord);
+            if !path.exists() {
+                continue;
+            }
+            let region = Region::read(&path)?;
+            for (index, chunk) in entries {
+                let Some(voxels) = region.get(index) else {
+                    continue;
+                };
+                let voxels = voxels?;
+                let Some(dir_index) = self.chunk_dir_index(chunk) else {
+                    continue;
+                };
+                loaded.insert(chunk);
+                self.modified.remove(&chunk);
+                // All air chunks just free their slot in write_chunks
+                dir_indices.push(dir_index);
+                data.extend_from_slice(&voxels);
+            }
+        }
+
+        self.write_chunks(context, &dir_indices, &data)?;
+        Ok(loaded)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn runs(encoded: &[u8]) -> Vec<(u16, u32)> {
+        encoded
+            .chunks_exact(6)
+            .map(|pair| {
+                (
+                    u16::from_le_bytes([pair[0], pair[1]]),
+                    u32::from_le_bytes([pair[2], pair[3], pair[4], pair[5]]),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn rle_round_trips_mixed_chunk() {
+        let voxels: Vec<u32> = (0..CHUNK_VOLUME as u32)
+            .map(|i| (i / 7) % 5 * 1000)
+            .collect();
+        let encoded = encode_rle(&voxels);
+        assert_eq!(decode_rle(&encoded).unwrap(), voxels);
+    }
+
+    #[test]
+    fn rle_round_trips_uniform_chunk() {
+        let voxels = vec![42; CHUNK_VOLUME];
+        let encoded = encode_rle(&voxels);
+        assert_eq!(runs(&encoded), [(CHUNK_VOLUME as u16, 42)]);
+        assert_eq!(decode_rle(&encoded).unwrap(), voxels);
+    }
+
+    #[test]
+    fn rle_splits_runs_longer_than_u16() {
+        let voxels = vec![3; u16::MAX as usize * 2 + 10];
+        let encoded = encode_rle(&voxels);
+        assert_eq!(runs(&encoded), [(u16::MAX, 3), (u16::MAX, 3), (10, 3)]);
+        // More voxels than a chunk holds
+        assert!(decode_rle(&encoded).is_err());
+    }
+
+    #[test]
+    fn rle_rejects_truncated_input() {
+        let encoded = encode_rle(&vec![1; CHUNK_VOLUME]);
+        assert!(decode_rle(&encoded[..encoded.len() - 1]).is_err());
+        assert!(decode_rle(&[]).is_err());
+    }
+
+    #[test]
+    fn rle_rejects_incomplete_chunk() {
+        let encoded = encode_rle(&vec![1; CHUNK_VOLUME - 1]);
+        assert!(decode_rle(&encoded).is_err());
+    }
+
+    #[test]
+    fn rle_rejects_block_ids_over_limit() {
+        let encoded = encode_rle(&vec![MAX_BLOCK_ID + 1; CHUNK_VOLUME]);
+        assert!(decode_rle(&encoded).is_err());
+    }
+
+    #[test]
+    fn rle_rejects_overflowing_runs() {
+        let mut encoded = encode_rle(&vec![1; CHUNK_VOLUME]);
+        encoded.extend_from_slice(&1u16.to_le_bytes());
+        encoded.extend_from_slice(&2u32.to_le_bytes());
+        assert!(decode_rle(&encoded).is_err());
+    }
+}
@@ -0,0 +1,313 @@
+use std::{collections::HashSet, path::PathBuf};
+
+use ash::vk;
+use log::*;
+use nalgebra::{Point3, Vector3};
+
+use crate::{
+    core::world::{CHUNK_SIZE, ChunkedWorld, WORLD_CHUNKS},
+    vulkan::context::VulkanContext,
+};
+
+pub struct StreamingSettings {
+    /// Horizontal distance in chunks kept resident around the camera, at most
+    /// WORLD_CHUNKS / 2 - 1 so the window never wraps onto itself.
+    pub radius: i32,
+    /// Resident chunk layers, `min_chunk_y..max_chunk_y`.
+    pub min_chunk_y: i32,
+    pub max_chunk_y: i32,
+    /// Chunks generated per `update` call.
+    pub chunks_per_update: usize,
+    /// Region directory chunks are loaded from before falling back to the
+    /// generator. Edited chunks are saved there when they are evicted.
+    pub save_dir: Option<PathBuf>,
+}
+
+impl Default for StreamingSettings {
+    fn default() -> Self {
+        Self {
+            radius: 8,
+            min_chunk_y: 0,
+            max_chunk_y: 8,
+            chunks_per_update: 16,
+            save_dir: None,
+        }
+    }
+}
+
+/// Keeps a camera-centred set of chunks resident in a ChunkedWorld. The window
+/// origin follows the camera, missing chunks are loaded or generated nearest
+/// first a few at a time and chunks outside the radius are evicted.
+pub struct WorldStreamer {
+    pub settings: StreamingSettings,
+    center: Option<Vector3<i32>>,
+    generated: HashSet<Vector3<i32>>,
+    pending: Vec<Vector3<i32>>,
+}
+
+impl WorldStreamer {
+    pub fn new(settings: StreamingSettings) -> Self {
+        let max_radius = WORLD_CHUNKS as i32 / 2 - 1;
+        let radius = settings.radius.clamp(0, max_radius);
+        Self {
+            settings: StreamingSettings { radius, ..settings },
+            center: None,
+            generated: HashSet::new(),
+            pending: Vec::new(),
+        }
+    }
+
+    /// Chunks still waiting for generation.
+    pub fn pending_count(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Whether `update` would touch the world for this camera position.
+    pub fn needs_update(&self, camera_position: Point3<f32>) -> bool {
+        !self.pending.is_empty() || self.center != Some(chunk_of(camera_position))
+    }
+
+    pub fn update(
+        &mut self,
+        context: &VulkanContext,
+        world: &mut ChunkedWorld,
+        camera_position: Point3<f32>,
+    ) -> Result<(), vk::Result> {
+        let center = chunk_of(camera_position);
+        if self.center != Some(center) {
+            self.recenter(context, world, center)?;
+        }
+
+        let count = self.settings.chunks_per_update.min(self.pending.len());
+        if count == 0 {
+            return Ok(());
+        }
+        // Pending is sorted farthest first so the nearest chunks pop off the end
+        let batch = self.pending.split_off(self.pending.len() - count);
+        let loaded = match &self.settings.save_dir {
+            Some(dir) => world
+                .load_chunks(context, dir, &batch)
+                .unwrap_or_else(|err| {
+                    warn!("Unable to load chunks from {}: {}", dir.display(), err);
+                    HashSet::new()
+                }),
+            None => HashSet::new(),
+        };
+        let missing: Vec<Vector3<i32>> = batch
+            .iter()
+            .copied()
+            .filter(|chunk| !loaded.contains(chunk))
+            .collect();
+        world.generate_chunks(context, &missing)?;
+        self.generated.extend(batch);
+        Ok(())
+    }
+
+    /// Forgets the streaming state after the world was replaced, e.g. by
+    /// `ChunkedWorld::load`. `present` chunks are kept, the rest of the range
+    /// streams in again on the next update.
+    pub fn reset(&mut self, present: impl IntoIterator<Item = Vector3<i32>>) {
+        self.center = None;
+        self.pending.clear();
+        self.generated = present.into_iter().collect();
+    }
+
+    fn recenter(
+        &mut self,
+        context: &VulkanContext,
+        world: &mut ChunkedWorld,
+        center: Vector3<i32>,
+    ) -> Result<(), vk::Result> {
+        self.center = Some(center);
+        // Chunks that got into the world some other way, by the initial
+        // generation or an edit, are evicted alike
+        let resident: HashSet<Vector3<i32>> = world
+            .resident_chunks()
+            .into_iter()
+            .map(|(chunk, _)| chunk)
+            .collect();
+        let plan = plan_recenter(
+            &self.settings,
+            center,
+            &resident,
+            &world.modified,
+            &self.generated,
+        );
+
+        if let Some(dir) = &self.settings.save_dir {
+            // Edited chunks about to be evicted are written out while still resident
+            let leaving: Vec<Vector3<i32>> = plan
+                .evicted
+                .iter()
+                .copied()
+                .filter(|chunk| world.modified.contains(chunk))
+                .collect();
+            if let Err(err) = world.save_chunks(context, dir, &leaving) {
+                warn!("Unable to save chunks to {}: {}", dir.display(), err);
+            }
+        }
+        world.set_window_origin(plan.origin)?;
+        for chunk in plan.evicted {
+            if let Some(dir_index) = world.chunk_dir_index(chunk) {
+                world.free_chunk(dir_index)?;
+            }
+        }
+        self.generated = plan.streamed;
+        self.pending = plan.pending;
+        Ok(())
+    }
+}
+
+/// What recentring the streamed range on a chunk does, worked out without
+/// the GPU.
+#[derive(Debug)]
+struct RecenterPlan {
+    origin: Vector3<i32>,
+    /// Chunks leaving the range or the window, to be freed
+    evicted: Vec<Vector3<i32>>,
+    /// Chunks in range that are already there
+    streamed: HashSet<Vector3<i32>>,
+    /// Chunks in range still to load or generate, farthest first
+    pending: Vec<Vector3<i32>>,
+}
+
+/// Plans the window and chunks around `center` from the chunks resident in
+/// the world, the modified ones, and the ones streamed in so far.
+fn plan_recenter(
+    settings: &StreamingSettings,
+    center: Vector3<i32>,
+    resident: &HashSet<Vector3<i32>>,
+    modified: &HashSet<Vector3<i32>>,
+    generated: &HashSet<Vector3<i32>>,
+) -> RecenterPlan {
+    let half = WORLD_CHUNKS as i32 / 2;
+    let origin = Vector3::new(center.x - half, 0, center.z - half);
+    let window = 0..WORLD_CHUNKS as i32;
+    let keep = |chunk: &Vector3<i32>| {
+        (chunk.x - center.x).abs() <= settings.radius
+            && (chunk.z - center.z).abs() <= settings.radius
+            && (settings.min_chunk_y..settings.max_chunk_y).contains(&chunk.y)
+            && (chunk - origin).iter().all(|c| window.contains(c))
+    };
+
+    let known: HashSet<Vector3<i32>> = resident
+        .iter()
+        .chain(modified)
+        .chain(generated)
+        .copied()
+        .collect();
+    let mut evicted: Vec<Vector3<i32>> = known.into_iter().filter(|chunk| !keep(chunk)).collect();
+    evicted.sort_by_key(|chunk| (chunk.z, chunk.y, chunk.x));
+    let streamed: HashSet<Vector3<i32>> = resident
+        .iter()
+        .chain(generated)
+        .copied()
+        .filter(|chunk| keep(chunk))
+        .collect();
+
+    let radius = settings.radius;
+    let mut pending = Vec::new();
+    for z in center.z - radius..=center.z + radius {
+        for y in settings.min_chunk_y..settings.max_chunk_y {
+            for x in center.x - radius..=center.x + radius {
+                let chunk = Vector3::new(x, y, z);
+                if keep(&chunk) && !streamed.contains(&chunk) {
+                    pending.push(chunk);
+                }
+            }
+        }
+    }
+    pending.sort_by_key(|chunk| {
+        let offset = chunk - center;
+        std::cmp::Reverse(offset.x * offset.x + offset.z * offset.z)
+    });
+    RecenterPlan {
+        origin,
+        evicted,
+        streamed,
+        pending,
+    }
+}
+
+fn chunk_of(position: Point3<f32>) -> Vector3<i32> {
+    position
+        .coords
+        .map(|c| (c / CHUNK_SIZE as f32).floor() as i32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings() -> StreamingSettings {
+        StreamingSettings {
+            radius: 2,
+            min_chunk_y: 0,
+            max_chunk_y: 2,
+            ..Default::default()
+        }
+    }
+
+    fn set(chunks: &[[i32; 3]]) -> HashSet<Vector3<i32>> {
+        chunks.iter().map(|&c| Vector3::from(c)).collect()
+    }
+
+    #[test]
+    fn pends_the_range_nearest_last() {
+        let center = Vector3::new(10, 0, -4);
+        let plan = plan_recenter(&settings(), center, &set(&[]), &set(&[]), &set(&[]));
+        assert_eq!(plan.origin, Vector3::new(10 - 16, 0, -4 - 16));
+        // 5 x 5 columns of 2 layers
+        assert_eq!(plan.pending.len(), 50);
+        let distances: Vec<i32> = plan
+            .pending
+            .iter()
+            .map(|chunk| {
+                let offset = chunk - center;
+                offset.x * offset.x + offset.z * offset.z
+            })
+            .collect();
+        assert!(distances.is_sorted_by(|a, b| a >= b));
+        assert_eq!(*distances.last().unwrap(), 0);
+        assert!(plan.pending.iter().all(|chunk| (0..2).contains(&chunk.y)));
+    }
+
+    #[test]
+    fn keeps_what_is_already_streamed() {
+        let center = Vector3::zeros();
+        let generated = set(&[[0, 0, 0], [1, 1, -2]]);
+        let resident = set(&[[2, 0, 2]]);
+        let plan = plan_recenter(&settings(), center, &resident, &set(&[]), &generated);
+        assert!(plan.evicted.is_empty());
+        assert_eq!(plan.streamed, set(&[[0, 0, 0], [1, 1, -2], [2, 0, 2]]));
+        assert_eq!(plan.pending.len(), 47);
+        assert!(
+            plan.pending
+                .iter()
+                .all(|chunk| !plan.streamed.contains(chunk))
+        );
+    }
+
+    #[test]
+    fn evicts_everything_out_of_range() {
+        let center = Vector3::new(40, 0, 0);
+        // Out of the radius, out of the y range, out of the new window,
+        // and chunks the streamer never generated itself
+        let generated = set(&[[37, 0, 0], [40, 2, 0], [0, 0, 0], [41, 1, 1]]);
+        let resident = set(&[[40, -1, 0], [43, 0, 0], [42, 0, 2]]);
+        let modified = set(&[[0, 1, 0]]);
+        let plan = plan_recenter(&settings(), center, &resident, &modified, &generated);
+        assert_eq!(
+            plan.evicted.into_iter().collect::<HashSet<_>>(),
+            set(&[
+                [37, 0, 0],
+                [40, 2, 0],
+                [0, 0, 0],
+                [40, -1, 0],
+                [43, 0, 0],
+                [0, 1, 0]
+            ])
+        );
+        assert_eq!(plan.streamed, set(&[[41, 1, 1], [42, 0, 2]]));
+    }
+}
@@ -0,0 +1,358 @@
+use ash::vk;
+use nalgebra::Vector3;
+
+use crate::{
+    core::{
+        biome::{ColumnBiome, MAX_FILL_DEPTH, biome_at},
+        blocks,
+        features::{FeaturePlacer, decorate_voxel},
+        generator::GeneratorSettings,
+        world::{CHUNK_SIZE, CHUNK_VOLUME, ChunkedWorld, local_index},
+    },
+    vulkan::context::VulkanContext,
+};
+
+// CPU port of generate.comp. Every operation mirrors the shader in the same
+// order with f32 math so both produce the same block for every voxel; keep the
+// two in sync. `hash_int` drives feature placement, `hash` is the value hash
+// the generator has used from the start.
+
+pub(crate) fn hash_int(p: Vector3<i32>, seed: u32) -> u32 {
+    let mut h = (p.x as u32).wrapping_mul(0x8da6b343)
+        ^ (p.y as u32).wrapping_mul(0xd8163841)
+        ^ (p.z as u32).wrapping_mul(0xcb1ab31f);
+    h = h.wrapping_add(seed.wrapping_mul(0x9e3779b9));
+    h ^= h >> 16;
+    h = h.wrapping_mul(0x7feb352d);
+    h ^= h >> 15;
+    h = h.wrapping_mul(0x846ca68b);
+    h ^= h >> 16;
+    h
+}
+
+/// Lattice shift of a seed, none for seed 0 so unseeded worlds keep their
+/// terrain.
+fn seed_offset(seed: u32) -> Vector3<i32> {
+    let h = hash_int(Vector3::zeros(), seed);
+    Vector3::new(h & 1023, (h >> 10) & 1023, (h >> 20) & 1023).cast::<i32>()
+}
+
+fn fract(x: f32) -> f32 {
+    x - x.floor()
+}
+
+// The shader's literal, not quite FRAC_1_PI
+#[allow(clippy::approx_constant)]
+fn hash(i: Vector3<i32>, seed: u32) -> f32 {
+    let p = (i + seed_offset(seed))
+        .cast::<f32>()
+        .map(|c| fract(c * 0.3183099 + 0.1) * 17.0);
+    fract(p.x * p.y * p.z * (p.x + p.y + p.z))
+}
+
+/// GLSL's mix().
+pub(crate) fn lerp(a: f32, b: f32, t: f32) -> f32 {
+    a * (1.0 - t) + b * t
+}
+
+pub fn noise(x: Vector3<f32>, seed: u32) -> f32 {
+    let fl = x.map(f32::floor);
+    let i = fl.map(|c| c as i32);
+    let f = (x - fl).map(|f| f * f * (3.0 - 2.0 * f));
+    let h = |dx, dy, dz| hash(i + Vector3::new(dx, dy, dz), seed);
+
+    lerp(
+        lerp(
+            lerp(h(0, 0, 0), h(1, 0, 0), f.x),
+            lerp(h(0, 1, 0), h(1, 1, 0), f.x),
+            f.y,
+        ),
+        lerp(
+            lerp(h(0, 0, 1), h(1, 0, 1), f.x),
+            lerp(h(0, 1, 1), h(1, 1, 1), f.x),
+            f.y,
+        ),
+        f.z,
+    )
+}
+
+pub fn fbm(x: Vector3<f32>, settings: &GeneratorSettings) -> f32 {
+    let mut v = 0.0;
+    let mut p = x;
+    let mut a = 0.5;
+    let shift = Vector3::repeat(100.0);
+    for _ in 0..settings.octaves {
+        v += a * noise(p, settings.seed);
+        p = p * settings.lacunarity + shift;
+        a *= settings.gain;
+    }
+    v
+}
+
+/// Density at a voxel of a column with the given biome, solid above 0.
+pub fn density(settings: &GeneratorSettings, column: &ColumnBiome, pos: Vector3<i32>) -> f32 {
+    let noise_pos = pos.cast::<f32>() * settings.scale;
+    let height_bias = (pos.y as f32 - column.base_height) * column.inv_height_falloff;
+    fbm(noise_pos, settings) - height_bias
+}
+
+/// Whether the cave pass hollows out the voxel at `pos`.
+pub fn is_cave(settings: &GeneratorSettings, pos: Vector3<i32>) -> bool {
+    let caves = &settings.caves;
+    if pos.y >= caves.max_height {
+        return false;
+    }
+    let p = pos.cast::<f32>();
+    let cheese = noise(p * caves.cheese_scale, settings.seed.wrapping_add(3));
+    if cheese > caves.cheese_threshold {
+        return true;
+    }
+    // Tunnels run mostly sideways, so the worm fields change faster along y
+    let worm_pos = Vector3::new(p.x, p.y * 2.0, p.z) * caves.worm_scale;
+    let worm_a = noise(worm_pos, settings.seed.wrapping_add(4)) - 0.5;
+    let worm_b = noise(worm_pos, settings.seed.wrapping_add(5)) - 0.5;
+    worm_a.abs() < caves.worm_radius && worm_b.abs() < caves.worm_radius
+}
+
+/// Generates `count` blocks of the column at `(x, z)` starting at height
+/// `y_min`, calling `emit(offset, block)` from the top down. Like generate.comp
+/// it starts MAX_FILL_DEPTH voxels higher to find the surface.
+pub fn generate_column<F: FnMut(usize, u32)>(
+    settings: &GeneratorSettings,
+    x: i32,
+    z: i32,
+    y_min: i32,
+    count: usize,
+    mut emit: F,
+) {
+    let layers = &settings.layers;
+    let column = biome_at(settings, x, z);
+    let biome = &settings.biomes[column.biome as usize];
+    let fill_depth = biome.fill_depth.min(MAX_FILL_DEPTH - 1);
+
+    // Solid voxels directly above, capped; the scan starts as if buried
+    let mut depth = MAX_FILL_DEPTH;
+    for offset in (0..count + MAX_FILL_DEPTH as usize).rev() {
+        let y = y_min + offset as i32;
+        let mut block_id = blocks::AIR;
+        if density(settings, &column, Vector3::new(x, y, z)) > 0.0 {
+            block_id = if depth == 0 {
+                biome.surface_block
+            } else if depth <= fill_depth {
+                biome.fill_block
+            } else {
+                layers.stone_block
+            };
+            depth = (depth + 1).min(MAX_FILL_DEPTH);
+            if y >= layers.bedrock_height && is_cave(settings, Vector3::new(x, y, z)) {
+                block_id = blocks::AIR;
+            }
+        } else {
+            if y < settings.sea_level {
+                block_id = layers.fluid_block;
+            }
+            depth = 0;
+        }
+
+        if y < layers.bedrock_height {
+            block_id = layers.bedrock_block;
+        }
+        if offset < count {
+            emit(offset, block_id);
+        }
+    }
+}
+
+/// Block the generator places at a world voxel position, features included.
+pub fn block_at(
+    settings: &GeneratorSettings,
+    placer: &mut FeaturePlacer,
+    world_pos: Vector3<i32>,
+) -> u32 {
+    let mut block_id = blocks::AIR;
+    generate_column(
+        settings,
+        world_pos.x,
+        world_pos.z,
+        world_pos.y,
+        1,
+        |_, block| {
+            block_id = block;
+        },
+    );
+    let chunk = world_pos.map(|c| c.div_euclid(CHUNK_SIZE as i32));
+    let features = placer.chunk_features(settings, chunk);
+    decorate_voxel(&features, world_pos, block_id, settings.layers.stone_block)
+}
+
+/// Generates a whole chunk on the CPU, laid out like a pool slot, decorated
+/// with the features `placer` finds for it.
+pub fn generate_chunk(
+    settings: &GeneratorSettings,
+    placer: &mut FeaturePlacer,
+    chunk: Vector3<i32>,
+) -> Box<[u32; CHUNK_VOLUME]> {
+    let origin = chunk * CHUNK_SIZE as i32;
+    let mut voxels = vec![0; CHUNK_VOLUME];
+    for z in 0..CHUNK_SIZE {
+        for x in 0..CHUNK_SIZE {
+            let (wx, wz) = (origin.x + x as i32, origin.z + z as i32);
+            generate_column(settings, wx, wz, origin.y, CHUNK_SIZE, |y, block| {
+                voxels[local_index(x, y, z)] = block;
+            });
+        }
+    }
+
+    // Feature by feature over its clipped bounds, the same per voxel order as
+    // decorate.comp
+    let chunk_max = origin.add_scalar(CHUNK_SIZE as i32 - 1);
+    for feature in placer.chunk_features(settings, chunk) {
+        let (min, max) = feature.bounds();
+        let (min, max) = (min.sup(&origin) - origin, max.inf(&chunk_max) - origin);
+        for z in min.z..=max.z {
+            for y in min.y..=max.y {
+                for x in min.x..=max.x {
+                    let index = local_index(x as usize, y as usize, z as usize);
+                    let pos = origin + Vector3::new(x, y, z);
+                    if let Some(block) =
+                        feature.block_at(pos, voxels[index], settings.layers.stone_block)
+                    {
+                        voxels[index] = block;
+                    }
+                }
+            }
+        }
+    }
+    voxels
+        .into_boxed_slice()
+        .try_into()
+        .expect("chunk has CHUNK_VOLUME voxels")
+}
+
+/// A chunk whose GPU and CPU generation disagree.
+#[derive(Debug)]
+pub struct ChunkMismatch {
+    pub chunk: Vector3<i32>,
+    pub differing_voxels: usize,
+    /// First differing voxel as (local position, gpu block, cpu block)
+    pub first: (Vector3<usize>, u32, u32),
+}
+
+impl ChunkedWorld {
+    /// Regenerates `chunks` with generate.comp and compares each against
+    /// `generate_chunk`. Returns the chunks that differ; edits to them are lost.
+    pub fn verify_generator(
+        &mut self,
+        context: &VulkanContext,
+        chunks: &[Vector3<i32>],
+    ) -> Result<Vec<ChunkMismatch>, vk::Result> {
+        self.generate_chunks(context, chunks)?;
+
+        let mut placer = FeaturePlacer::default();
+        let mut mismatches = Vec::new();
+        for &chunk in chunks.iter().filter(|&&chunk| self.in_window(chunk)) {
+            let gpu = self.read_chunk(context, chunk)?;
+            let cpu = generate_chunk(&self.generator_settings, &mut placer, chunk);
+
+            let mut differing = gpu
+                .iter()
+                .zip(cpu.iter())
+                .enumerate()
+                .filter(|(_, (g, c))| g != c);
+            let Some((index, (&gpu_block, &cpu_block))) = differing.next() else {
+                continue;
+            };
+            let local = Vector3::new(
+                index % CHUNK_SIZE,
+                (index / CHUNK_SIZE) % CHUNK_SIZE,
+                index / (CHUNK_SIZE * CHUNK_SIZE),
+            );
+            mismatches.push(ChunkMismatch {
+                chunk,
+                differing_voxels: 1 + differing.count(),
+                first: (local, gpu_block, cpu_block),
+            });
+        }
+        Ok(mismatches)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seed_zero_keeps_the_lattice() {
+        assert_eq!(seed_offset(0), Vector3::zeros());
+        assert_ne!(seed_offset(1), Vector3::zeros());
+    }
+
+    #[test]
+    #[allow(clippy::approx_constant)]
+    fn hash_matches_the_original_shader() {
+        // fract(1.7^3 * 5.1), the float hash of the origin
+        let p = fract(0.0 * 0.3183099 + 0.1) * 17.0;
+        assert_eq!(hash(Vector3::zeros(), 0), fract(p * p * p * (p + p + p)));
+        assert_eq!(hash(Vector3::zeros(), 0), f32::from_bits(0x3d669e00));
+    }
+
+    #[test]
+    fn hash_is_deterministic_and_in_unit_range() {
+        for seed in [0, 1, 0xdead_beef] {
+            for p in [
+                Vector3::new(0, 0, 0),
+                Vector3::new(-17, 300, 5),
+                Vector3::new(1 << 20, -9, 42),
+            ] {
+                let h = hash(p, seed);
+                assert_eq!(h.to_bits(), hash(p, seed).to_bits());
+                assert!((0.0..1.0).contains(&h), "{} at {:?}", h, p);
+            }
+        }
+    }
+
+    #[test]
+    fn noise_known_values() {
+        let cases = [
+            (Vector3::new(0.0, 0.0, 0.0), 0, 0x3d669e00),
+            (Vector3::new(1.0, 2.0, 3.0), 0, 0x3ea85000),
+            (Vector3::new(-5.0, 7.0, 11.0), 0, 0x3f138000),
+            (Vector3::new(0.25, 0.5, 0.75), 0, 0x3ec40704),
+            (Vector3::new(-3.7, 12.1, 40.9), 0, 0x3e5dc39a),
+            (Vector3::new(-3.7, 12.1, 40.9), 1, 0x3e9e455f),
+        ];
+        for (p, seed, bits) in cases {
+            assert_eq!(
+                noise(p, seed).to_bits(),
+                bits,
+                "noise at {:?} seed {}",
+                p,
+                seed
+            );
+        }
+    }
+
+    #[test]
+    fn noise_hits_the_hash_on_lattice_points() {
+        for p in [Vector3::new(3, -4, 8), Vector3::new(-100, 0, 77)] {
+            assert_eq!(noise(p.cast::<f32>(), 2), hash(p, 2));
+        }
+    }
+
+    #[test]
+    fn noise_is_continuous() {
+        let p = Vector3::new(4.0, -2.5, 9.25);
+        let step = Vector3::new(1e-3, 0.0, 0.0);
+        assert!((noise(p + step, 0) - noise(p - step, 0)).abs() < 0.01);
+        assert_ne!(noise(p, 0), noise(p, 7));
+    }
+
+    #[test]
+    fn generation_is_deterministic() {
+        let settings = GeneratorSettings::default();
+        let chunk = Vector3::new(10, 2, 10);
+        let a = generate_chunk(&settings, &mut FeaturePlacer::default(), chunk);
+        let b = generate_chunk(&settings, &mut FeaturePlacer::default(), chunk);
+        assert_eq!(a, b);
+    }
+}
@@ -0,0 +1,621 @@
+use std::collections::HashMap;
+
+use ash::vk;
+use log::*;
+use nalgebra::Vector3;
+
+use crate::{
+    core::{
+        palette::MAX_BLOCK_ID,
+        world::{CHUNK_SIZE, CHUNK_VOLUME, ChunkedWorld, WORLD_CHUNKS, local_index},
+    },
+    vulkan::{buffer::Buffer, context::VulkanContext},
+};
+
+/// Children per axis of a tree node, 4^3 = 64 per node.
+pub const TREE_BRANCHING: usize = 4;
+/// Node levels between the root and the voxels, 4^5 voxels span the window.
+pub const TREE_DEPTH: usize = 5;
+/// Edge of the root node in voxels.
+pub const TREE_SIZE: usize = WORLD_CHUNKS * CHUNK_SIZE;
+
+/// The node's children are voxels, stored in the voxel array.
+pub const NODE_LEAF: u32 = 1 << 0;
+/// The whole node is one block, kept in the bits above 8 of `info`.
+pub const NODE_UNIFORM: u32 = 1 << 1;
+
+/// Chunks read back from the pool per submit while building.
+const READBACK_BATCH: usize = 128;
+
+/// Edge of the cells a chunk's subtrees start at, the largest nodes that fit
+/// in one chunk.
+const CELL_SIZE: usize = TREE_SIZE / TREE_BRANCHING.pow(3);
+/// Cells per chunk along each axis.
+const CHUNK_CELLS: usize = CHUNK_SIZE / CELL_SIZE;
+const _: () =
+    assert!(CHUNK_SIZE.is_multiple_of(CELL_SIZE) && CELL_SIZE * TREE_BRANCHING > CHUNK_SIZE);
+
+/// Node as laid out in raytrace.comp's tree buffer.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) struct TreeNode {
+    /// One bit per child that isn't all air, children ordered x, then y, then z
+    child_mask: [u32; 2],
+    /// Index of the first present child, the others follow in mask order. Leaf
+    /// nodes index the voxel array instead of the node array.
+    first_child: u32,
+    /// NODE_* flags, the block of uniform nodes above bit 8
+    info: u32,
+}
+
+impl TreeNode {
+    fn uniform(block: u32) -> Self {
+        Self {
+            info: NODE_UNIFORM | (block << 8),
+            ..Default::default()
+        }
+    }
+
+    fn has_child(&self, index: usize) -> bool {
+        self.child_mask[index / 32] & (1 << (index % 32)) != 0
+    }
+
+    /// Position of a present child among the present ones.
+    fn rank(&self, index: usize) -> u32 {
+        let below = |word: usize| {
+            let bits = index.saturating_sub(word * 32).min(32);
+            let mask = if bits == 32 {
+                u32::MAX
+            } else {
+                (1u32 << bits) - 1
+            };
+            (self.child_mask[word] & mask).count_ones()
+        };
+        below(0) + below(1)
+    }
+}
+
+/// A cell of the tree while it is built. Nodes are placed in the node array
+/// by their parent, so that siblings end up next to each other.
+#[derive(Clone, Copy, Debug)]
+enum Cell {
+    Empty,
+    Uniform(u32),
+    Node(TreeNode),
+}
+
+/// The subtrees of one chunk's cells, built on their own with node and
+/// voxel indices local to the chunk. Assembling the tree only offsets them.
+#[derive(Clone, Debug)]
+struct ChunkTree {
+    cells: [Cell; CHUNK_CELLS.pow(3)],
+    nodes: Vec<TreeNode>,
+    voxels: Vec<u16>,
+}
+
+impl ChunkTree {
+    /// Subtrees of a chunk's voxels, None when it is all air.
+    fn build(data: &[u32]) -> Option<Self> {
+        let mut nodes = Vec::new();
+        let mut voxels = Vec::new();
+        let cells: [Cell; CHUNK_CELLS.pow(3)] = std::array::from_fn(|index| {
+            let cell = Vector3::new(
+                index % CHUNK_CELLS,
+                (index / CHUNK_CELLS) % CHUNK_CELLS,
+                index / (CHUNK_CELLS * CHUNK_CELLS),
+            );
+            build_cell(&mut nodes, &mut voxels, data, cell * CELL_SIZE, CELL_SIZE)
+        });
+        if cells.iter().all(|cell| matches!(cell, Cell::Empty)) {
+            return None;
+        }
+        Some(Self {
+            cells,
+            nodes,
+            voxels,
+        })
+    }
+}
+
+/// Sparse 64-tree over the resident window: every node splits into 4^3
+/// children, empty children take no memory and uniform ones collapse into a
+/// single node. An alternative to the chunk directory and pool for
+/// raytrace.comp, assembled from a ChunkedWorld's chunks by TreeChunks.
+///
+/// Voxels are stored as 16 bits, which holds every id up to MAX_BLOCK_ID.
+#[derive(Clone, Debug)]
+pub struct VoxelTree {
+    /// The root is the first node
+    nodes: Vec<TreeNode>,
+    voxels: Vec<u16>,
+    /// Chunk the root's corner sits on
+    window_origin: Vector3<i32>,
+}
+
+impl VoxelTree {
+    /// Builds the tree of the window at `window_origin` from the voxels of its
+    /// chunks. Missing chunks are air.
+    pub fn build(window_origin: Vector3<i32>, chunks: &HashMap<Vector3<i32>, Vec<u32>>) -> Self {
+        let chunks = chunks
+            .iter()
+            .filter_map(|(&chunk, data)| Some((chunk, ChunkTree::build(data)?)))
+            .collect();
+        Self::assemble(window_origin, &chunks)
+    }
+
+    /// Joins the subtrees of the chunks inside the window under the nodes
+    /// above them.
+    fn assemble(window_origin: Vector3<i32>, chunks: &HashMap<Vector3<i32>, ChunkTree>) -> Self {
+        let mut tree = Self {
+            nodes: vec![TreeNode::default()],
+            voxels: Vec::new(),
+            window_origin,
+        };
+        let mut offsets = HashMap::new();
+        tree.nodes[0] = match tree.assemble_cell(chunks, &mut offsets, Vector3::zeros(), TREE_SIZE)
+        {
+            Cell::Empty => TreeNode::default(),
+            Cell::Uniform(block) => TreeNode::uniform(block),
+            Cell::Node(node) => node,
+        };
+        tree
+    }
+
+    /// Cell of `size` voxels at `origin` in the window. A chunk's nodes and
+    /// voxels are copied over the first time one of its cells is used, and
+    /// `offsets` remembers where they went.
+    fn assemble_cell(
+        &mut self,
+        chunks: &HashMap<Vector3<i32>, ChunkTree>,
+        offsets: &mut HashMap<Vector3<i32>, (u32, u32)>,
+        origin: Vector3<usize>,
+        size: usize,
+    ) -> Cell {
+        let first_chunk = self.window_origin + (origin / CHUNK_SIZE).cast::<i32>();
+        if size == CELL_SIZE {
+            let Some(chunk) = chunks.get(&first_chunk) else {
+                return Cell::Empty;
+            };
+            let cell = origin.map(|c| c % CHUNK_SIZE / CELL_SIZE);
+            let cell =
+                chunk.cells[cell.x + cell.y * CHUNK_CELLS + cell.z * CHUNK_CELLS * CHUNK_CELLS];
+            let Cell::Node(node) = cell else {
+                return cell;
+            };
+            let (node_offset, voxel_offset) = *offsets.entry(first_chunk).or_insert_with(|| {
+                let node_offset = self.nodes.len() as u32;
+                let voxel_offset = self.voxels.len() as u32;
+                self.nodes.extend(
+                    chunk
+                        .nodes
+                        .iter()
+                        .map(|&node| relocate(node, node_offset, voxel_offset)),
+                );
+                self.voxels.extend_from_slice(&chunk.voxels);
+                (node_offset, voxel_offset)
+            });
+            return Cell::Node(relocate(node, node_offset, voxel_offset));
+        }
+
+        let span = (size / CHUNK_SIZE) as i32;
+        let any_resident = (0..span).any(|z| {
+            (0..span).any(|y| {
+                (0..span).any(|x| chunks.contains_key(&(first_chunk + Vector3::new(x, y, z))))
+            })
+        });
+        if !any_resident {
+            return Cell::Empty;
+        }
+
+        let child_size = size / TREE_BRANCHING;
+        let children = (0..TREE_BRANCHING.pow(3))
+            .map(|index| {
+                let child_origin = origin + child_offset(index) * child_size;
+                self.assemble_cell(chunks, offsets, child_origin, child_size)
+            })
+            .collect();
+        join_children(&mut self.nodes, children)
+    }
+
+    /// Block at a world voxel, the same walk raytrace.comp does. Air outside
+    /// the window.
+    pub fn get(&self, pos: Vector3<i32>) -> u32 {
+        let local = pos - self.window_origin * CHUNK_SIZE as i32;
+        if local.iter().any(|&c| c < 0 || c >= TREE_SIZE as i32) {
+            return 0;
+        }
+        let local = local.map(|c| c as usize);
+        let mut node = self.nodes[0];
+        let mut size = TREE_SIZE;
+        for _ in 0..TREE_DEPTH {
+            if node.info & NODE_UNIFORM != 0 {
+                return node.info >> 8;
+            }
+            size /= TREE_BRANCHING;
+            let child = local.map(|c| (c / size) % TREE_BRANCHING);
+            let index =
+                child.x + child.y * TREE_BRANCHING + child.z * TREE_BRANCHING * TREE_BRANCHING;
+            if !node.has_child(index) {
+                return 0;
+            }
+            let child_index = (node.first_child + node.rank(index)) as usize;
+            if node.info & NODE_LEAF != 0 {
+                return self.voxels[child_index] as u32;
+            }
+            node = self.nodes[child_index];
+        }
+        0
+    }
+
+    pub fn window_origin(&self) -> Vector3<i32> {
+        self.window_origin
+    }
+
+    pub fn node_count(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// Bytes of the node and voxel arrays on the GPU.
+    pub fn memory_bytes(&self) -> usize {
+        self.nodes.len() * std::mem::size_of::<TreeNode>()
+            + self.voxels.len().next_multiple_of(2) * 2
+    }
+}
+
+/// Subtrees of the resident chunks, kept between builds so that a change to
+/// the world only rebuilds the chunks it touched.
+#[derive(Default)]
+pub struct TreeChunks {
+    chunks: HashMap<Vector3<i32>, ChunkTree>,
+    /// World revision the subtrees are current with, None before the first
+    /// update
+    revision: Option<u64>,
+}
+
+impl TreeChunks {
+    /// Reads back the chunks `world` changed since the last update and
+    /// rebuilds their subtrees. Chunks that left the window are dropped.
+    pub fn update(
+        &mut self,
+        context: &VulkanContext,
+        world: &ChunkedWorld,
+    ) -> Result<(), vk::Result> {
+        if self.revision == Some(world.revision) {
+            return Ok(());
+        }
+        self.chunks.retain(|&chunk, _| world.in_window(chunk));
+        let changed = world.changed_since(self.revision);
+        for batch in changed.chunks(READBACK_BATCH) {
+            let entries: Vec<u32> = batch
+                .iter()
+                .map(|&chunk| {
+                    world
+                        .chunk_dir_index(chunk)
+                        .map_or(0, |dir_index| world.directory[dir_index])
+                })
+                .collect();
+            let voxels = world.read_chunks(context, &entries)?;
+            for (&chunk, data) in batch.iter().zip(voxels.chunks_exact(CHUNK_VOLUME)) {
+                match ChunkTree::build(data) {
+                    Some(tree) => self.chunks.insert(chunk, tree),
+                    None => self.chunks.remove(&chunk),
+                };
+            }
+        }
+        self.revision = Some(world.revision);
+        Ok(())
+    }
+
+    /// Tree of the window at `window_origin` from the current subtrees.
+    pub fn tree(&self, window_origin: Vector3<i32>) -> VoxelTree {
+        VoxelTree::assemble(window_origin, &self.chunks)
+    }
+}
+
+/// Builds the cell of `size` voxels at `origin` inside a chunk's voxels,
+/// pushing the nodes and voxels below it.
+fn build_cell(
+    nodes: &mut Vec<TreeNode>,
+    voxels: &mut Vec<u16>,
+    data: &[u32],
+    origin: Vector3<usize>,
+    size: usize,
+) -> Cell {
+    let child_size = size / TREE_BRANCHING;
+    if child_size > 1 {
+        let children = (0..TREE_BRANCHING.pow(3))
+            .map(|index| {
+                let child_origin = origin + child_offset(index) * child_size;
+                build_cell(nodes, voxels, data, child_origin, child_size)
+            })
+            .collect();
+        return join_children(nodes, children);
+    }
+
+    let mut mask = [0u32; 2];
+    let mut blocks = Vec::new();
+    for index in 0..TREE_BRANCHING.pow(3) {
+        let p = origin + child_offset(index);
+        let block = data[local_index(p.x, p.y, p.z)];
+        if block != 0 {
+            mask[index / 32] |= 1 << (index % 32);
+            blocks.push(block);
+        }
+    }
+    if blocks.is_empty() {
+        return Cell::Empty;
+    }
+    if blocks.len() == TREE_BRANCHING.pow(3) && blocks.iter().all(|&b| b == blocks[0]) {
+        return Cell::Uniform(blocks[0]);
+    }
+    let first_child = voxels.len() as u32;
+    voxels.extend(
+        blocks
+            .iter()
+            .map(|&block| u16::try_from(block).unwrap_or(MAX_BLOCK_ID as u16)),
+    );
+    Cell::Node(TreeNode {
+        child_mask: mask,
+        first_child,
+        info: NODE_LEAF,
+    })
+}
+
+/// Cell over `children`, all TREE_BRANCHING^3 of them in order. Present
+/// children are pushed next to each other, unless they are all the same block.
+fn join_children(nodes: &mut Vec<TreeNode>, children: Vec<Cell>) -> Cell {
+    let mut mask = [0u32; 2];
+    for (index, cell) in children.iter().enumerate() {
+        if !matches!(cell, Cell::Empty) {
+            mask[index / 32] |= 1 << (index % 32);
+        }
+    }
+    if let [Cell::Uniform(block), ..] = children[..]
+        && children
+            .iter()
+            .all(|cell| matches!(cell, Cell::Uniform(b) if *b == block))
+    {
+        return Cell::Uniform(block);
+    }
+
+    let first_child = nodes.len() as u32;
+    nodes.extend(children.into_iter().filter_map(|cell| match cell {
+        Cell::Empty => None,
+        Cell::Uniform(block) => Some(TreeNode::uniform(block)),
+        Cell::Node(node) => Some(node),
+    }));
+    if nodes.len() == first_child as usize {
+        return Cell::Empty;
+    }
+    Cell::Node(TreeNode {
+        child_mask: mask,
+        first_child,
+        info: 0,
+    })
+}
+
+/// A chunk-local node moved to node `node_offset` and voxel `voxel_offset`
+/// of the assembled tree.
+fn relocate(node: TreeNode, node_offset: u32, voxel_offset: u32) -> TreeNode {
+    let offset = if node.info & NODE_LEAF != 0 {
+        voxel_offset
+    } else if node.info & NODE_UNIFORM != 0 {
+        0
+    } else {
+        node_offset
+    };
+    TreeNode {
+        first_child: node.first_child + offset,
+        ..node
+    }
+}
+
+/// Offset of child `index` inside its parent, in children.
+fn child_offset(index: usize) -> Vector3<usize> {
+    Vector3::new(
+        index % TREE_BRANCHING,
+        (index / TREE_BRANCHING) % TREE_BRANCHING,
+        index / (TREE_BRANCHING * TREE_BRANCHING),
+    )
+}
+
+/// GPU copy of a VoxelTree, rebuilt when the world changes.
+pub struct TreeBuffers {
+    pub node_buffer: Buffer,
+    pub voxel_buffer: Buffer,
+    /// World revision the buffers were built from, None before the first build
+    pub revision: Option<u64>,
+    /// Nodes and bytes of the last upload
+    pub node_count: usize,
+    pub memory_bytes: usize,
+}
+
+impl TreeBuffers {
+    /// Buffers of an all air tree.
+    pub fn new(context: &VulkanContext) -> Result<Self, vk::Result> {
+        let (node_buffer, voxel_buffer) = Self::create(context, &[TreeNode::default()], &[0])?;
+        Ok(Self {
+            node_buffer,
+            voxel_buffer,
+            revision: None,
+            node_count: 0,
+            memory_bytes: 0,
+        })
+    }
+
+    fn create(
+        context: &VulkanContext,
+        nodes: &[TreeNode],
+        voxels: &[u16],
+    ) -> Result<(Buffer, Buffer), vk::Result> {
+        let node_buffer = Buffer::device_local_with_data(
+            context,
+            vk::BufferUsageFlags::STORAGE_BUFFER,
+            "Tree Nodes",
+            nodes,
+        )?;
+        // Read as uints by the shader
+        let mut padded = voxels.to_vec();
+        padded.resize(voxels.len().next_multiple_of(2).max(2), 0);
+        let voxel_buffer = Buffer::device_local_with_data(
+            context,
+            vk::BufferUsageFlags::STORAGE_BUFFER,
+            "Tree Voxels",
+            &padded,
+        )?;
+        Ok((node_buffer, voxel_buffer))
+    }
+
+    /// Replaces the buffers with `tree`'s. The old ones must no longer be in
+    /// use, and descriptors pointing at them need an update.
+    pub fn upload(
+        &mut self,
+        context: &VulkanContext,
+        tree: &VoxelTree,
+        revision: u64,
+    ) -> Result<(), vk::Result> {
+        let (node_buffer, voxel_buffer) = Self::create(context, &tree.nodes, &tree.voxels)?;
+        self.node_buffer.destroy(context);
+        self.voxel_buffer.destroy(context);
+        self.node_buffer = node_buffer;
+        self.voxel_buffer = voxel_buffer;
+        self.revision = Some(revision);
+        self.node_count = tree.node_count();
+        self.memory_bytes = tree.memory_bytes();
+        debug!(
+            "Voxel tree rebuilt: {} nodes, {} voxels, {} KiB",
+            tree.node_count(),
+            tree.voxels.len(),
+            tree.memory_bytes() / 1024
+        );
+        Ok(())
+    }
+
+    pub fn destroy(&mut self, context: &VulkanContext) {
+        self.node_buffer.destroy(context);
+        self.voxel_buffer.destroy(context);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Blocks below 256 mixed with air, different for every chunk.
+    fn patterned_chunk(seed: u32) -> Vec<u32> {
+        (0..CHUNK_VOLUME as u32)
+            .map(|i| {
+                let hash = (i ^ seed).wrapping_mul(0x9e37_79b9) >> 24;
+                if hash < 96 { 0 } else { hash }
+            })
+            .collect()
+    }
+
+    fn check(tree: &VoxelTree, chunks: &HashMap<Vector3<i32>, Vec<u32>>) {
+        let size = CHUNK_SIZE as i32;
+        for (&chunk, data) in chunks {
+            let in_window = (0..3).all(|axis| {
+                (0..WORLD_CHUNKS as i32).contains(&(chunk[axis] - tree.window_origin[axis]))
+            });
+            for z in 0..CHUNK_SIZE {
+                for y in 0..CHUNK_SIZE {
+                    for x in 0..CHUNK_SIZE {
+                        let pos = chunk * size + Vector3::new(x, y, z).cast::<i32>();
+                        let expected = if in_window {
+                            data[local_index(x, y, z)]
+                        } else {
+                            0
+                        };
+                        assert_eq!(tree.get(pos), expected, "voxel {:?}", pos);
+                    }
+                }
+            }
+        }
+    }
+
+    fn sample_chunks(origin: Vector3<i32>) -> HashMap<Vector3<i32>, Vec<u32>> {
+        let mut chunks = HashMap::new();
+        chunks.insert(origin, patterned_chunk(1));
+        chunks.insert(origin + Vector3::new(1, 0, 0), vec![5; CHUNK_VOLUME]);
+        chunks.insert(origin + Vector3::new(2, 0, 0), vec![0; CHUNK_VOLUME]);
+        let mut single = vec![0; CHUNK_VOLUME];
+        single[local_index(17, 3, 30)] = 200;
+        chunks.insert(origin + Vector3::new(5, 6, 7), single);
+        chunks.insert(origin + Vector3::new(-1, 0, 0), patterned_chunk(2));
+        chunks.insert(
+            origin.add_scalar(WORLD_CHUNKS as i32 - 1),
+            patterned_chunk(3),
+        );
+        // A whole 64 voxel node of one block
+        for z in 8..10 {
+            for y in 0..2 {
+                for x in 8..10 {
+                    chunks.insert(origin + Vector3::new(x, y, z), vec![9; CHUNK_VOLUME]);
+                }
+            }
+        }
+        chunks
+    }
+
+    #[test]
+    fn get_matches_source_chunks() {
+        let origin = Vector3::new(-3, -1, 4);
+        let chunks = sample_chunks(origin);
+        let tree = VoxelTree::build(origin, &chunks);
+        check(&tree, &chunks);
+
+        // Untouched chunks and voxels outside the window are air
+        assert_eq!(
+            tree.get(origin * CHUNK_SIZE as i32 + Vector3::new(0, 0, 100)),
+            0
+        );
+        assert_eq!(tree.get(origin * CHUNK_SIZE as i32 - Vector3::x()), 0);
+    }
+
+    #[test]
+    fn keeps_block_ids_over_a_byte() {
+        let origin = Vector3::zeros();
+        let mut chunks = HashMap::new();
+        let mut data: Vec<u32> = (0..CHUNK_VOLUME as u32).map(|i| 256 + i % 3).collect();
+        data[local_index(7, 8, 9)] = MAX_BLOCK_ID;
+        data[local_index(0, 0, 0)] = 0;
+        chunks.insert(origin, data);
+        chunks.insert(origin + Vector3::new(1, 0, 0), vec![1000; CHUNK_VOLUME]);
+        check(&VoxelTree::build(origin, &chunks), &chunks);
+    }
+
+    #[test]
+    fn empty_window_is_a_bare_root() {
+        let empty = VoxelTree::build(Vector3::zeros(), &HashMap::new());
+        assert_eq!(empty.node_count(), 1);
+        assert_eq!(empty.get(Vector3::new(5, 5, 5)), 0);
+    }
+
+    #[test]
+    fn rebuilt_chunk_matches_full_build() {
+        let origin = Vector3::new(2, 0, -5);
+        let mut chunks = sample_chunks(origin);
+        let mut trees: HashMap<Vector3<i32>, ChunkTree> = chunks
+            .iter()
+            .filter_map(|(&chunk, data)| Some((chunk, ChunkTree::build(data)?)))
+            .collect();
+
+        // Edit one chunk and add another, rebuilding only those
+        let edited = origin + Vector3::new(1, 0, 0);
+        let mut data = vec![5; CHUNK_VOLUME];
+        data[local_index(0, 0, 0)] = 0;
+        data[local_index(31, 31, 31)] = 77;
+        let added = origin + Vector3::new(3, 3, 3);
+        for (chunk, data) in [(edited, data), (added, patterned_chunk(4))] {
+            trees.insert(chunk, ChunkTree::build(&data).unwrap());
+            chunks.insert(chunk, data);
+        }
+
+        let incremental = VoxelTree::assemble(origin, &trees);
+        check(&incremental, &chunks);
+        assert_eq!(
+            incremental.node_count(),
+            VoxelTree::build(origin, &chunks).node_count()
+        );
+    }
+}
@@ -1,53 +1,111 @@
+use std::collections::{HashMap, HashSet};
+
 use ash::vk;
 use gpu_allocator::MemoryLocation;
-use nalgebra::{Vector2, Vector3};
+use log::*;
+use nalgebra::Vector3;
 
-use crate::{core::generator::VoxelGenerator, vulkan::{buffer::Buffer, context::VulkanContext}};
+use crate::{
+    core::{
+        allocator::ChunkAllocator,
+        features::{FeatureData, FeaturePlacer},
+        generator::{GeneratorSettings, MAX_FEATURES, SCRATCH_CHUNKS, VoxelGenerator},
+        palette::{
+            ChunkHeader, MAX_CHUNK_PAGES, PALETTE_CAPACITY, PackedChunk, Palette, page_count,
+        },
+    },
+    vulkan::{buffer::Buffer, context::VulkanContext},
+};
 
 pub const CHUNK_SIZE: usize = 32;
 pub const CHUNK_VOLUME: usize = CHUNK_SIZE * CHUNK_SIZE * CHUNK_SIZE;
 
 pub const WORLD_CHUNKS: usize = 32;
 pub const DIR_SIZE: usize = WORLD_CHUNKS * WORLD_CHUNKS * WORLD_CHUNKS;
-pub const MAX_CHUNKS: usize = 2048;
+pub const MAX_CHUNKS: usize = 8192;
+/// Directory entries with this bit set are uniform chunks: the rest of the
+/// entry is the block filling the whole chunk, which has no pool slot.
+pub const UNIFORM_CHUNK: u32 = 1 << 31;
+
+/// u32 words per pool page. A chunk's packed voxels take one page per bit of
+/// their palette indices.
+pub const PAGE_WORDS: usize = 1024;
+/// Pages of the voxel pool, shared by every chunk.
+pub const POOL_PAGES: usize = 32768;
+
+/// Voxels along the edge of a brick. Every pool slot keeps one occupancy bit
+/// per brick so the raytracer can skip the empty ones.
+pub const BRICK_SIZE: usize = 4;
+pub const CHUNK_BRICKS: usize = CHUNK_SIZE / BRICK_SIZE;
+/// u32 words of brick occupancy per pool slot.
+pub const BRICK_WORDS: usize = CHUNK_BRICKS * CHUNK_BRICKS * CHUNK_BRICKS / 32;
+
+/// Downsampled copies kept per pool slot, each halving the edge: 16^3, 8^3
+/// and 4^3 cells for distant chunks.
+pub const MIP_LEVELS: usize = 3;
+/// u32 words of all mip levels of a pool slot, level 1 first, 16 bits per
+/// cell so every id up to MAX_BLOCK_ID fits.
+pub const MIP_WORDS: usize = (CHUNK_VOLUME / 8 + CHUNK_VOLUME / 64 + CHUNK_VOLUME / 512) / 2;
+
+/// Chunk coordinate and range of the feature list the decorate pass stamps
+/// into it.
+type Decoration = ([i32; 3], u32, u32);
 
 pub struct ChunkedWorld {
     pub dir_buffer: Buffer,
+    /// POOL_PAGES pages of packed palette indices
     pub pool_buffer: Buffer,
-    pub active_chunk_count: u32,
+    /// ChunkHeader per pool slot: index width and the pages it is packed into
+    pub header_buffer: Buffer,
+    /// PALETTE_CAPACITY block ids per pool slot
+    pub palette_buffer: Buffer,
+    /// Brick occupancy bits per pool slot, rebuilt whenever the pool is written
+    pub brick_buffer: Buffer,
+    /// Mip levels per pool slot, rebuilt along with the brick masks
+    pub mip_buffer: Buffer,
     pub generator: VoxelGenerator,
+    /// Used for every chunk generated from now on
+    pub generator_settings: GeneratorSettings,
+    /// Trees, boulders and ores of the chunks around the window
+    pub features: FeaturePlacer,
+    /// CPU copy of dir_buffer: a pool slot, a UNIFORM_CHUNK entry, or 0 for
+    /// an empty (all air) chunk
+    pub directory: Vec<u32>,
+    pub allocator: ChunkAllocator,
+    /// CPU copies of header_buffer and palette_buffer, indexed by pool slot
+    pub headers: Vec<ChunkHeader>,
+    pub palettes: Vec<Palette>,
+    /// Hands out pool pages
+    pub pages: ChunkAllocator,
+    /// Lowest chunk coordinate of the resident window. The directory is
+    /// toroidal: a chunk lives at its coordinate wrapped by WORLD_CHUNKS.
+    pub window_origin: Vector3<i32>,
+    /// Chunks edited since they were last saved
+    pub modified: HashSet<Vector3<i32>>,
+    /// Revision at which each chunk of the window was last written,
+    /// generated or freed, so derived data such as the block lights and the
+    /// voxel tree only revisit the chunks changed since they were built
+    pub changed: HashMap<Vector3<i32>, u64>,
+    /// Bumped whenever the directory or the pool changes, lets derived data
+    /// such as the voxel tree tell when it is stale
+    pub revision: u64,
 }
 
 impl ChunkedWorld {
-    pub fn new(context: &VulkanContext) -> Result<Self, vk::Result> {
-        let mut dir_data = vec![1u32; DIR_SIZE];
+    pub fn new(
+        context: &VulkanContext,
+        generator_settings: GeneratorSettings,
+    ) -> Result<Self, vk::Result> {
+        let dir_data = vec![0u32; DIR_SIZE];
 
         let range_x = 16;
-        let range_y = 8;
+        let range_y: usize = 8;
         let range_z = 16;
         let start_x = (WORLD_CHUNKS - range_x) / 2;
-        let start_y = 0;
+        let start_y: usize = 0;
         let start_z = (WORLD_CHUNKS - range_z) / 2;
-        let mut pool_id_counter = 1;
-
-        for x in 0..range_x {
-            for y in 0..range_y {
-                for z in 0..range_z {
-                    let cx = start_x + x;
-                    let cy = start_y + y;
-                    let cz = start_z + z;
-                    let dir_idx = cx + (cy * WORLD_CHUNKS) + (cz * WORLD_CHUNKS * WORLD_CHUNKS);
-                    
-                    if pool_id_counter < MAX_CHUNKS {
-                        dir_data[dir_idx] = pool_id_counter as u32;
-                        pool_id_counter += 1;
-                    }
-                }
-            }
-        }
-
 
-        let pool_size = (CHUNK_VOLUME * MAX_CHUNKS * std::mem::size_of::<u32>()) as u64;
+        let pool_size = (POOL_PAGES * PAGE_WORDS * std::mem::size_of::<u32>()) as u64;
         let mut dir_buffer = Buffer::new(
             context,
             (DIR_SIZE * 4) as u64,
@@ -60,22 +118,709 @@ impl ChunkedWorld {
         let pool_buffer = Buffer::new(
             context,
             pool_size as u64,
-            vk::BufferUsageFlags::STORAGE_BUFFER | vk::BufferUsageFlags::TRANSFER_DST,
+            vk::BufferUsageFlags::STORAGE_BUFFER
+                | vk::BufferUsageFlags::TRANSFER_DST
+                | vk::BufferUsageFlags::TRANSFER_SRC,
             MemoryLocation::GpuOnly,
             "Chunk Pool",
         )?;
 
-        let generator = VoxelGenerator::new(context, &dir_buffer, &pool_buffer)?;
-        let start = [start_x as i32, start_y as i32, start_z as i32];
-        let size = [range_x as u32, range_y as u32, range_z as u32];
-        
-        generator.run(context, start, size)?;
+        let headers = vec![ChunkHeader::default(); MAX_CHUNKS];
+        let mut header_buffer = Buffer::new(
+            context,
+            (MAX_CHUNKS * std::mem::size_of::<ChunkHeader>()) as u64,
+            vk::BufferUsageFlags::STORAGE_BUFFER,
+            MemoryLocation::CpuToGpu,
+            "Chunk Headers",
+        )?;
+        header_buffer.update_slice(&headers)?;
+
+        let mut palette_buffer = Buffer::new(
+            context,
+            (MAX_CHUNKS * PALETTE_CAPACITY * 4) as u64,
+            vk::BufferUsageFlags::STORAGE_BUFFER,
+            MemoryLocation::CpuToGpu,
+            "Chunk Palettes",
+        )?;
+        palette_buffer.update_slice(&vec![0u32; MAX_CHUNKS * PALETTE_CAPACITY])?;
 
-        Ok(Self {
+        let brick_buffer = Buffer::new(
+            context,
+            (MAX_CHUNKS * BRICK_WORDS * 4) as u64,
+            vk::BufferUsageFlags::STORAGE_BUFFER,
+            MemoryLocation::GpuOnly,
+            "Chunk Bricks",
+        )?;
+
+        let mip_buffer = Buffer::new(
+            context,
+            (MAX_CHUNKS * MIP_WORDS * 4) as u64,
+            vk::BufferUsageFlags::STORAGE_BUFFER,
+            MemoryLocation::GpuOnly,
+            "Chunk Mips",
+        )?;
+
+        let generator = VoxelGenerator::new(
+            context,
+            &dir_buffer,
+            &pool_buffer,
+            &header_buffer,
+            &palette_buffer,
+            &brick_buffer,
+            &mip_buffer,
+        )?;
+
+        let mut world = Self {
             dir_buffer,
             pool_buffer,
-            active_chunk_count: 1,
-            generator
+            header_buffer,
+            palette_buffer,
+            brick_buffer,
+            mip_buffer,
+            generator,
+            generator_settings,
+            features: FeaturePlacer::default(),
+            directory: dir_data,
+            allocator: ChunkAllocator::new(MAX_CHUNKS),
+            headers,
+            palettes: vec![Palette::default(); MAX_CHUNKS],
+            pages: ChunkAllocator::new(POOL_PAGES),
+            window_origin: Vector3::zeros(),
+            modified: HashSet::new(),
+            changed: HashMap::new(),
+            revision: 0,
+        };
+
+        let start = Vector3::new(start_x as i32, start_y as i32, start_z as i32);
+        let size = Vector3::new(range_x as i32, range_y as i32, range_z as i32);
+        world.generate(context, start, size)?;
+
+        Ok(world)
+    }
+
+    /// Chunks currently holding a pool slot.
+    pub fn active_chunk_count(&self) -> u32 {
+        self.allocator.used() as u32
+    }
+
+    /// Bytes the resident chunks take: the directory, the pages in use, plus
+    /// a header, palette, brick masks and mips per chunk holding a slot.
+    pub fn resident_bytes(&self) -> usize {
+        let slot_bytes =
+            std::mem::size_of::<ChunkHeader>() + (PALETTE_CAPACITY + BRICK_WORDS + MIP_WORDS) * 4;
+        DIR_SIZE * 4 + self.pages.used() * PAGE_WORDS * 4 + self.allocator.used() * slot_bytes
+    }
+
+    /// Chunks collapsed into a UNIFORM_CHUNK directory entry.
+    pub fn uniform_chunk_count(&self) -> u32 {
+        self.directory
+            .iter()
+            .filter(|&&entry| entry_block(entry).is_some())
+            .count() as u32
+    }
+
+    /// Chunk coordinates and directory entries of every chunk that isn't air.
+    pub fn resident_chunks(&self) -> Vec<(Vector3<i32>, u32)> {
+        self.directory
+            .iter()
+            .enumerate()
+            .filter(|&(_, &entry)| entry != 0)
+            .map(|(dir_index, &entry)| {
+                (
+                    unwrap_coord(dir_index_coord(dir_index), self.window_origin),
+                    entry,
+                )
+            })
+            .collect()
+    }
+
+    /// (Re)generates every chunk in the box `start..start + size`, giving
+    /// chunks a pool slot while any are left. Chunks that come out all air or
+    /// all one block release theirs again.
+    pub fn generate(
+        &mut self,
+        context: &VulkanContext,
+        start: Vector3<i32>,
+        size: Vector3<i32>,
+    ) -> Result<(), vk::Result> {
+        let chunks = self.box_dir_indices(start, size);
+        self.generate_regions(context, &chunks, &[(start, size)])
+    }
+
+    /// Same as `generate` for a list of scattered chunks, recorded as one
+    /// dispatch per chunk in a single submit.
+    pub fn generate_chunks(
+        &mut self,
+        context: &VulkanContext,
+        chunks: &[Vector3<i32>],
+    ) -> Result<(), vk::Result> {
+        let resident: Vec<Vector3<i32>> = chunks
+            .iter()
+            .copied()
+            .filter(|&chunk| self.chunk_dir_index(chunk).is_some())
+            .collect();
+        let dir_indices: Vec<usize> = resident
+            .iter()
+            .filter_map(|&chunk| self.chunk_dir_index(chunk))
+            .collect();
+        let regions: Vec<(Vector3<i32>, Vector3<i32>)> = resident
+            .into_iter()
+            .map(|chunk| (chunk, Vector3::repeat(1)))
+            .collect();
+        self.generate_regions(context, &dir_indices, &regions)
+    }
+
+    fn generate_regions(
+        &mut self,
+        context: &VulkanContext,
+        dir_indices: &[usize],
+        regions: &[(Vector3<i32>, Vector3<i32>)],
+    ) -> Result<(), vk::Result> {
+        if regions.is_empty() {
+            return Ok(());
+        }
+        let regions: Vec<([i32; 3], [u32; 3])> = regions
+            .iter()
+            .map(|(start, size)| {
+                (
+                    [start.x, start.y, start.z],
+                    [size.x as u32, size.y as u32, size.z as u32],
+                )
+            })
+            .collect();
+        let window_origin = [
+            self.window_origin.x,
+            self.window_origin.y,
+            self.window_origin.z,
+        ];
+
+        // The scratch buffer holds SCRATCH_CHUNKS unpacked chunks at a time.
+        // Slots are handed out per batch so the uniform chunks of one batch
+        // leave theirs to the next
+        for batch in dir_indices.chunks(SCRATCH_CHUNKS) {
+            let mut slots = Vec::new();
+            for &dir_index in batch {
+                let slot = match entry_slot(self.directory[dir_index]) {
+                    Some(slot) => slot,
+                    None => {
+                        let Some(slot) = self.allocator.allocate() else {
+                            warn!("Chunk pool exhausted, part of the region is left as it was");
+                            break;
+                        };
+                        self.set_chunk_slot(dir_index, slot)?;
+                        slot
+                    }
+                };
+                self.mark_changed(dir_index);
+                slots.push((dir_index, slot));
+            }
+            let (features, decorations) = self.batch_features(&slots);
+
+            let batch_slots: Vec<u32> = slots.iter().map(|&(_, slot)| slot).collect();
+            let palettes = self.generator.run(
+                context,
+                &self.generator_settings,
+                window_origin,
+                &regions,
+                &features,
+                &decorations,
+                &batch_slots,
+            )?;
+
+            let mut packed = Vec::new();
+            for (scratch, (&(dir_index, slot), palette)) in slots.iter().zip(palettes).enumerate() {
+                if let Some(block) = palette.uniform() {
+                    self.free_chunk(dir_index)?;
+                    if block != 0 {
+                        self.set_chunk_slot(dir_index, UNIFORM_CHUNK | block)?;
+                    }
+                } else if !self.set_chunk_format(slot, palette)? {
+                    self.free_chunk(dir_index)?;
+                } else {
+                    packed.push((slot, scratch as u32));
+                }
+            }
+            self.generator.pack(context, &packed)?;
+        }
+        self.revision += 1;
+        Ok(())
+    }
+
+    /// Features and `(chunk, first, count)` decoration ranges for a batch of
+    /// generated chunks. Each chunk gets every feature reaching into it,
+    /// wherever it is anchored, so structures continue across borders no
+    /// matter the generation order.
+    fn batch_features(&mut self, slots: &[(usize, u32)]) -> (Vec<FeatureData>, Vec<Decoration>) {
+        let mut features = Vec::new();
+        let mut decorations = Vec::new();
+        for &(dir_index, _) in slots {
+            let chunk = unwrap_coord(dir_index_coord(dir_index), self.window_origin);
+            let chunk_features = self
+                .features
+                .chunk_features(&self.generator_settings, chunk);
+            if chunk_features.is_empty() {
+                continue;
+            }
+            if features.len() + chunk_features.len() > MAX_FEATURES {
+                warn!("Feature buffer full, chunk {:?} left undecorated", chunk);
+                continue;
+            }
+            decorations.push((
+                [chunk.x, chunk.y, chunk.z],
+                features.len() as u32,
+                chunk_features.len() as u32,
+            ));
+            features.extend(chunk_features.iter().map(|feature| feature.data()));
+        }
+        (features, decorations)
+    }
+
+    /// Moves the resident window. Directory entries whose chunk falls outside
+    /// the new window are freed, since their index now belongs to another chunk.
+    pub fn set_window_origin(&mut self, origin: Vector3<i32>) -> Result<(), vk::Result> {
+        if origin == self.window_origin {
+            return Ok(());
+        }
+        let old_origin = self.window_origin;
+        self.window_origin = origin;
+        self.revision += 1;
+        self.features.retain_window(origin);
+        for dir_index in 0..DIR_SIZE {
+            if self.directory[dir_index] == 0 {
+                continue;
+            }
+            let wrapped = dir_index_coord(dir_index);
+            if unwrap_coord(wrapped, old_origin) != unwrap_coord(wrapped, origin) {
+                self.free_chunk(dir_index)?;
+            }
+        }
+        let mut changed = std::mem::take(&mut self.changed);
+        changed.retain(|&chunk, _| self.in_window(chunk));
+        self.changed = changed;
+        Ok(())
+    }
+
+    /// Whether a chunk lies inside the resident window.
+    pub fn in_window(&self, chunk: Vector3<i32>) -> bool {
+        let size = WORLD_CHUNKS as i32;
+        (0..3).all(|axis| {
+            let offset = chunk[axis] - self.window_origin[axis];
+            (0..size).contains(&offset)
         })
     }
+
+    /// Voxel bounds `min..max` covered by the resident window.
+    pub fn window_voxel_bounds(&self) -> (Vector3<i32>, Vector3<i32>) {
+        let min = self.window_origin * CHUNK_SIZE as i32;
+        (min, min.add_scalar((WORLD_CHUNKS * CHUNK_SIZE) as i32))
+    }
+
+    /// Empties a directory entry and returns its slot and pages to the
+    /// allocators.
+    pub fn free_chunk(&mut self, dir_index: usize) -> Result<(), vk::Result> {
+        let entry = self.directory[dir_index];
+        let Some(slot) = entry_slot(entry) else {
+            if entry != 0 {
+                self.set_chunk_slot(dir_index, 0)?;
+            }
+            return Ok(());
+        };
+        let header = self.headers[slot as usize];
+        for &page in &header.pages[..header.page_count()] {
+            self.pages.free(page);
+        }
+        self.headers[slot as usize] = ChunkHeader::default();
+        self.palettes[slot as usize] = Palette::default();
+        self.header_buffer
+            .update_range(slot as usize, &[ChunkHeader::default()])?;
+        self.allocator.free(slot);
+        self.set_chunk_slot(dir_index, 0)
+    }
+
+    /// Gives a pool slot `palette` and pages for the index width it needs.
+    /// The pages are kept while the width stays the same; a palette that
+    /// outgrows it moves the chunk to new ones, which it must then be packed
+    /// into again. Returns false and leaves the slot as it was when the pool
+    /// is out of pages.
+    pub(crate) fn set_chunk_format(
+        &mut self,
+        slot: u32,
+        palette: Palette,
+    ) -> Result<bool, vk::Result> {
+        let pages = if self.headers[slot as usize].bits != palette.bits {
+            let Some(pages) = self.reserve_pages(palette.bits) else {
+                return Ok(false);
+            };
+            Some(pages)
+        } else {
+            None
+        };
+        self.apply_chunk_format(slot, palette, pages)?;
+        Ok(true)
+    }
+
+    /// Allocates the pages of a chunk packed at `bits`, or none of them.
+    fn reserve_pages(&mut self, bits: u32) -> Option<Vec<u32>> {
+        let count = page_count(bits);
+        let pages: Vec<u32> = (0..count).map_while(|_| self.pages.allocate()).collect();
+        if pages.len() < count {
+            warn!(
+                "Voxel pool out of pages, a chunk at {} bits per voxel doesn't fit",
+                bits
+            );
+            for page in pages {
+                self.pages.free(page);
+            }
+            return None;
+        }
+        Some(pages)
+    }
+
+    /// Writes a slot's palette and header, moving it to `pages` reserved for
+    /// a new index width and freeing the old ones.
+    fn apply_chunk_format(
+        &mut self,
+        slot: u32,
+        palette: Palette,
+        pages: Option<Vec<u32>>,
+    ) -> Result<(), vk::Result> {
+        let mut header = self.headers[slot as usize];
+        if let Some(pages) = pages {
+            for &page in &header.pages[..header.page_count()] {
+                self.pages.free(page);
+            }
+            header.bits = palette.bits;
+            header.pages = [0; MAX_CHUNK_PAGES];
+            header.pages[..pages.len()].copy_from_slice(&pages);
+        }
+        header.palette_len = palette.blocks.len() as u32;
+
+        self.palette_buffer
+            .update_range(slot as usize * PALETTE_CAPACITY, &palette.blocks)?;
+        self.header_buffer.update_range(slot as usize, &[header])?;
+        self.headers[slot as usize] = header;
+        self.palettes[slot as usize] = palette;
+        Ok(())
+    }
+
+    /// Directory indices of the resident chunks of the box `start..start + size`.
+    pub fn box_dir_indices(&self, start: Vector3<i32>, size: Vector3<i32>) -> Vec<usize> {
+        let mut indices = Vec::new();
+        for z in start.z..start.z + size.z {
+            for y in start.y..start.y + size.y {
+                for x in start.x..start.x + size.x {
+                    if let Some(dir_index) = self.chunk_dir_index(Vector3::new(x, y, z)) {
+                        indices.push(dir_index);
+                    }
+                }
+            }
+        }
+        indices
+    }
+
+    /// Directory index of a chunk coordinate, None outside the resident window.
+    pub fn chunk_dir_index(&self, chunk: Vector3<i32>) -> Option<usize> {
+        if !self.in_window(chunk) {
+            return None;
+        }
+        let wrapped = chunk.map(|c| c.rem_euclid(WORLD_CHUNKS as i32) as usize);
+        Some(wrapped.x + wrapped.y * WORLD_CHUNKS + wrapped.z * WORLD_CHUNKS * WORLD_CHUNKS)
+    }
+
+    /// Directory index and voxel index inside the chunk for a world voxel position.
+    pub fn locate(&self, pos: Vector3<i32>) -> Option<(usize, usize)> {
+        let size = CHUNK_SIZE as i32;
+        let chunk = pos.map(|c| c.div_euclid(size));
+        let local = pos.map(|c| c.rem_euclid(size) as usize);
+        let dir_index = self.chunk_dir_index(chunk)?;
+        Some((dir_index, local_index(local.x, local.y, local.z)))
+    }
+
+    /// Sets a directory entry: a pool slot, a UNIFORM_CHUNK entry or 0 for
+    /// air.
+    pub(crate) fn set_chunk_slot(&mut self, dir_index: usize, slot: u32) -> Result<(), vk::Result> {
+        self.directory[dir_index] = slot;
+        self.revision += 1;
+        self.mark_changed(dir_index);
+        self.dir_buffer.update_range(dir_index, &[slot])
+    }
+
+    /// Records that the chunk at a directory index changed, see `changed`.
+    pub(crate) fn mark_changed(&mut self, dir_index: usize) {
+        self.revision += 1;
+        self.changed.insert(
+            unwrap_coord(dir_index_coord(dir_index), self.window_origin),
+            self.revision,
+        );
+    }
+
+    /// Chunks of the window changed after `revision`, all of them for None.
+    pub fn changed_since(&self, revision: Option<u64>) -> Vec<Vector3<i32>> {
+        self.changed
+            .iter()
+            .filter(|&(_, &changed)| revision.is_none_or(|revision| changed > revision))
+            .map(|(&chunk, _)| chunk)
+            .collect()
+    }
+
+    /// Copies a chunk's voxels back from the pool. Empty chunks and chunks
+    /// outside the window read as all air.
+    pub fn read_chunk(
+        &self,
+        context: &VulkanContext,
+        chunk: Vector3<i32>,
+    ) -> Result<Box<[u32; CHUNK_VOLUME]>, vk::Result> {
+        let entry = self
+            .chunk_dir_index(chunk)
+            .map_or(0, |dir_index| self.directory[dir_index]);
+        let voxels = self.read_chunks(context, &[entry])?;
+        // Through a Vec so the 128 KiB array never lives on the stack
+        Ok(voxels
+            .into_boxed_slice()
+            .try_into()
+            .expect("read_chunks returned a partial chunk"))
+    }
+
+    /// Reads chunks back by directory entry and unpacks them, CHUNK_VOLUME
+    /// voxels per entry in order. Air and uniform chunks, and slots without
+    /// pages, are filled in without touching the pool.
+    pub(crate) fn read_chunks(
+        &self,
+        context: &VulkanContext,
+        entries: &[u32],
+    ) -> Result<Vec<u32>, vk::Result> {
+        let ranges: Vec<(u64, u64)> = entries
+            .iter()
+            .filter_map(|&entry| entry_slot(entry))
+            .flat_map(|slot| self.page_ranges(slot))
+            .collect();
+        let words = self.read_pool(context, &ranges)?;
+
+        let mut voxels = Vec::with_capacity(entries.len() * CHUNK_VOLUME);
+        let mut words = words.as_slice();
+        for &entry in entries {
+            let Some(slot) =
+                entry_slot(entry).filter(|&slot| self.headers[slot as usize].bits != 0)
+            else {
+                voxels.resize(voxels.len() + CHUNK_VOLUME, entry_block(entry).unwrap_or(0));
+                continue;
+            };
+            let header = &self.headers[slot as usize];
+            let (chunk, rest) = words.split_at(header.page_count() * PAGE_WORDS);
+            voxels.extend(PackedChunk::decode(&self.palettes[slot as usize], chunk));
+            words = rest;
+        }
+        Ok(voxels)
+    }
+
+    /// Packs CHUNK_VOLUME voxels per directory entry into the pool, in a
+    /// single submit. Air and uniform entries get a slot, chunks that come out
+    /// all air or all one block give theirs back, and chunks whose palette
+    /// outgrew their index width are repacked into new pages. Chunks that
+    /// don't fit in the pool are left as they were.
+    pub(crate) fn write_chunks(
+        &mut self,
+        context: &VulkanContext,
+        dir_indices: &[usize],
+        voxels: &[u32],
+    ) -> Result<(), vk::Result> {
+        // Slots and pages are reserved for every chunk before any header or
+        // palette changes, so running short leaves out whole chunks rather
+        // than pointing headers at pages that never get written
+        let mut collapsed = Vec::new();
+        let mut reserved = Vec::new();
+        for (&dir_index, voxels) in dir_indices.iter().zip(voxels.chunks_exact(CHUNK_VOLUME)) {
+            let packed = PackedChunk::encode(voxels);
+            if let Some(block) = packed.palette.uniform() {
+                collapsed.push((dir_index, block));
+                continue;
+            }
+            let (slot, fresh) = match entry_slot(self.directory[dir_index]) {
+                Some(slot) => (slot, false),
+                None => {
+                    let Some(slot) = self.allocator.allocate() else {
+                        warn!("Chunk pool exhausted, a chunk is left as it was");
+                        continue;
+                    };
+                    (slot, true)
+                }
+            };
+            let pages = if self.headers[slot as usize].bits != packed.palette.bits {
+                let Some(pages) = self.reserve_pages(packed.palette.bits) else {
+                    if fresh {
+                        self.allocator.free(slot);
+                    }
+                    continue;
+                };
+                Some(pages)
+            } else {
+                None
+            };
+            reserved.push((dir_index, slot, fresh, pages, packed));
+        }
+
+        for (dir_index, block) in collapsed {
+            self.free_chunk(dir_index)?;
+            if block != 0 {
+                self.set_chunk_slot(dir_index, UNIFORM_CHUNK | block)?;
+            }
+        }
+        let mut slots = Vec::new();
+        let mut ranges = Vec::new();
+        let mut data = Vec::new();
+        for (dir_index, slot, fresh, pages, packed) in reserved {
+            if fresh {
+                self.set_chunk_slot(dir_index, slot)?;
+            }
+            self.mark_changed(dir_index);
+            self.apply_chunk_format(slot, packed.palette, pages)?;
+            ranges.extend(self.page_ranges(slot));
+            data.extend(packed.words);
+            slots.push(slot);
+        }
+        self.write_pool(context, &slots, &ranges, &data)
+    }
+
+    /// `(offset, len)` word ranges of the pages a slot is packed into.
+    fn page_ranges(&self, slot: u32) -> impl Iterator<Item = (u64, u64)> + use<> {
+        let header = self.headers[slot as usize];
+        (0..header.page_count())
+            .map(move |page| (page_offset(header.pages[page]), PAGE_WORDS as u64))
+    }
+
+    /// Reads `(offset, len)` word ranges of the pool back to the CPU, concatenated.
+    pub(crate) fn read_pool(
+        &self,
+        context: &VulkanContext,
+        ranges: &[(u64, u64)],
+    ) -> Result<Vec<u32>, vk::Result> {
+        let total: u64 = ranges.iter().map(|(_, len)| len).sum();
+        if total == 0 {
+            return Ok(Vec::new());
+        }
+        let mut staging = Buffer::new(
+            context,
+            total * 4,
+            vk::BufferUsageFlags::TRANSFER_DST,
+            MemoryLocation::GpuToCpu,
+            "Staging-Pool Readback",
+        )?;
+        let mut dst_offset = 0;
+        let regions: Vec<vk::BufferCopy> = ranges
+            .iter()
+            .map(|&(offset, len)| {
+                let copy = vk::BufferCopy {
+                    src_offset: offset * 4,
+                    dst_offset: dst_offset * 4,
+                    size: len * 4,
+                };
+                dst_offset += len;
+                copy
+            })
+            .collect();
+
+        context.immediate_submit(|cmd| unsafe {
+            context
+                .device
+                .cmd_copy_buffer(cmd, self.pool_buffer.buffer, staging.buffer, &regions);
+        })?;
+
+        let data = staging.read_slice::<u32>(total as usize);
+        staging.destroy(context);
+        data
+    }
+
+    /// Uploads words into the pool through a staging buffer. `ranges` are
+    /// `(offset, len)` in words and consume `data` in order. The brick masks
+    /// and mips of `slots` are rebuilt from the result in the same submit.
+    pub(crate) fn write_pool(
+        &mut self,
+        context: &VulkanContext,
+        slots: &[u32],
+        ranges: &[(u64, u64)],
+        data: &[u32],
+    ) -> Result<(), vk::Result> {
+        if ranges.is_empty() {
+            return Ok(());
+        }
+        let mut staging = Buffer::new(
+            context,
+            (data.len().max(1) * 4) as u64,
+            vk::BufferUsageFlags::TRANSFER_SRC,
+            MemoryLocation::CpuToGpu,
+            "Staging-Pool Upload",
+        )?;
+        staging.update_slice(data)?;
+
+        let mut src_offset = 0;
+        let regions: Vec<vk::BufferCopy> = ranges
+            .iter()
+            .map(|&(offset, len)| {
+                let copy = vk::BufferCopy {
+                    src_offset: src_offset * 4,
+                    dst_offset: offset * 4,
+                    size: len * 4,
+                };
+                src_offset += len;
+                copy
+            })
+            .collect();
+
+        context.immediate_submit(|cmd| unsafe {
+            context
+                .device
+                .cmd_copy_buffer(cmd, staging.buffer, self.pool_buffer.buffer, &regions);
+            self.generator.record_bricks(context, cmd, slots);
+            self.generator.record_mips(context, cmd, slots);
+        })?;
+        self.revision += 1;
+
+        staging.destroy(context);
+        Ok(())
+    }
+
+    pub fn destroy(&mut self, context: &VulkanContext) {
+        self.generator.destroy(context);
+        self.dir_buffer.destroy(context);
+        self.pool_buffer.destroy(context);
+        self.header_buffer.destroy(context);
+        self.palette_buffer.destroy(context);
+        self.brick_buffer.destroy(context);
+        self.mip_buffer.destroy(context);
+    }
+}
+
+/// Voxel index inside a chunk, x-major so rows along x are contiguous.
+pub fn local_index(x: usize, y: usize, z: usize) -> usize {
+    x + y * CHUNK_SIZE + z * CHUNK_SIZE * CHUNK_SIZE
+}
+
+/// Pool slot of a directory entry, None for air and uniform chunks.
+pub fn entry_slot(entry: u32) -> Option<u32> {
+    (entry != 0 && entry & UNIFORM_CHUNK == 0).then_some(entry)
+}
+
+/// Block filling a uniform chunk, None for other directory entries.
+pub fn entry_block(entry: u32) -> Option<u32> {
+    (entry & UNIFORM_CHUNK != 0).then_some(entry & !UNIFORM_CHUNK)
+}
+
+/// Wrapped chunk coordinate stored at a directory index.
+fn dir_index_coord(dir_index: usize) -> Vector3<i32> {
+    Vector3::new(
+        dir_index % WORLD_CHUNKS,
+        (dir_index / WORLD_CHUNKS) % WORLD_CHUNKS,
+        dir_index / (WORLD_CHUNKS * WORLD_CHUNKS),
+    )
+    .cast::<i32>()
+}
+
+/// The chunk inside the window starting at `origin` that maps to `wrapped`.
+fn unwrap_coord(wrapped: Vector3<i32>, origin: Vector3<i32>) -> Vector3<i32> {
+    origin + (wrapped - origin).map(|c| c.rem_euclid(WORLD_CHUNKS as i32))
+}
+
+/// First word of a pool page.
+pub fn page_offset(page: u32) -> u64 {
+    page as u64 * PAGE_WORDS as u64
 }
@@ -1,4 +1,5 @@
 use std::collections::HashSet;
+use std::path::Path;
 
 use log::*;
 use nalgebra::Vector3;
@@ -8,15 +9,20 @@ use winit::event_loop::{ActiveEventLoop, ControlFlow, EventLoop};
 use winit::keyboard::{KeyCode, PhysicalKey};
 use winit::window::WindowId;
 
-use crate::core::engine::VoxelEngine;
+use voxentia::core::blocks;
+use voxentia::core::engine::VoxelEngine;
+use voxentia::core::generator::GeneratorSettings;
+use voxentia::core::materials::MATERIALS_FILE;
+use voxentia::core::streaming::StreamingSettings;
+use voxentia::vulkan::pipelines::raytrace::{RenderMode, WorldLayout};
 
-mod core;
-mod vulkan;
+const SAVE_DIR: &str = "world";
 
 #[derive(Default)]
 struct App {
     pub engine: Option<VoxelEngine>,
     pub input: InputState,
+    pub generator_settings: GeneratorSettings,
 }
 
 #[derive(Default)]
@@ -62,9 +68,120 @@ impl App {
     }
 }
 
+impl App {
+    fn take_screenshot(&mut self) {
+        let Some(engine) = self.engine.as_mut() else {
+            return;
+        };
+        let timestamp = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_millis())
+            .unwrap_or_default();
+        let path = format!("screenshot-{}.png", timestamp);
+        if let Err(err) = engine.capture_frame(path) {
+            error!("Unable to capture frame: {}", err);
+        }
+    }
+
+    fn save_world(&mut self) {
+        if let Some(engine) = self.engine.as_mut()
+            && let Err(err) = engine.save_world(Path::new(SAVE_DIR))
+        {
+            error!("Unable to save world: {}", err);
+        }
+    }
+
+    fn load_world(&mut self) {
+        if let Some(engine) = self.engine.as_mut()
+            && let Err(err) = engine.load_world(Path::new(SAVE_DIR))
+        {
+            error!("Unable to load world: {}", err);
+        }
+    }
+
+    fn reload_materials(&mut self) {
+        let Some(engine) = self.engine.as_mut() else {
+            return;
+        };
+        match engine.reload_materials(Path::new(MATERIALS_FILE)) {
+            Ok(()) => info!("Materials reloaded from {}", MATERIALS_FILE),
+            Err(err) => error!("Unable to reload materials: {}", err),
+        }
+    }
+
+    fn toggle_shadows(&mut self) {
+        if let Some(engine) = self.engine.as_mut() {
+            let settings = &mut engine.render_settings;
+            settings.shadows = !settings.shadows;
+            info!("Shadows {}", if settings.shadows { "on" } else { "off" });
+        }
+    }
+
+    fn cycle_ao_quality(&mut self) {
+        if let Some(engine) = self.engine.as_mut() {
+            let settings = &mut engine.render_settings;
+            settings.ambient_occlusion = settings.ambient_occlusion.next();
+            info!("Ambient occlusion {:?}", settings.ambient_occlusion);
+        }
+    }
+
+    fn toggle_path_tracing(&mut self) {
+        if let Some(engine) = self.engine.as_mut() {
+            let settings = &mut engine.render_settings;
+            settings.mode = match settings.mode {
+                RenderMode::Shaded => RenderMode::PathTraced,
+                RenderMode::PathTraced => RenderMode::Shaded,
+            };
+            info!("Render mode {:?}", settings.mode);
+        }
+    }
+
+    fn toggle_layout(&mut self) {
+        if let Some(engine) = self.engine.as_mut() {
+            let settings = &mut engine.render_settings;
+            settings.layout = match settings.layout {
+                WorldLayout::Chunks => WorldLayout::Tree,
+                WorldLayout::Tree => WorldLayout::Chunks,
+            };
+            info!("World layout {:?}", settings.layout);
+        }
+    }
+
+    fn shift_time(&mut self, hours: f32) {
+        if let Some(engine) = self.engine.as_mut() {
+            let environment = &mut engine.environment;
+            environment.time_of_day = (environment.time_of_day + hours).rem_euclid(24.0);
+            info!("Time of day {:.1}h", environment.time_of_day);
+        }
+    }
+
+    /// Places `block` a few voxels in front of the camera.
+    fn place_block(&mut self, block: u32) {
+        let Some(engine) = self.engine.as_mut() else {
+            return;
+        };
+        let target = engine.camera.position + engine.camera.forward * 4.0;
+        let pos = target.coords.map(|c| c.floor() as i32);
+        if let Err(err) = engine.set_block(pos, block) {
+            error!("Unable to place block {}: {}", block, err);
+        }
+    }
+
+    fn request_redraw(&self) {
+        if let Some(window) = self.engine.as_ref().and_then(|engine| engine.window()) {
+            window.request_redraw();
+        }
+    }
+}
+
 impl ApplicationHandler for App {
     fn resumed(&mut self, event_loop: &ActiveEventLoop) {
-        let engine = VoxelEngine::new(event_loop).expect("Voxel engine initialization failed");
+        let mut engine = VoxelEngine::new(event_loop, self.generator_settings)
+            .expect("Voxel engine initialization failed");
+        engine.enable_streaming(StreamingSettings {
+            save_dir: Some(SAVE_DIR.into()),
+            ..Default::default()
+        });
         self.engine = Some(engine);
     }
     fn device_event(
@@ -73,18 +190,19 @@ impl ApplicationHandler for App {
         _device_id: winit::event::DeviceId,
         event: DeviceEvent,
     ) {
-        if let Some(engine) = self.engine.as_mut() {
-            if let DeviceEvent::MouseMotion { delta } = event {
-                engine
-                    .camera
-                    .input_rotate(delta.0 as f32, delta.1 as f32, 0.002);
-            }
+        if let Some(engine) = self.engine.as_mut()
+            && let DeviceEvent::MouseMotion { delta } = event
+        {
+            engine
+                .camera
+                .input_rotate(delta.0 as f32, delta.1 as f32, 0.002);
         }
     }
     fn window_event(&mut self, event_loop: &ActiveEventLoop, _id: WindowId, event: WindowEvent) {
         match event {
             WindowEvent::CloseRequested => {
                 info!("The close button was pressed; stopping");
+                self.save_world();
                 event_loop.exit();
             }
             WindowEvent::RedrawRequested => {
@@ -94,8 +212,11 @@ impl ApplicationHandler for App {
                     .unwrap()
                     .draw_frame()
                     .expect("Unable to draw frame");
-                info!("camera pos: {:?}",self.engine.as_ref().unwrap().camera.position);
-                self.engine.as_ref().unwrap().window.request_redraw();
+                info!(
+                    "camera pos: {:?}",
+                    self.engine.as_ref().unwrap().camera.position
+                );
+                self.request_redraw();
             }
             WindowEvent::Resized(physical_size) => {
                 if physical_size.width == 0 || physical_size.height == 0 {
@@ -109,13 +230,30 @@ impl ApplicationHandler for App {
                         .camera
                         .update_aspect(physical_size.width, physical_size.height);
                 }
-                self.engine.as_ref().unwrap().window.request_redraw();
+                self.request_redraw();
             }
             WindowEvent::KeyboardInput {
                 event: key_event, ..
             } => {
                 if let PhysicalKey::Code(code) = key_event.physical_key {
                     if key_event.state.is_pressed() {
+                        if !key_event.repeat {
+                            match code {
+                                KeyCode::F12 => self.take_screenshot(),
+                                KeyCode::F5 => self.save_world(),
+                                KeyCode::F6 => self.reload_materials(),
+                                KeyCode::F7 => self.toggle_shadows(),
+                                KeyCode::F8 => self.cycle_ao_quality(),
+                                KeyCode::KeyP => self.toggle_path_tracing(),
+                                KeyCode::KeyL => self.toggle_layout(),
+                                KeyCode::BracketLeft => self.shift_time(-1.0),
+                                KeyCode::BracketRight => self.shift_time(1.0),
+                                KeyCode::KeyT => self.place_block(blocks::TORCH),
+                                KeyCode::KeyG => self.place_block(blocks::GLASS),
+                                KeyCode::F9 => self.load_world(),
+                                _ => (),
+                            }
+                        }
                         self.input.keys_held.insert(code);
                     } else {
                         self.input.keys_held.remove(&code);
@@ -127,10 +265,203 @@ impl ApplicationHandler for App {
     }
 }
 
+struct HeadlessOptions {
+    width: u32,
+    height: u32,
+    frames: u32,
+    output: Option<String>,
+    time_of_day: Option<f32>,
+    path_trace: bool,
+    tree: bool,
+}
+
+impl HeadlessOptions {
+    /// Parses `--headless [--size WxH] [--frames N] [--output FILE] [--time H]
+    /// [--path-trace] [--tree]`, returns None when the windowed app should run instead.
+    fn from_args(args: &[String]) -> Option<Self> {
+        if !args.iter().any(|arg| arg == "--headless") {
+            return None;
+        }
+        let mut options = Self {
+            width: 1280,
+            height: 720,
+            frames: 1,
+            output: None,
+            time_of_day: None,
+            path_trace: false,
+            tree: false,
+        };
+        let mut iter = args.iter();
+        while let Some(arg) = iter.next() {
+            match arg.as_str() {
+                "--size" => {
+                    let size = iter.next().expect("--size expects WIDTHxHEIGHT");
+                    let (w, h) = size.split_once('x').expect("--size expects WIDTHxHEIGHT");
+                    options.width = w.parse().expect("Invalid width");
+                    options.height = h.parse().expect("Invalid height");
+                }
+                "--frames" => {
+                    let frames = iter.next().expect("--frames expects a count");
+                    options.frames = frames.parse().expect("Invalid frame count");
+                }
+                "--output" => {
+                    options.output = Some(iter.next().expect("--output expects a path").clone());
+                }
+                "--time" => {
+                    let time = iter.next().expect("--time expects an hour");
+                    options.time_of_day = Some(time.parse().expect("Invalid time of day"));
+                }
+                "--path-trace" => options.path_trace = true,
+                "--tree" => options.tree = true,
+                _ => (),
+            }
+        }
+        Some(options)
+    }
+}
+
+/// Parses `[--seed N]` on top of the default generator settings.
+fn generator_settings_from_args(args: &[String]) -> GeneratorSettings {
+    let mut settings = GeneratorSettings::default();
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        if arg == "--seed" {
+            let seed = iter.next().expect("--seed expects a number");
+            settings.seed = seed.parse().expect("Invalid seed");
+        }
+    }
+    settings
+}
+
+fn run_headless(options: HeadlessOptions, generator_settings: GeneratorSettings) {
+    let mut engine = VoxelEngine::new_headless(options.width, options.height, generator_settings)
+        .expect("Headless voxel engine initialization failed");
+    if let Some(time) = options.time_of_day {
+        engine.environment.time_of_day = time.rem_euclid(24.0);
+    }
+    // Every frame adds one sample per pixel to the captured image
+    if options.path_trace {
+        engine.render_settings.mode = RenderMode::PathTraced;
+    }
+    if options.tree {
+        engine.render_settings.layout = WorldLayout::Tree;
+    }
+    for frame in 0..options.frames {
+        let start = std::time::Instant::now();
+        engine
+            .render_offscreen()
+            .expect("Unable to render offscreen frame");
+        info!("headless frame {} rendered in {:?}", frame, start.elapsed());
+    }
+    if let Some(output) = options.output {
+        engine.capture_frame(output).expect("Unable to save frame");
+    }
+}
+
+/// Regenerates the initial region with generate.comp and checks it against
+/// the CPU port chunk by chunk. Exits with an error if any chunk differs.
+fn run_verify_generator(generator_settings: GeneratorSettings) {
+    let mut engine = VoxelEngine::new_headless(64, 64, generator_settings)
+        .expect("Headless voxel engine initialization failed");
+    let mut chunks = Vec::new();
+    for z in 8..24 {
+        for y in 0..8 {
+            for x in 8..24 {
+                chunks.push(Vector3::new(x, y, z));
+            }
+        }
+    }
+    let mismatches = engine
+        .world
+        .verify_generator(&engine.vkcontext, &chunks)
+        .expect("Unable to run generator comparison");
+    for mismatch in &mismatches {
+        error!(
+            "chunk {:?}: {} voxels differ, first at {:?} (gpu {}, cpu {})",
+            mismatch.chunk,
+            mismatch.differing_voxels,
+            mismatch.first.0,
+            mismatch.first.1,
+            mismatch.first.2
+        );
+    }
+    if !mismatches.is_empty() {
+        error!("{} of {} chunks differ", mismatches.len(), chunks.len());
+        std::process::exit(1);
+    }
+    info!(
+        "CPU and GPU generators agree on all {} chunks",
+        chunks.len()
+    );
+}
+
+/// Renders the same view with both world layouts and logs their frame times
+/// and the memory the world takes in each.
+fn run_benchmark(generator_settings: GeneratorSettings, frames: u32) {
+    let mut engine = VoxelEngine::new_headless(1280, 720, generator_settings)
+        .expect("Headless voxel engine initialization failed");
+    for layout in [WorldLayout::Chunks, WorldLayout::Tree] {
+        engine.render_settings.layout = layout;
+        // The first frame builds the tree, keep it out of the timings
+        let start = std::time::Instant::now();
+        engine
+            .render_offscreen()
+            .expect("Unable to render offscreen frame");
+        let warmup = start.elapsed();
+
+        let start = std::time::Instant::now();
+        for _ in 0..frames {
+            engine
+                .render_offscreen()
+                .expect("Unable to render offscreen frame");
+        }
+        let average = start.elapsed() / frames.max(1);
+        let memory = match layout {
+            WorldLayout::Chunks => engine.world.resident_bytes(),
+            WorldLayout::Tree => engine.tree.memory_bytes,
+        };
+        info!(
+            "{:?}: {:?} per frame over {} frames (first frame {:?}), {} KiB of world data",
+            layout,
+            average,
+            frames,
+            warmup,
+            memory / 1024
+        );
+    }
+    info!(
+        "{} resident chunks ({} uniform), {} tree nodes",
+        engine.world.resident_chunks().len(),
+        engine.world.uniform_chunk_count(),
+        engine.tree.node_count
+    );
+}
+
 fn main() {
-    let event_loop = EventLoop::new().unwrap();
     env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let generator_settings = generator_settings_from_args(&args);
+    if args.iter().any(|arg| arg == "--verify-generator") {
+        run_verify_generator(generator_settings);
+        return;
+    }
+    if let Some(index) = args.iter().position(|arg| arg == "--benchmark") {
+        let frames = args
+            .get(index + 1)
+            .and_then(|frames| frames.parse().ok())
+            .unwrap_or(100);
+        run_benchmark(generator_settings, frames);
+        return;
+    }
+    if let Some(options) = HeadlessOptions::from_args(&args) {
+        run_headless(options, generator_settings);
+        return;
+    }
+    let event_loop = EventLoop::new().unwrap();
     event_loop.set_control_flow(ControlFlow::Poll);
-    let mut app = App::default();
+    let mut app = App {
+        generator_settings,
+        ..Default::default()
+    };
     let _ = event_loop.run_app(&mut app);
 }
@@ -0,0 +1,121 @@
+use ash::vk;
+use gpu_allocator::MemoryLocation;
+use gpu_allocator::vulkan::{Allocation, AllocationCreateDesc};
+
+use crate::vulkan::context::VulkanContext;
+
+/// RGBA32F storage image holding the running mean of the path traced samples
+/// of every pixel. Kept in GENERAL layout for its whole life.
+#[allow(unused)]
+pub struct AccumulationImage {
+    pub image: vk::Image,
+    pub image_view: vk::ImageView,
+    pub allocation: Option<Allocation>,
+    pub extent: vk::Extent2D,
+}
+
+impl AccumulationImage {
+    pub fn new(context: &VulkanContext, extent: vk::Extent2D) -> Result<Self, vk::Result> {
+        let device = &context.device;
+        let format = vk::Format::R32G32B32A32_SFLOAT;
+
+        let image = unsafe {
+            let create_info = vk::ImageCreateInfo::default()
+                .image_type(vk::ImageType::TYPE_2D)
+                .format(format)
+                .extent(vk::Extent3D {
+                    width: extent.width,
+                    height: extent.height,
+                    depth: 1,
+                })
+                .mip_levels(1)
+                .array_layers(1)
+                .samples(vk::SampleCountFlags::TYPE_1)
+                .tiling(vk::ImageTiling::OPTIMAL)
+                .usage(vk::ImageUsageFlags::STORAGE)
+                .sharing_mode(vk::SharingMode::EXCLUSIVE)
+                .initial_layout(vk::ImageLayout::UNDEFINED);
+            device.create_image(&create_info, None)?
+        };
+        context.set_object_name(image, "Accumulation Image")?;
+
+        let allocation = unsafe {
+            let requirements = device.get_image_memory_requirements(image);
+            let mut allocator = context
+                .allocator
+                .lock()
+                .map_err(|_| vk::Result::NOT_READY)?;
+            let allocation = allocator
+                .allocate(&AllocationCreateDesc {
+                    name: "Accumulation Image",
+                    requirements,
+                    location: MemoryLocation::GpuOnly,
+                    linear: false,
+                    allocation_scheme: gpu_allocator::vulkan::AllocationScheme::GpuAllocatorManaged,
+                })
+                .map_err(|_| vk::Result::ERROR_OUT_OF_DEVICE_MEMORY)?;
+            device.bind_image_memory(image, allocation.memory(), allocation.offset())?;
+            allocation
+        };
+
+        let subresource_range = vk::ImageSubresourceRange {
+            aspect_mask: vk::ImageAspectFlags::COLOR,
+            base_mip_level: 0,
+            level_count: 1,
+            base_array_layer: 0,
+            layer_count: 1,
+        };
+
+        context.immediate_submit(|cmd| {
+            let to_general = vk::ImageMemoryBarrier::default()
+                .old_layout(vk::ImageLayout::UNDEFINED)
+                .new_layout(vk::ImageLayout::GENERAL)
+                .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .image(image)
+                .subresource_range(subresource_range)
+                .src_access_mask(vk::AccessFlags::empty())
+                .dst_access_mask(vk::AccessFlags::SHADER_READ | vk::AccessFlags::SHADER_WRITE);
+            unsafe {
+                device.cmd_pipeline_barrier(
+                    cmd,
+                    vk::PipelineStageFlags::TOP_OF_PIPE,
+                    vk::PipelineStageFlags::COMPUTE_SHADER,
+                    vk::DependencyFlags::empty(),
+                    &[],
+                    &[],
+                    &[to_general],
+                );
+            }
+        })?;
+
+        let image_view = unsafe {
+            let create_info = vk::ImageViewCreateInfo::default()
+                .image(image)
+                .view_type(vk::ImageViewType::TYPE_2D)
+                .format(format)
+                .components(vk::ComponentMapping::default())
+                .subresource_range(subresource_range);
+            device.create_image_view(&create_info, None)?
+        };
+
+        Ok(Self {
+            image,
+            image_view,
+            allocation: Some(allocation),
+            extent,
+        })
+    }
+
+    pub fn destroy(&mut self, context: &VulkanContext) {
+        let device = &context.device;
+        unsafe {
+            device.destroy_image_view(self.image_view, None);
+            device.destroy_image(self.image, None);
+        }
+        if let Some(alloc) = self.allocation.take() {
+            let mut allocator = context.allocator.lock().unwrap();
+            let _ = allocator.free(alloc);
+        }
+    }
+}
@@ -63,30 +63,36 @@ impl Buffer {
         name: &str,
         data: &[T],
     ) -> Result<Self, vk::Result> {
-        let size = (std::mem::size_of::<T>() * data.len()) as u64;
+        let size = std::mem::size_of_val(data) as u64;
 
         let mut staging = Self::new(
-            context, 
-            size, 
+            context,
+            size,
             vk::BufferUsageFlags::TRANSFER_SRC,
             MemoryLocation::CpuToGpu,
-            &format!("Staging-{}", name)
+            &format!("Staging-{}", name),
         )?;
-        
+
         staging.update_slice(data)?;
 
         let gpu_buffer = Self::new(
-            context, 
-            size, 
-            usage | vk::BufferUsageFlags::TRANSFER_DST, 
+            context,
+            size,
+            usage | vk::BufferUsageFlags::TRANSFER_DST,
             MemoryLocation::GpuOnly,
-            name
+            name,
         )?;
 
         context.immediate_submit(|cmd| {
-            let copy = vk::BufferCopy { src_offset: 0, dst_offset: 0, size };
+            let copy = vk::BufferCopy {
+                src_offset: 0,
+                dst_offset: 0,
+                size,
+            };
             unsafe {
-                context.device.cmd_copy_buffer(cmd, staging.buffer, gpu_buffer.buffer, &[copy]);
+                context
+                    .device
+                    .cmd_copy_buffer(cmd, staging.buffer, gpu_buffer.buffer, &[copy]);
             }
         })?;
 
@@ -96,21 +102,58 @@ impl Buffer {
     }
 
     pub fn update_slice<T: Copy>(&mut self, data: &[T]) -> Result<(), vk::Result> {
-        if let Some(alloc) = &self.allocation {
-             if let Some(ptr) = alloc.mapped_ptr() {
-                 let size_bytes = (std::mem::size_of::<T>() * data.len()) as u64;
-                 if size_bytes > self.size {
-                     return Err(vk::Result::ERROR_MEMORY_MAP_FAILED);
-                 }
-                 unsafe {
-                     std::ptr::copy_nonoverlapping(data.as_ptr(), ptr.as_ptr() as *mut T, data.len());
-                 }
-                 return Ok(());
-             }
+        if let Some(alloc) = &self.allocation
+            && let Some(ptr) = alloc.mapped_ptr()
+        {
+            let size_bytes = std::mem::size_of_val(data) as u64;
+            if size_bytes > self.size {
+                return Err(vk::Result::ERROR_MEMORY_MAP_FAILED);
+            }
+            unsafe {
+                std::ptr::copy_nonoverlapping(data.as_ptr(), ptr.as_ptr() as *mut T, data.len());
+            }
+            return Ok(());
+        }
+        Err(vk::Result::ERROR_OUT_OF_HOST_MEMORY)
+    }
+
+    // Writes `data` starting at element `offset`, for partial updates of mapped buffers
+    pub fn update_range<T: Copy>(&mut self, offset: usize, data: &[T]) -> Result<(), vk::Result> {
+        if let Some(alloc) = &self.allocation
+            && let Some(ptr) = alloc.mapped_ptr()
+        {
+            let end_bytes =
+                (std::mem::size_of::<T>() * offset + std::mem::size_of_val(data)) as u64;
+            if end_bytes > self.size {
+                return Err(vk::Result::ERROR_MEMORY_MAP_FAILED);
+            }
+            unsafe {
+                let dst = (ptr.as_ptr() as *mut T).add(offset);
+                std::ptr::copy_nonoverlapping(data.as_ptr(), dst, data.len());
+            }
+            return Ok(());
         }
         Err(vk::Result::ERROR_OUT_OF_HOST_MEMORY)
     }
-    
+
+    // Counterpart of update_slice for GpuToCpu buffers
+    pub fn read_slice<T: Copy>(&self, count: usize) -> Result<Vec<T>, vk::Result> {
+        if let Some(alloc) = &self.allocation
+            && let Some(ptr) = alloc.mapped_ptr()
+        {
+            if (std::mem::size_of::<T>() * count) as u64 > self.size {
+                return Err(vk::Result::ERROR_MEMORY_MAP_FAILED);
+            }
+            let mut data = Vec::with_capacity(count);
+            unsafe {
+                std::ptr::copy_nonoverlapping(ptr.as_ptr() as *const T, data.as_mut_ptr(), count);
+                data.set_len(count);
+            }
+            return Ok(data);
+        }
+        Err(vk::Result::ERROR_OUT_OF_HOST_MEMORY)
+    }
+
     // For single struct (Uniforms)
     pub fn update_item<T: Copy>(&mut self, data: T) -> Result<(), vk::Result> {
         self.update_slice(std::slice::from_ref(&data))
@@ -118,7 +161,9 @@ impl Buffer {
 
     pub fn destroy(&mut self, context: &VulkanContext) {
         let device = &context.device;
-        unsafe { device.destroy_buffer(self.buffer, None); }
+        unsafe {
+            device.destroy_buffer(self.buffer, None);
+        }
         if let Some(alloc) = self.allocation.take() {
             let mut allocator = context.allocator.lock().unwrap();
             let _ = allocator.free(alloc);
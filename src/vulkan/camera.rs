@@ -1,4 +1,4 @@
-use nalgebra::{Matrix4, Vector4, Point3, Vector3};
+use nalgebra::{Matrix4, Point3, Vector3, Vector4};
 
 #[repr(C)]
 #[derive(Clone, Copy, Debug)]
@@ -14,10 +14,10 @@ pub struct Camera {
     pub forward: Vector3<f32>,
     pub up: Vector3<f32>,
     pub right: Vector3<f32>,
-    
+
     pub aspect: f32,
     pub fov: f32,
-    
+
     pub yaw: f32,
     pub pitch: f32,
 }
@@ -62,8 +62,9 @@ impl Camera {
         let forward = Vector3::new(
             self.yaw.cos() * self.pitch.cos(),
             self.pitch.sin(),
-            self.yaw.sin() * self.pitch.cos()
-        ).normalize();
+            self.yaw.sin() * self.pitch.cos(),
+        )
+        .normalize();
 
         self.forward = forward;
         self.right = self.forward.cross(&Vector3::y()).normalize();
@@ -1,4 +1,4 @@
-use std::sync::Mutex;
+use std::{mem::ManuallyDrop, sync::Mutex};
 
 use ash::vk;
 use gpu_allocator::vulkan::{Allocator, AllocatorCreateDesc};
@@ -19,7 +19,8 @@ pub struct VulkanContext {
     pub command_pool: vk::CommandPool,
     pub surface: vk::SurfaceKHR,
     pub surface_loader: ash::khr::surface::Instance,
-    pub allocator: Mutex<Allocator>,
+    /// Dropped by `destroy`, before the device it frees memory from
+    pub allocator: Mutex<ManuallyDrop<Allocator>>,
     pub debug_utils_loader: ash::ext::debug_utils::Instance,
     pub debug_utils: ash::ext::debug_utils::Device,
     pub debug_call_back: vk::DebugUtilsMessengerEXT,
@@ -27,17 +28,33 @@ pub struct VulkanContext {
 
 impl VulkanContext {
     pub fn new(window: &Window) -> Result<Self, vk::Result> {
+        Self::create(Some(window))
+    }
+
+    /// Context without a surface, for offscreen rendering on machines with no
+    /// display (CI runners, lavapipe).
+    pub fn new_headless() -> Result<Self, vk::Result> {
+        Self::create(None)
+    }
+
+    fn create(window: Option<&Window>) -> Result<Self, vk::Result> {
         // TODO allocation callbacks
         // TODO better physical device picker
         let entry = ash::Entry::linked();
-        let display_handle = window
-            .display_handle()
-            .map_err(|_| vk::Result::ERROR_INITIALIZATION_FAILED)?
-            .as_raw();
-        let window_handle = window
-            .window_handle()
-            .map_err(|_| vk::Result::ERROR_INITIALIZATION_FAILED)?
-            .as_raw();
+        let handles = match window {
+            Some(window) => {
+                let display_handle = window
+                    .display_handle()
+                    .map_err(|_| vk::Result::ERROR_INITIALIZATION_FAILED)?
+                    .as_raw();
+                let window_handle = window
+                    .window_handle()
+                    .map_err(|_| vk::Result::ERROR_INITIALIZATION_FAILED)?
+                    .as_raw();
+                Some((display_handle, window_handle))
+            }
+            None => None,
+        };
         let instance = unsafe {
             let app_info = vk::ApplicationInfo::default()
                 .engine_name(c"Voxentia")
@@ -45,10 +62,24 @@ impl VulkanContext {
                 .engine_version(vk::make_api_version(0, 0, 1, 0))
                 .application_name(c"Voxentia Example")
                 .application_version(vk::make_api_version(0, 0, 1, 0));
-            let mut extension_names =
-                ash_window::enumerate_required_extensions(display_handle)?.to_vec();
+            let mut extension_names = match handles {
+                Some((display_handle, _)) => {
+                    ash_window::enumerate_required_extensions(display_handle)?.to_vec()
+                }
+                None => Vec::new(),
+            };
             extension_names.push(ash::ext::debug_utils::NAME.as_ptr());
-            let validation_layers = [c"VK_LAYER_KHRONOS_validation".as_ptr()];
+            // Headless runners usually ship without the SDK layers
+            let validation_available = entry
+                .enumerate_instance_layer_properties()?
+                .iter()
+                .any(|layer| layer.layer_name_as_c_str() == Ok(c"VK_LAYER_KHRONOS_validation"));
+            let validation_layers = if validation_available {
+                vec![c"VK_LAYER_KHRONOS_validation".as_ptr()]
+            } else {
+                warn!("VK_LAYER_KHRONOS_validation not found, running without validation");
+                Vec::new()
+            };
             let create_info = vk::InstanceCreateInfo::default()
                 .application_info(&app_info)
                 .enabled_layer_names(&validation_layers)
@@ -76,9 +107,12 @@ impl VulkanContext {
             unsafe { debug_utils_loader.create_debug_utils_messenger(&debug_info, None)? };
 
         let surface_loader = ash::khr::surface::Instance::new(&entry, &instance);
-        let surface = unsafe {
-            ash_window::create_surface(&entry, &instance, display_handle, window_handle, None)
-        }?;
+        let surface = match handles {
+            Some((display_handle, window_handle)) => unsafe {
+                ash_window::create_surface(&entry, &instance, display_handle, window_handle, None)?
+            },
+            None => vk::SurfaceKHR::null(),
+        };
         let pdevices = unsafe { instance.enumerate_physical_devices()? };
         let (physical_device, compute_queue_fi) = unsafe {
             pdevices
@@ -91,13 +125,14 @@ impl VulkanContext {
                         .find_map(|(index, info)| {
                             let supports_compute =
                                 info.queue_flags.contains(vk::QueueFlags::COMPUTE);
-                            let supports_surface = surface_loader
-                                .get_physical_device_surface_support(
-                                    *pdevice,
-                                    index as u32,
-                                    surface,
-                                )
-                                .unwrap_or(false);
+                            let supports_surface = surface == vk::SurfaceKHR::null()
+                                || surface_loader
+                                    .get_physical_device_surface_support(
+                                        *pdevice,
+                                        index as u32,
+                                        surface,
+                                    )
+                                    .unwrap_or(false);
                             if supports_compute && supports_surface {
                                 Some((*pdevice, index as u32))
                             } else {
@@ -107,17 +142,20 @@ impl VulkanContext {
                 })
                 .ok_or(vk::Result::ERROR_DEVICE_LOST)?
         };
-        let mut features12 = vk::PhysicalDeviceVulkan12Features::default()
-            .buffer_device_address(true);
-        let mut features = vk::PhysicalDeviceFeatures2::default()
-            .push_next(&mut features12);
+        let mut features12 =
+            vk::PhysicalDeviceVulkan12Features::default().buffer_device_address(true);
+        let mut features = vk::PhysicalDeviceFeatures2::default().push_next(&mut features12);
 
         let device = unsafe {
             let queue_priorities = [1.0];
             let queue_infos = [vk::DeviceQueueCreateInfo::default()
                 .queue_priorities(&queue_priorities)
                 .queue_family_index(compute_queue_fi)];
-            let extensions = [ash::khr::swapchain::NAME.as_ptr()];
+            let extensions = if surface == vk::SurfaceKHR::null() {
+                vec![]
+            } else {
+                vec![ash::khr::swapchain::NAME.as_ptr()]
+            };
             let create_info = vk::DeviceCreateInfo::default()
                 .queue_create_infos(&queue_infos)
                 .enabled_extension_names(&extensions)
@@ -142,7 +180,9 @@ impl VulkanContext {
                 debug_settings: Default::default(),
                 allocation_sizes: Default::default(),
             };
-            Mutex::new(Allocator::new(&desc).map_err(|_| vk::Result::ERROR_INITIALIZATION_FAILED)?)
+            let allocator =
+                Allocator::new(&desc).map_err(|_| vk::Result::ERROR_INITIALIZATION_FAILED)?;
+            Mutex::new(ManuallyDrop::new(allocator))
         };
 
         Ok(Self {
@@ -209,6 +249,25 @@ impl VulkanContext {
         }
         Ok(())
     }
+
+    /// Tears down the device and the instance. Every object created from
+    /// the context must be destroyed first, and the context not used after.
+    pub fn destroy(&mut self) {
+        unsafe {
+            let _ = self.device.device_wait_idle();
+            if let Ok(allocator) = self.allocator.get_mut() {
+                ManuallyDrop::drop(allocator);
+            }
+            self.device.destroy_command_pool(self.command_pool, None);
+            self.device.destroy_device(None);
+            if self.surface != vk::SurfaceKHR::null() {
+                self.surface_loader.destroy_surface(self.surface, None);
+            }
+            self.debug_utils_loader
+                .destroy_debug_utils_messenger(self.debug_call_back, None);
+            self.instance.destroy_instance(None);
+        }
+    }
 }
 
 extern "system" fn vulkan_debug_callback(
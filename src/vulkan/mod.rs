@@ -1,5 +1,8 @@
-pub mod context;
-pub mod swapchain;
-pub mod pipelines;
+pub mod accumulation;
 pub mod buffer;
 pub mod camera;
+pub mod context;
+pub mod offscreen;
+pub mod pipelines;
+pub mod swapchain;
+pub mod texture;
@@ -0,0 +1,110 @@
+use ash::vk;
+use gpu_allocator::MemoryLocation;
+use gpu_allocator::vulkan::{Allocation, AllocationCreateDesc};
+
+use crate::vulkan::{buffer::Buffer, context::VulkanContext};
+
+/// Storage image the raytracer writes into when there is no swapchain, plus the
+/// host visible buffer it is copied to after each frame.
+#[allow(unused)]
+pub struct OffscreenTarget {
+    pub image: vk::Image,
+    pub image_view: vk::ImageView,
+    pub allocation: Option<Allocation>,
+    pub format: vk::Format,
+    pub extent: vk::Extent2D,
+    pub readback: Buffer,
+}
+
+impl OffscreenTarget {
+    pub fn new(context: &VulkanContext, width: u32, height: u32) -> Result<Self, vk::Result> {
+        let device = &context.device;
+        let format = vk::Format::R8G8B8A8_UNORM;
+        let extent = vk::Extent2D { width, height };
+
+        let image = unsafe {
+            let create_info = vk::ImageCreateInfo::default()
+                .image_type(vk::ImageType::TYPE_2D)
+                .format(format)
+                .extent(vk::Extent3D {
+                    width,
+                    height,
+                    depth: 1,
+                })
+                .mip_levels(1)
+                .array_layers(1)
+                .samples(vk::SampleCountFlags::TYPE_1)
+                .tiling(vk::ImageTiling::OPTIMAL)
+                .usage(vk::ImageUsageFlags::STORAGE | vk::ImageUsageFlags::TRANSFER_SRC)
+                .sharing_mode(vk::SharingMode::EXCLUSIVE)
+                .initial_layout(vk::ImageLayout::UNDEFINED);
+            device.create_image(&create_info, None)?
+        };
+        context.set_object_name(image, "Offscreen Image")?;
+
+        let allocation = unsafe {
+            let requirements = device.get_image_memory_requirements(image);
+            let mut allocator = context
+                .allocator
+                .lock()
+                .map_err(|_| vk::Result::NOT_READY)?;
+            let allocation = allocator
+                .allocate(&AllocationCreateDesc {
+                    name: "Offscreen Image",
+                    requirements,
+                    location: MemoryLocation::GpuOnly,
+                    linear: false,
+                    allocation_scheme: gpu_allocator::vulkan::AllocationScheme::GpuAllocatorManaged,
+                })
+                .map_err(|_| vk::Result::ERROR_OUT_OF_DEVICE_MEMORY)?;
+            device.bind_image_memory(image, allocation.memory(), allocation.offset())?;
+            allocation
+        };
+
+        let image_view = unsafe {
+            let create_info = vk::ImageViewCreateInfo::default()
+                .image(image)
+                .view_type(vk::ImageViewType::TYPE_2D)
+                .format(format)
+                .components(vk::ComponentMapping::default())
+                .subresource_range(vk::ImageSubresourceRange {
+                    aspect_mask: vk::ImageAspectFlags::COLOR,
+                    base_mip_level: 0,
+                    level_count: 1,
+                    base_array_layer: 0,
+                    layer_count: 1,
+                });
+            device.create_image_view(&create_info, None)?
+        };
+
+        let readback = Buffer::new(
+            context,
+            width as u64 * height as u64 * 4,
+            vk::BufferUsageFlags::TRANSFER_DST,
+            MemoryLocation::GpuToCpu,
+            "Offscreen Readback",
+        )?;
+
+        Ok(Self {
+            image,
+            image_view,
+            allocation: Some(allocation),
+            format,
+            extent,
+            readback,
+        })
+    }
+
+    pub fn destroy(&mut self, context: &VulkanContext) {
+        let device = &context.device;
+        unsafe {
+            device.destroy_image_view(self.image_view, None);
+            device.destroy_image(self.image, None);
+        }
+        if let Some(alloc) = self.allocation.take() {
+            let mut allocator = context.allocator.lock().unwrap();
+            let _ = allocator.free(alloc);
+        }
+        self.readback.destroy(context);
+    }
+}
@@ -1,2 +1,2 @@
-pub mod raytrace;
 pub mod generate;
+pub mod raytrace;
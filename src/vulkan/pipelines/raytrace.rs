@@ -1,10 +1,196 @@
 use ash::vk;
 
 use crate::{
-    core::world::ChunkedWorld,
-    vulkan::{buffer::Buffer, context::VulkanContext, swapchain::SurfaceSwapchain},
+    core::{lights::LightSet, tree::TreeBuffers, world::ChunkedWorld},
+    vulkan::{
+        accumulation::AccumulationImage, buffer::Buffer, context::VulkanContext,
+        texture::TextureArray,
+    },
 };
 
+/// FrameConstants flags, mirrored in raytrace.comp.
+pub const FLAG_SHADOWS: u32 = 1 << 0;
+pub const FLAG_VOXEL_AO: u32 = 1 << 1;
+pub const FLAG_LIGHT_SHADOWS: u32 = 1 << 2;
+pub const FLAG_PATH_TRACE: u32 = 1 << 3;
+pub const FLAG_TREE: u32 = 1 << 4;
+
+/// How raytrace.comp turns rays into colours.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RenderMode {
+    /// One hit per pixel with direct light, shadows and AO
+    #[default]
+    Shaded,
+    /// Diffuse bounces with one sample per pixel and frame, averaged over the
+    /// frames while the view stays the same
+    PathTraced,
+}
+
+/// Which copy of the world raytrace.comp walks.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum WorldLayout {
+    /// Chunk directory and dense pool, with brick masks
+    #[default]
+    Chunks,
+    /// Sparse 64-tree rebuilt from the chunks whenever they change
+    Tree,
+}
+
+/// Ambient occlusion quality, from cheapest to most accurate.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AoQuality {
+    Off,
+    /// Corner darkening from the voxels next to the hit face
+    Low,
+    /// Low plus a few short hemisphere rays
+    #[default]
+    Medium,
+    /// Low plus more and longer hemisphere rays
+    High,
+}
+
+impl AoQuality {
+    pub const ALL: [AoQuality; 4] = [
+        AoQuality::Off,
+        AoQuality::Low,
+        AoQuality::Medium,
+        AoQuality::High,
+    ];
+
+    /// Hemisphere rays traced per hit and their length in voxels.
+    pub fn rays(&self) -> (u32, f32) {
+        match self {
+            AoQuality::Off | AoQuality::Low => (0, 0.0),
+            AoQuality::Medium => (4, 6.0),
+            AoQuality::High => (12, 16.0),
+        }
+    }
+
+    /// The next quality, wrapping around to Off.
+    pub fn next(&self) -> Self {
+        let index = Self::ALL.iter().position(|q| q == self).unwrap_or(0);
+        Self::ALL[(index + 1) % Self::ALL.len()]
+    }
+}
+
+/// Shading options of the raytrace pass, adjustable at runtime.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RenderSettings {
+    /// Trace a ray toward the sun from every hit
+    pub shadows: bool,
+    /// How far shadow rays look for occluders, in voxels
+    pub shadow_distance: f32,
+    pub ambient_occlusion: AoQuality,
+    /// Trace a ray toward every point light that reaches a hit
+    pub light_shadows: bool,
+    pub mode: RenderMode,
+    /// Diffuse bounces after the first hit in path traced mode
+    pub path_bounces: u32,
+    /// How far camera rays go before showing the sky, in voxels. The DDA's
+    /// step budget follows it.
+    pub view_distance: f32,
+    pub layout: WorldLayout,
+    /// Chunks this far from the camera are traced at their first mip level,
+    /// and at the next one every time the distance doubles. 0 keeps every
+    /// chunk at full resolution. The tree layout has no mips.
+    pub lod_distance: f32,
+}
+
+impl Default for RenderSettings {
+    fn default() -> Self {
+        Self {
+            shadows: true,
+            shadow_distance: 256.0,
+            ambient_occlusion: AoQuality::default(),
+            light_shadows: true,
+            mode: RenderMode::default(),
+            path_bounces: 3,
+            view_distance: 512.0,
+            layout: WorldLayout::default(),
+            lod_distance: 128.0,
+        }
+    }
+}
+
+/// DDA iterations that let a ray cross `view_distance` voxels diagonally one
+/// voxel at a time, the worst case once no brick can be skipped.
+fn max_steps(view_distance: f32) -> u32 {
+    (view_distance.max(0.0) * 3f32.sqrt()).ceil() as u32 + 64
+}
+
+/// Push constants of raytrace.comp, refreshed every frame.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FrameConstants {
+    pub window_origin: [i32; 4],
+    pub flags: u32,
+    pub shadow_distance: f32,
+    pub ao_rays: u32,
+    pub ao_distance: f32,
+    /// Samples already averaged into the accumulation image
+    pub sample_index: u32,
+    pub path_bounces: u32,
+    pub view_distance: f32,
+    /// Iteration cap of the DDA loop
+    pub max_steps: u32,
+    pub lod_distance: f32,
+}
+
+impl FrameConstants {
+    pub fn new(window_origin: [i32; 4], settings: &RenderSettings, sample_index: u32) -> Self {
+        let mut flags = 0;
+        if settings.shadows {
+            flags |= FLAG_SHADOWS;
+        }
+        if settings.ambient_occlusion != AoQuality::Off {
+            flags |= FLAG_VOXEL_AO;
+        }
+        if settings.light_shadows {
+            flags |= FLAG_LIGHT_SHADOWS;
+        }
+        if settings.mode == RenderMode::PathTraced {
+            flags |= FLAG_PATH_TRACE;
+        }
+        if settings.layout == WorldLayout::Tree {
+            flags |= FLAG_TREE;
+        }
+        let (ao_rays, ao_distance) = settings.ambient_occlusion.rays();
+        Self {
+            window_origin,
+            flags,
+            shadow_distance: settings.shadow_distance,
+            ao_rays,
+            ao_distance,
+            sample_index,
+            path_bounces: settings.path_bounces,
+            view_distance: settings.view_distance,
+            max_steps: max_steps(settings.view_distance),
+            lod_distance: settings.lod_distance,
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        unsafe {
+            std::slice::from_raw_parts(
+                self as *const Self as *const u8,
+                std::mem::size_of::<Self>(),
+            )
+        }
+    }
+}
+
+/// Resources raytrace.comp reads besides its target image.
+pub struct SceneBindings<'a> {
+    pub camera_buffer: &'a Buffer,
+    pub world: &'a ChunkedWorld,
+    pub material_buffer: &'a Buffer,
+    pub textures: &'a TextureArray,
+    pub environment_buffer: &'a Buffer,
+    pub lights: &'a LightSet,
+    pub accumulation: &'a AccumulationImage,
+    pub tree: &'a TreeBuffers,
+}
+
 #[allow(unused)]
 pub struct TestPipeline {
     pub pipeline: vk::Pipeline,
@@ -15,11 +201,12 @@ pub struct TestPipeline {
 }
 
 impl TestPipeline {
+    /// One descriptor set is allocated per target image view, so the same
+    /// pipeline drives both swapchain images and the offscreen target.
     pub fn new(
         context: &VulkanContext,
-        swapchain: &SurfaceSwapchain,
-        camera_buffer: &Buffer,
-        world: &ChunkedWorld,
+        target_views: &[vk::ImageView],
+        scene: &SceneBindings,
     ) -> Result<Self, vk::Result> {
         let descriptor_set_layout = unsafe {
             let bindings = [
@@ -43,6 +230,66 @@ impl TestPipeline {
                     .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
                     .descriptor_count(1)
                     .stage_flags(vk::ShaderStageFlags::COMPUTE),
+                vk::DescriptorSetLayoutBinding::default()
+                    .binding(4)
+                    .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                    .descriptor_count(1)
+                    .stage_flags(vk::ShaderStageFlags::COMPUTE),
+                vk::DescriptorSetLayoutBinding::default()
+                    .binding(5)
+                    .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                    .descriptor_count(1)
+                    .stage_flags(vk::ShaderStageFlags::COMPUTE),
+                vk::DescriptorSetLayoutBinding::default()
+                    .binding(6)
+                    .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER)
+                    .descriptor_count(1)
+                    .stage_flags(vk::ShaderStageFlags::COMPUTE),
+                vk::DescriptorSetLayoutBinding::default()
+                    .binding(7)
+                    .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                    .descriptor_count(1)
+                    .stage_flags(vk::ShaderStageFlags::COMPUTE),
+                vk::DescriptorSetLayoutBinding::default()
+                    .binding(8)
+                    .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                    .descriptor_count(1)
+                    .stage_flags(vk::ShaderStageFlags::COMPUTE),
+                vk::DescriptorSetLayoutBinding::default()
+                    .binding(9)
+                    .descriptor_type(vk::DescriptorType::STORAGE_IMAGE)
+                    .descriptor_count(1)
+                    .stage_flags(vk::ShaderStageFlags::COMPUTE),
+                vk::DescriptorSetLayoutBinding::default()
+                    .binding(10)
+                    .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                    .descriptor_count(1)
+                    .stage_flags(vk::ShaderStageFlags::COMPUTE),
+                vk::DescriptorSetLayoutBinding::default()
+                    .binding(11)
+                    .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                    .descriptor_count(1)
+                    .stage_flags(vk::ShaderStageFlags::COMPUTE),
+                vk::DescriptorSetLayoutBinding::default()
+                    .binding(12)
+                    .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                    .descriptor_count(1)
+                    .stage_flags(vk::ShaderStageFlags::COMPUTE),
+                vk::DescriptorSetLayoutBinding::default()
+                    .binding(13)
+                    .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                    .descriptor_count(1)
+                    .stage_flags(vk::ShaderStageFlags::COMPUTE),
+                vk::DescriptorSetLayoutBinding::default()
+                    .binding(14)
+                    .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                    .descriptor_count(1)
+                    .stage_flags(vk::ShaderStageFlags::COMPUTE),
+                vk::DescriptorSetLayoutBinding::default()
+                    .binding(15)
+                    .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                    .descriptor_count(1)
+                    .stage_flags(vk::ShaderStageFlags::COMPUTE),
             ];
 
             let layout_info = vk::DescriptorSetLayoutCreateInfo::default().bindings(&bindings);
@@ -55,7 +302,10 @@ impl TestPipeline {
         let layout = unsafe {
             let set_layouts = [descriptor_set_layout];
 
-            let push_constant_ranges = []; //time?
+            let push_constant_ranges = [vk::PushConstantRange::default()
+                .stage_flags(vk::ShaderStageFlags::COMPUTE)
+                .offset(0)
+                .size(std::mem::size_of::<FrameConstants>() as u32)];
 
             let create_info = vk::PipelineLayoutCreateInfo::default()
                 .set_layouts(&set_layouts)
@@ -80,7 +330,7 @@ impl TestPipeline {
             let stage = vk::PipelineShaderStageCreateInfo::default()
                 .stage(vk::ShaderStageFlags::COMPUTE)
                 .module(shader_module)
-                .name(&entry_point_name);
+                .name(entry_point_name);
 
             let create_info = vk::ComputePipelineCreateInfo::default()
                 .stage(stage)
@@ -96,7 +346,7 @@ impl TestPipeline {
             context.device.destroy_shader_module(shader_module, None);
         }
 
-        let image_len = swapchain.images.len();
+        let image_len = target_views.len();
         let descriptor_pool = unsafe {
             let pool_sizes = [
                 vk::DescriptorPoolSize {
@@ -115,6 +365,30 @@ impl TestPipeline {
                     ty: vk::DescriptorType::STORAGE_BUFFER,
                     descriptor_count: image_len as u32,
                 },
+                vk::DescriptorPoolSize {
+                    ty: vk::DescriptorType::STORAGE_BUFFER,
+                    descriptor_count: image_len as u32,
+                },
+                vk::DescriptorPoolSize {
+                    ty: vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+                    descriptor_count: image_len as u32,
+                },
+                vk::DescriptorPoolSize {
+                    ty: vk::DescriptorType::UNIFORM_BUFFER,
+                    descriptor_count: image_len as u32,
+                },
+                vk::DescriptorPoolSize {
+                    ty: vk::DescriptorType::STORAGE_BUFFER,
+                    descriptor_count: 2 * image_len as u32,
+                },
+                vk::DescriptorPoolSize {
+                    ty: vk::DescriptorType::STORAGE_IMAGE,
+                    descriptor_count: image_len as u32,
+                },
+                vk::DescriptorPoolSize {
+                    ty: vk::DescriptorType::STORAGE_BUFFER,
+                    descriptor_count: 6 * image_len as u32,
+                },
             ];
 
             let create_info = vk::DescriptorPoolCreateInfo::default()
@@ -142,7 +416,7 @@ impl TestPipeline {
             descriptor_sets,
         };
 
-        test_pipeline.update_descriptors(context, swapchain, camera_buffer, world);
+        test_pipeline.update_descriptors(context, target_views, scene);
 
         Ok(test_pipeline)
     }
@@ -150,18 +424,17 @@ impl TestPipeline {
     pub fn update_descriptors(
         &self,
         context: &VulkanContext,
-        swapchain: &SurfaceSwapchain,
-        camera_buffer: &Buffer,
-        world: &ChunkedWorld,
+        target_views: &[vk::ImageView],
+        scene: &SceneBindings,
     ) {
         for (i, descriptor_set) in self
             .descriptor_sets
             .iter()
             .enumerate()
-            .take(swapchain.images.len())
+            .take(target_views.len())
         {
             let image_info = [vk::DescriptorImageInfo::default()
-                .image_view(swapchain.image_views[i])
+                .image_view(target_views[i])
                 .image_layout(vk::ImageLayout::GENERAL)];
 
             let write_image = vk::WriteDescriptorSet::default()
@@ -172,7 +445,7 @@ impl TestPipeline {
                 .image_info(&image_info);
 
             let camera_buffer_info = [vk::DescriptorBufferInfo::default()
-                .buffer(camera_buffer.buffer)
+                .buffer(scene.camera_buffer.buffer)
                 .offset(0)
                 .range(vk::WHOLE_SIZE)];
             let write_camera = vk::WriteDescriptorSet::default()
@@ -183,7 +456,7 @@ impl TestPipeline {
                 .buffer_info(&camera_buffer_info);
 
             let dir_info = [vk::DescriptorBufferInfo::default()
-                .buffer(world.dir_buffer.buffer)
+                .buffer(scene.world.dir_buffer.buffer)
                 .offset(0)
                 .range(vk::WHOLE_SIZE)];
             let write_dir = vk::WriteDescriptorSet::default()
@@ -193,9 +466,8 @@ impl TestPipeline {
                 .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
                 .buffer_info(&dir_info);
 
-
             let pool_info = [vk::DescriptorBufferInfo::default()
-                .buffer(world.pool_buffer.buffer)
+                .buffer(scene.world.pool_buffer.buffer)
                 .offset(0)
                 .range(vk::WHOLE_SIZE)];
             let write_pool = vk::WriteDescriptorSet::default()
@@ -204,11 +476,173 @@ impl TestPipeline {
                 .descriptor_count(1)
                 .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
                 .buffer_info(&pool_info);
+
+            let material_info = [vk::DescriptorBufferInfo::default()
+                .buffer(scene.material_buffer.buffer)
+                .offset(0)
+                .range(vk::WHOLE_SIZE)];
+            let write_materials = vk::WriteDescriptorSet::default()
+                .dst_set(*descriptor_set)
+                .dst_binding(4)
+                .descriptor_count(1)
+                .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                .buffer_info(&material_info);
+
+            let texture_info = [vk::DescriptorImageInfo::default()
+                .sampler(scene.textures.sampler)
+                .image_view(scene.textures.image_view)
+                .image_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)];
+            let write_textures = vk::WriteDescriptorSet::default()
+                .dst_set(*descriptor_set)
+                .dst_binding(5)
+                .descriptor_count(1)
+                .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                .image_info(&texture_info);
+
+            let environment_info = [vk::DescriptorBufferInfo::default()
+                .buffer(scene.environment_buffer.buffer)
+                .offset(0)
+                .range(vk::WHOLE_SIZE)];
+            let write_environment = vk::WriteDescriptorSet::default()
+                .dst_set(*descriptor_set)
+                .dst_binding(6)
+                .descriptor_count(1)
+                .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER)
+                .buffer_info(&environment_info);
+
+            let light_info = [vk::DescriptorBufferInfo::default()
+                .buffer(scene.lights.light_buffer.buffer)
+                .offset(0)
+                .range(vk::WHOLE_SIZE)];
+            let write_lights = vk::WriteDescriptorSet::default()
+                .dst_set(*descriptor_set)
+                .dst_binding(7)
+                .descriptor_count(1)
+                .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                .buffer_info(&light_info);
+
+            let light_grid_info = [vk::DescriptorBufferInfo::default()
+                .buffer(scene.lights.grid_buffer.buffer)
+                .offset(0)
+                .range(vk::WHOLE_SIZE)];
+            let write_light_grid = vk::WriteDescriptorSet::default()
+                .dst_set(*descriptor_set)
+                .dst_binding(8)
+                .descriptor_count(1)
+                .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                .buffer_info(&light_grid_info);
+
+            let accumulation_info = [vk::DescriptorImageInfo::default()
+                .image_view(scene.accumulation.image_view)
+                .image_layout(vk::ImageLayout::GENERAL)];
+            let write_accumulation = vk::WriteDescriptorSet::default()
+                .dst_set(*descriptor_set)
+                .dst_binding(9)
+                .descriptor_count(1)
+                .descriptor_type(vk::DescriptorType::STORAGE_IMAGE)
+                .image_info(&accumulation_info);
+
+            let brick_info = [vk::DescriptorBufferInfo::default()
+                .buffer(scene.world.brick_buffer.buffer)
+                .offset(0)
+                .range(vk::WHOLE_SIZE)];
+            let write_bricks = vk::WriteDescriptorSet::default()
+                .dst_set(*descriptor_set)
+                .dst_binding(10)
+                .descriptor_count(1)
+                .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                .buffer_info(&brick_info);
+
+            let tree_node_info = [vk::DescriptorBufferInfo::default()
+                .buffer(scene.tree.node_buffer.buffer)
+                .offset(0)
+                .range(vk::WHOLE_SIZE)];
+            let write_tree_nodes = vk::WriteDescriptorSet::default()
+                .dst_set(*descriptor_set)
+                .dst_binding(11)
+                .descriptor_count(1)
+                .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                .buffer_info(&tree_node_info);
+
+            let tree_voxel_info = [vk::DescriptorBufferInfo::default()
+                .buffer(scene.tree.voxel_buffer.buffer)
+                .offset(0)
+                .range(vk::WHOLE_SIZE)];
+            let write_tree_voxels = vk::WriteDescriptorSet::default()
+                .dst_set(*descriptor_set)
+                .dst_binding(12)
+                .descriptor_count(1)
+                .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                .buffer_info(&tree_voxel_info);
+
+            let mip_info = [vk::DescriptorBufferInfo::default()
+                .buffer(scene.world.mip_buffer.buffer)
+                .offset(0)
+                .range(vk::WHOLE_SIZE)];
+            let write_mips = vk::WriteDescriptorSet::default()
+                .dst_set(*descriptor_set)
+                .dst_binding(13)
+                .descriptor_count(1)
+                .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                .buffer_info(&mip_info);
+
+            let header_info = [vk::DescriptorBufferInfo::default()
+                .buffer(scene.world.header_buffer.buffer)
+                .offset(0)
+                .range(vk::WHOLE_SIZE)];
+            let write_headers = vk::WriteDescriptorSet::default()
+                .dst_set(*descriptor_set)
+                .dst_binding(14)
+                .descriptor_count(1)
+                .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                .buffer_info(&header_info);
+
+            let palette_info = [vk::DescriptorBufferInfo::default()
+                .buffer(scene.world.palette_buffer.buffer)
+                .offset(0)
+                .range(vk::WHOLE_SIZE)];
+            let write_palettes = vk::WriteDescriptorSet::default()
+                .dst_set(*descriptor_set)
+                .dst_binding(15)
+                .descriptor_count(1)
+                .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                .buffer_info(&palette_info);
             unsafe {
-                context
-                    .device
-                    .update_descriptor_sets(&[write_image, write_camera, write_dir, write_pool], &[]);
+                context.device.update_descriptor_sets(
+                    &[
+                        write_image,
+                        write_camera,
+                        write_dir,
+                        write_pool,
+                        write_materials,
+                        write_textures,
+                        write_environment,
+                        write_lights,
+                        write_light_grid,
+                        write_accumulation,
+                        write_bricks,
+                        write_tree_nodes,
+                        write_tree_voxels,
+                        write_mips,
+                        write_headers,
+                        write_palettes,
+                    ],
+                    &[],
+                );
             }
         }
     }
+
+    /// Destroys the pool along with its descriptor sets, the layouts and the
+    /// pipeline.
+    pub fn destroy(&mut self, context: &VulkanContext) {
+        let device = &context.device;
+        unsafe {
+            device.destroy_pipeline(self.pipeline, None);
+            device.destroy_pipeline_layout(self.layout, None);
+            device.destroy_descriptor_pool(self.descriptor_pool, None);
+            device.destroy_descriptor_set_layout(self.descriptor_set_layout, None);
+        }
+        self.descriptor_sets.clear();
+    }
 }
@@ -18,6 +18,7 @@ pub struct SurfaceSwapchain {
     pub image_views: Vec<vk::ImageView>,
     pub surface_format: vk::SurfaceFormatKHR,
     pub extent: vk::Extent2D,
+    pub usage: vk::ImageUsageFlags,
 }
 
 impl SurfaceSync {
@@ -49,6 +50,25 @@ impl SurfaceSync {
             current_frame: 0,
         })
     }
+
+    pub fn destroy(&mut self, context: &VulkanContext) {
+        let device = &context.device;
+        unsafe {
+            for &fence in &self.in_flight_fences {
+                device.destroy_fence(fence, None);
+            }
+            for &semaphore in self
+                .image_available_semaphores
+                .iter()
+                .chain(&self.render_finished_semaphores)
+            {
+                device.destroy_semaphore(semaphore, None);
+            }
+        }
+        self.in_flight_fences.clear();
+        self.image_available_semaphores.clear();
+        self.render_finished_semaphores.clear();
+    }
 }
 
 impl SurfaceSwapchain {
@@ -106,6 +126,13 @@ impl SurfaceSwapchain {
         {
             usage |= vk::ImageUsageFlags::STORAGE
         };
+        // Needed to copy presentable images out for screenshots
+        if capabilities
+            .supported_usage_flags
+            .contains(vk::ImageUsageFlags::TRANSFER_SRC)
+        {
+            usage |= vk::ImageUsageFlags::TRANSFER_SRC
+        };
         let image_count = if capabilities.max_image_count > 0 {
             (capabilities.min_image_count + 1).min(capabilities.max_image_count)
         } else {
@@ -155,6 +182,21 @@ impl SurfaceSwapchain {
             image_views,
             surface_format: *format,
             extent,
+            usage,
         })
     }
+
+    /// Destroys the views and the swapchain, the images go with it.
+    pub fn destroy(&mut self, context: &VulkanContext) {
+        unsafe {
+            for &view in &self.image_views {
+                context.device.destroy_image_view(view, None);
+            }
+            self.swapchain_loader
+                .destroy_swapchain(self.swapchain, None);
+        }
+        self.image_views.clear();
+        self.images.clear();
+        self.swapchain = vk::SwapchainKHR::null();
+    }
 }
@@ -0,0 +1,280 @@
+use std::{fs::File, io::BufReader, path::Path};
+
+use ash::vk;
+use gpu_allocator::MemoryLocation;
+use gpu_allocator::vulkan::{Allocation, AllocationCreateDesc};
+use log::*;
+
+use crate::{
+    core::error::VoxelError,
+    vulkan::{buffer::Buffer, context::VulkanContext},
+};
+
+/// Layer size used when no texture could be read.
+const FALLBACK_SIZE: u32 = 16;
+
+/// Sampled RGBA8 2D array image holding one block texture per layer.
+#[allow(unused)]
+pub struct TextureArray {
+    pub image: vk::Image,
+    pub image_view: vk::ImageView,
+    pub sampler: vk::Sampler,
+    pub allocation: Option<Allocation>,
+    pub extent: vk::Extent2D,
+    pub layers: u32,
+}
+
+impl TextureArray {
+    /// Loads `<dir>/<name>.png` for every name, in order. Textures that can't
+    /// be read or don't match the size of the first one are replaced by a
+    /// white layer, which leaves the material's flat albedo.
+    pub fn load(context: &VulkanContext, dir: &Path, names: &[String]) -> Result<Self, vk::Result> {
+        let mut size = None;
+        let mut layers = Vec::with_capacity(names.len());
+        for name in names {
+            let path = dir.join(format!("{}.png", name));
+            match read_png(&path) {
+                Ok((width, height, pixels)) if size.is_none_or(|size| size == (width, height)) => {
+                    size = Some((width, height));
+                    layers.push(Some(pixels));
+                }
+                Ok((width, height, _)) => {
+                    warn!(
+                        "Texture {} is {}x{}, other block textures are {:?}",
+                        path.display(),
+                        width,
+                        height,
+                        size
+                    );
+                    layers.push(None);
+                }
+                Err(err) => {
+                    warn!("Unable to load texture {}: {}", path.display(), err);
+                    layers.push(None);
+                }
+            }
+        }
+
+        let (width, height) = size.unwrap_or((FALLBACK_SIZE, FALLBACK_SIZE));
+        let white = vec![255u8; width as usize * height as usize * 4];
+        let mut pixels: Vec<u8> = layers
+            .iter()
+            .flat_map(|layer| layer.as_deref().unwrap_or(&white).iter().copied())
+            .collect();
+        // Vulkan images need at least one layer
+        if layers.is_empty() {
+            pixels = white;
+        }
+        Self::new(context, width, height, &pixels)
+    }
+
+    /// Uploads tightly packed RGBA8 layers of `width` x `height`.
+    pub fn new(
+        context: &VulkanContext,
+        width: u32,
+        height: u32,
+        pixels: &[u8],
+    ) -> Result<Self, vk::Result> {
+        let device = &context.device;
+        let format = vk::Format::R8G8B8A8_UNORM;
+        let layer_size = width as usize * height as usize * 4;
+        let layers = (pixels.len() / layer_size) as u32;
+
+        let image = unsafe {
+            let create_info = vk::ImageCreateInfo::default()
+                .image_type(vk::ImageType::TYPE_2D)
+                .format(format)
+                .extent(vk::Extent3D {
+                    width,
+                    height,
+                    depth: 1,
+                })
+                .mip_levels(1)
+                .array_layers(layers)
+                .samples(vk::SampleCountFlags::TYPE_1)
+                .tiling(vk::ImageTiling::OPTIMAL)
+                .usage(vk::ImageUsageFlags::SAMPLED | vk::ImageUsageFlags::TRANSFER_DST)
+                .sharing_mode(vk::SharingMode::EXCLUSIVE)
+                .initial_layout(vk::ImageLayout::UNDEFINED);
+            device.create_image(&create_info, None)?
+        };
+        context.set_object_name(image, "Block Textures")?;
+
+        let allocation = unsafe {
+            let requirements = device.get_image_memory_requirements(image);
+            let mut allocator = context
+                .allocator
+                .lock()
+                .map_err(|_| vk::Result::NOT_READY)?;
+            let allocation = allocator
+                .allocate(&AllocationCreateDesc {
+                    name: "Block Textures",
+                    requirements,
+                    location: MemoryLocation::GpuOnly,
+                    linear: false,
+                    allocation_scheme: gpu_allocator::vulkan::AllocationScheme::GpuAllocatorManaged,
+                })
+                .map_err(|_| vk::Result::ERROR_OUT_OF_DEVICE_MEMORY)?;
+            device.bind_image_memory(image, allocation.memory(), allocation.offset())?;
+            allocation
+        };
+
+        let subresource_range = vk::ImageSubresourceRange {
+            aspect_mask: vk::ImageAspectFlags::COLOR,
+            base_mip_level: 0,
+            level_count: 1,
+            base_array_layer: 0,
+            layer_count: layers,
+        };
+
+        let mut staging = Buffer::new(
+            context,
+            pixels.len() as u64,
+            vk::BufferUsageFlags::TRANSFER_SRC,
+            MemoryLocation::CpuToGpu,
+            "Staging-Block Textures",
+        )?;
+        staging.update_slice(pixels)?;
+
+        context.immediate_submit(|cmd| {
+            let to_transfer = vk::ImageMemoryBarrier::default()
+                .old_layout(vk::ImageLayout::UNDEFINED)
+                .new_layout(vk::ImageLayout::TRANSFER_DST_OPTIMAL)
+                .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .image(image)
+                .subresource_range(subresource_range)
+                .src_access_mask(vk::AccessFlags::empty())
+                .dst_access_mask(vk::AccessFlags::TRANSFER_WRITE);
+            let to_sampled = vk::ImageMemoryBarrier::default()
+                .old_layout(vk::ImageLayout::TRANSFER_DST_OPTIMAL)
+                .new_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+                .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .image(image)
+                .subresource_range(subresource_range)
+                .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+                .dst_access_mask(vk::AccessFlags::SHADER_READ);
+            let region = vk::BufferImageCopy::default()
+                .image_subresource(vk::ImageSubresourceLayers {
+                    aspect_mask: vk::ImageAspectFlags::COLOR,
+                    mip_level: 0,
+                    base_array_layer: 0,
+                    layer_count: layers,
+                })
+                .image_extent(vk::Extent3D {
+                    width,
+                    height,
+                    depth: 1,
+                });
+            unsafe {
+                device.cmd_pipeline_barrier(
+                    cmd,
+                    vk::PipelineStageFlags::TOP_OF_PIPE,
+                    vk::PipelineStageFlags::TRANSFER,
+                    vk::DependencyFlags::empty(),
+                    &[],
+                    &[],
+                    &[to_transfer],
+                );
+                device.cmd_copy_buffer_to_image(
+                    cmd,
+                    staging.buffer,
+                    image,
+                    vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                    &[region],
+                );
+                device.cmd_pipeline_barrier(
+                    cmd,
+                    vk::PipelineStageFlags::TRANSFER,
+                    vk::PipelineStageFlags::COMPUTE_SHADER,
+                    vk::DependencyFlags::empty(),
+                    &[],
+                    &[],
+                    &[to_sampled],
+                );
+            }
+        })?;
+        staging.destroy(context);
+
+        let image_view = unsafe {
+            let create_info = vk::ImageViewCreateInfo::default()
+                .image(image)
+                .view_type(vk::ImageViewType::TYPE_2D_ARRAY)
+                .format(format)
+                .components(vk::ComponentMapping::default())
+                .subresource_range(subresource_range);
+            device.create_image_view(&create_info, None)?
+        };
+
+        // Nearest filtering keeps the texels crisp, like the voxels themselves
+        let sampler = unsafe {
+            let create_info = vk::SamplerCreateInfo::default()
+                .mag_filter(vk::Filter::NEAREST)
+                .min_filter(vk::Filter::NEAREST)
+                .mipmap_mode(vk::SamplerMipmapMode::NEAREST)
+                .address_mode_u(vk::SamplerAddressMode::REPEAT)
+                .address_mode_v(vk::SamplerAddressMode::REPEAT)
+                .address_mode_w(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+                .max_lod(0.0);
+            device.create_sampler(&create_info, None)?
+        };
+
+        Ok(Self {
+            image,
+            image_view,
+            sampler,
+            allocation: Some(allocation),
+            extent: vk::Extent2D { width, height },
+            layers,
+        })
+    }
+
+    pub fn destroy(&mut self, context: &VulkanContext) {
+        let device = &context.device;
+        unsafe {
+            device.destroy_sampler(self.sampler, None);
+            device.destroy_image_view(self.image_view, None);
+            device.destroy_image(self.image, None);
+        }
+        if let Some(alloc) = self.allocation.take() {
+            let mut allocator = context.allocator.lock().unwrap();
+            let _ = allocator.free(alloc);
+        }
+    }
+}
+
+/// Decodes a PNG into tightly packed RGBA8 pixels.
+pub fn read_png(path: &Path) -> Result<(u32, u32, Vec<u8>), VoxelError> {
+    let mut decoder = png::Decoder::new(BufReader::new(File::open(path)?));
+    // Expand palettes and low bit depths to 8 bit channels
+    decoder.set_transformations(png::Transformations::normalize_to_color8());
+    let mut reader = decoder
+        .read_info()
+        .map_err(|e| VoxelError::Format(e.to_string()))?;
+    let mut buffer = vec![0; reader.output_buffer_size().unwrap_or(0)];
+    let info = reader
+        .next_frame(&mut buffer)
+        .map_err(|e| VoxelError::Format(e.to_string()))?;
+    let pixels = &buffer[..info.buffer_size()];
+
+    let rgba = match info.color_type {
+        png::ColorType::Rgba => pixels.to_vec(),
+        png::ColorType::Rgb => pixels
+            .chunks_exact(3)
+            .flat_map(|p| [p[0], p[1], p[2], 255])
+            .collect(),
+        png::ColorType::GrayscaleAlpha => pixels
+            .chunks_exact(2)
+            .flat_map(|p| [p[0], p[0], p[0], p[1]])
+            .collect(),
+        png::ColorType::Grayscale => pixels.iter().flat_map(|&g| [g, g, g, 255]).collect(),
+        other => {
+            return Err(VoxelError::Format(format!(
+                "Unsupported PNG colour type {:?}",
+                other
+            )));
+        }
+    };
+    Ok((info.width, info.height, rgba))
+}